@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io;
 use std::io::{
     Read,
@@ -11,45 +12,166 @@ pub trait ReadBits {
     fn read_number(&mut self, len: usize) -> io::Result<u64>;
 }
 
-pub struct BitReader {
-    data: Box<Read>,
+// Where the bits come from, slices are read in place without going through
+// a boxed reader.
+enum Source<'a> {
+    Reader(Box<dyn Read + 'a>),
+    Slice(&'a [u8]),
+}
+
+impl <'a> Source<'a> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Reader(data) => data.read(buf),
+            Source::Slice(data) => data.read(buf),
+        }
+    }
+}
+
+// Reads bits starting from the least significant bit of each byte, as in
+// deflate.
+pub struct BitReader<'a> {
+    data: Source<'a>,
     buffer: u64,
     buffer_size: usize,
+    // Number of bytes read from `data` so far
+    consumed: u64,
 }
 
-impl BitReader {
-    pub fn new(data: Box<Read>) -> BitReader {
+impl <'a> BitReader<'a> {
+    pub fn new(data: Box<dyn Read + 'a>) -> BitReader<'a> {
+        BitReader::with_source(Source::Reader(data))
+    }
+
+    pub fn from_slice(data: &'a [u8]) -> BitReader<'a> {
+        BitReader::with_source(Source::Slice(data))
+    }
+
+    fn with_source(data: Source<'a>) -> BitReader<'a> {
         BitReader {
             data,
             buffer: 0,
             buffer_size: 0,
+            consumed: 0,
         }
     }
 
+    // Number of input bytes consumed so far, a partially read byte counts as
+    // consumed.
+    pub fn position(&self) -> u64 {
+        self.consumed - (self.buffer_size / 8) as u64
+    }
+
+    // Number of input bits consumed so far
+    pub(crate) fn bit_position(&self) -> u64 {
+        self.consumed * 8 - self.buffer_size as u64
+    }
+
+    // Whether there is no input left, this might need to read ahead from the
+    // underlying reader.
+    pub fn at_end(&mut self) -> io::Result<bool> {
+        if self.buffer_size > 0 {
+            return Ok(false);
+        }
+
+        let mut buf = [0; 8];
+        let read_len = self.data.read(&mut buf)?;
+        self.consumed += read_len as u64;
+
+        self.buffer = u64::from_le_bytes(buf);
+        self.buffer_size = read_len * 8;
+
+        Ok(read_len == 0)
+    }
+
+    // Returns the next `len` bits without consuming them, LSB-first like
+    // `read_number`, together with how many of them are actually available.
+    // Bits past the end of the input read as zeros.
+    pub(crate) fn peek_number(&mut self, len: usize) -> io::Result<(u64, usize)> {
+        assert!(len <= 56);
+
+        while self.buffer_size < len {
+            let buffer_size = self.buffer_size;
+            self.refill()?;
+
+            if self.buffer_size == buffer_size {
+                break;
+            }
+        }
+
+        let mask = (1 << len) - 1;
+        Ok((self.buffer & mask, cmp::min(len, self.buffer_size)))
+    }
+
+    // Consumes bits that have been returned by `peek_number`
+    pub(crate) fn skip_bits(&mut self, len: usize) {
+        assert!(len <= self.buffer_size && len < 64);
+
+        self.buffer >>= len;
+        self.buffer_size -= len;
+    }
+
+    // Reads as many whole bytes as fit in the buffer
+    fn refill(&mut self) -> io::Result<()> {
+        let free = (64 - self.buffer_size) / 8;
+        if free == 0 {
+            return Ok(());
+        }
+
+        let mut buf = [0; 8];
+        let read_len = self.data.read(&mut buf[..free])?;
+        self.consumed += read_len as u64;
+
+        let bytes = u64::from_le_bytes(buf);
+        self.buffer = if self.buffer_size == 0 {
+            bytes
+        } else {
+            self.buffer | (bytes << self.buffer_size)
+        };
+        self.buffer_size += read_len * 8;
+
+        Ok(())
+    }
 }
 
 const U64_BIT_MASK: u64 = 0xFFFFFFFFFFFFFFFF;
 
-impl Read for BitReader {
+// Reads the next bytes of the input. When the position is not on a byte
+// boundary each byte is made of the bits of two input bytes, and bits left
+// over at the end of the input are not returned.
+impl <'a> Read for BitReader<'a> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.buffer_size % 8 != 0 {
-            // hairy situation, let's crash for now
-            panic!("Misaligned buffer size.");
-        }
-
         // Let's collect the remaining buffer first
         let mut i = 0;
-        while self.buffer_size > 0 && i < buf.len() {
+        while self.buffer_size >= 8 && i < buf.len() {
             buf[i] = self.read_number(8)? as u8;
             i += 1;
         }
 
-        // When the buffer is exhausted, let's read from the raw data
-        self.data.read(&mut buf[i..]).map(|total| total + i)
+        if self.buffer_size == 0 {
+            // When the buffer is exhausted, let's read from the raw data
+            let read_len = self.data.read(&mut buf[i..])?;
+            self.consumed += read_len as u64;
+            return Ok(read_len + i);
+        }
+
+        while i < buf.len() {
+            let (byte, available) = self.peek_number(8)?;
+            if available < 8 {
+                break;
+            }
+
+            self.skip_bits(8);
+            buf[i] = byte as u8;
+            i += 1;
+        }
+
+        Ok(i)
     }
 }
 
-impl ReadBits for BitReader {
+impl <'a> ReadBits for BitReader<'a> {
     fn read_remaining_byte(&mut self) -> io::Result<u8> {
         Ok(self.read_bits(self.buffer_size % 8)? as u8)
     }
@@ -57,6 +179,8 @@ impl ReadBits for BitReader {
     fn read_bits(&mut self, mut len: usize) -> io::Result<u64> {
         assert!(len <= 64);
 
+        if len == 0 { return Ok(0); }
+
         let mut start = 0;
         let mut result = 0;
 
@@ -66,6 +190,7 @@ impl ReadBits for BitReader {
 
             let mut buf = [0; 8];
             let read_len = self.data.read(&mut buf)?;
+            self.consumed += read_len as u64;
 
             self.buffer = u64::from_le_bytes(buf);
 
@@ -99,7 +224,7 @@ impl ReadBits for BitReader {
         let piece = (self.buffer & (U64_BIT_MASK >> (64 - len))) << start;
         result = ((piece + result) << (64 - len - start)).reverse_bits();
 
-        self.buffer = self.buffer >> len;
+        self.buffer >>= len;
         self.buffer_size -= len;
 
         Ok(result)
@@ -110,6 +235,17 @@ impl ReadBits for BitReader {
 
         if len == 0 { return Ok(0); }
 
+        if len <= 56 {
+            let (result, available) = self.peek_number(len)?;
+            if available < len {
+                return Err(Error::new(ErrorKind::UnexpectedEof,
+                    "Unexpected code length."));
+            }
+
+            self.skip_bits(len);
+            return Ok(result);
+        }
+
         let mut buf = 0u64;
         let mut shift = 0;
 
@@ -132,6 +268,106 @@ impl ReadBits for BitReader {
     }
 }
 
+// Reads bits starting from the most significant bit of each byte, as in LZW,
+// CCITT fax and JBIG2. Numbers are stored with their most significant bit
+// first too, so `read_bits` and `read_number` return the same value.
+pub struct MsbBitReader<'a> {
+    data: Source<'a>,
+    // The next bit is bit `buffer_size - 1`
+    buffer: u64,
+    buffer_size: usize,
+    // Number of bytes read from `data` so far
+    consumed: u64,
+}
+
+impl <'a> MsbBitReader<'a> {
+    pub fn new(data: Box<dyn Read + 'a>) -> MsbBitReader<'a> {
+        MsbBitReader::with_source(Source::Reader(data))
+    }
+
+    pub fn from_slice(data: &'a [u8]) -> MsbBitReader<'a> {
+        MsbBitReader::with_source(Source::Slice(data))
+    }
+
+    fn with_source(data: Source<'a>) -> MsbBitReader<'a> {
+        MsbBitReader {
+            data,
+            buffer: 0,
+            buffer_size: 0,
+            consumed: 0,
+        }
+    }
+
+    // Number of input bytes consumed so far, a partially read byte counts as
+    // consumed.
+    pub fn position(&self) -> u64 {
+        self.consumed - (self.buffer_size / 8) as u64
+    }
+
+    // Whether there is no input left, this might need to read ahead from the
+    // underlying reader.
+    pub fn at_end(&mut self) -> io::Result<bool> {
+        if self.buffer_size == 0 {
+            self.refill()?;
+        }
+        Ok(self.buffer_size == 0)
+    }
+
+    // Reads as many whole bytes as fit in the buffer
+    fn refill(&mut self) -> io::Result<()> {
+        let free = (64 - self.buffer_size) / 8;
+        if free == 0 {
+            return Ok(());
+        }
+
+        let mut buf = [0; 8];
+        let read_len = self.data.read(&mut buf[..free])?;
+        self.consumed += read_len as u64;
+
+        for &byte in &buf[..read_len] {
+            self.buffer = (self.buffer << 8) | byte as u64;
+        }
+        self.buffer_size += read_len * 8;
+
+        Ok(())
+    }
+}
+
+impl <'a> ReadBits for MsbBitReader<'a> {
+    fn read_remaining_byte(&mut self) -> io::Result<u8> {
+        Ok(self.read_number(self.buffer_size % 8)? as u8)
+    }
+
+    fn read_bits(&mut self, len: usize) -> io::Result<u64> {
+        self.read_number(len)
+    }
+
+    fn read_number(&mut self, len: usize) -> io::Result<u64> {
+        assert!(len <= 64);
+
+        if len == 0 { return Ok(0); }
+
+        if len > 56 {
+            let high = self.read_number(len - 32)?;
+            let low = self.read_number(32)?;
+            return Ok((high << 32) | low);
+        }
+
+        while self.buffer_size < len {
+            let buffer_size = self.buffer_size;
+            self.refill()?;
+
+            if self.buffer_size == buffer_size {
+                return Err(Error::new(ErrorKind::UnexpectedEof,
+                    "Unexpected code length."));
+            }
+        }
+
+        self.buffer_size -= len;
+        Ok((self.buffer >> self.buffer_size) & ((1 << len) - 1))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -140,7 +376,7 @@ mod test {
         Cursor,
     };
 
-    fn test_bits(data: u8, len: usize, expected: u8) -> BitReader {
+    fn test_bits(data: u8, len: usize, expected: u8) -> BitReader<'static> {
         let mut reader = BitReader::new(Box::new(Cursor::new(vec![data])));
         let actual = reader.read_bits(len).unwrap();
 
@@ -256,6 +492,15 @@ mod test {
         assert_eq!(reader.read_bits(4).unwrap(), 0b1111);
     }
 
+    #[test]
+    fn test_read_remaining_byte_aligned() {
+        let mut reader = BitReader::new(Box::new(Cursor::new(vec![0xAB])));
+
+        assert_eq!(reader.read_remaining_byte().unwrap(), 0);
+        assert_eq!(reader.read_number(8).unwrap(), 0xAB);
+        assert_eq!(reader.read_remaining_byte().unwrap(), 0);
+    }
+
     #[test]
     fn test_read_bits_continuation() {
         let mut reader = BitReader::new(Box::new(Cursor::new(vec![
@@ -275,4 +520,78 @@ mod test {
 
         assert!(reader.read_bits(1).is_err())
     }
+
+    #[test]
+    fn test_position() {
+        let mut reader = BitReader::new(Box::new(Cursor::new(vec![
+            0x12, 0x34, 0x56])));
+
+        assert_eq!(reader.position(), 0);
+        reader.read_bits(3).unwrap();
+        assert_eq!(reader.position(), 1);
+        reader.read_remaining_byte().unwrap();
+        reader.read_number(8).unwrap();
+        assert_eq!(reader.position(), 2);
+
+        assert!(!reader.at_end().unwrap());
+        assert_eq!(reader.read_number(8).unwrap(), 0x56);
+        assert!(reader.at_end().unwrap());
+        assert_eq!(reader.position(), 3);
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xFF, 0xAB];
+        let mut reader = BitReader::from_slice(&data);
+
+        assert_eq!(reader.read_number(4).unwrap(), 0x2);
+        assert_eq!(reader.read_number(12).unwrap(), 0x341);
+        assert_eq!(reader.read_number(56).unwrap(), 0xABFFDEBC9A7856);
+        assert_eq!(reader.position(), 9);
+        assert!(reader.at_end().unwrap());
+    }
+
+    #[test]
+    fn test_read_misaligned() {
+        let mut reader = BitReader::from_slice(&[0x12, 0x34, 0x56]);
+        reader.read_number(4).unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[0x41, 0x63]);
+        // The last 4 bits are not a whole byte
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.read_number(4).unwrap(), 0x5);
+    }
+
+    #[test]
+    fn test_msb_read_number() {
+        let mut reader = MsbBitReader::from_slice(&[0b1011_0011, 0b1111_0000]);
+
+        assert_eq!(reader.read_number(3).unwrap(), 0b101);
+        assert_eq!(reader.read_number(7).unwrap(), 0b1001111);
+        assert_eq!(reader.position(), 2);
+        assert_eq!(reader.read_remaining_byte().unwrap(), 0b110000);
+        assert!(reader.at_end().unwrap());
+        assert!(reader.read_bits(1).is_err());
+    }
+
+    #[test]
+    fn test_msb_read_number_long() {
+        let data = [0xFF, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xFF];
+        let mut reader = MsbBitReader::new(Box::new(Cursor::new(data)));
+
+        assert_eq!(reader.read_number(8).unwrap(), 0xFF);
+        assert_eq!(reader.read_number(64).unwrap(), 0x123456789ABCDEFF);
+    }
+
+    #[test]
+    fn test_msb_lzw_codes() {
+        // The 9-bit codes 256, 65 and 257 as written by an LZW encoder
+        let mut reader = MsbBitReader::from_slice(&[0x80, 0x10, 0x60, 0x20]);
+
+        assert_eq!(reader.read_number(9).unwrap(), 256);
+        assert_eq!(reader.read_number(9).unwrap(), 65);
+        assert_eq!(reader.read_number(9).unwrap(), 257);
+    }
 }
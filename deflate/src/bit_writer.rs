@@ -0,0 +1,399 @@
+use std::io;
+use std::io::Write;
+
+// The writing side of `ReadBits`, values written with one of these methods
+// are read back by the method of the same name.
+pub trait WriteBits {
+    fn write_bits(&mut self, value: u64, len: usize) -> io::Result<()>;
+    fn write_number(&mut self, value: u64, len: usize) -> io::Result<()>;
+    // Pads the current byte with zeros so that the next write starts on a
+    // byte boundary.
+    fn align(&mut self) -> io::Result<()>;
+}
+
+fn unaligned() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   "Bytes can only be written on a byte boundary.")
+}
+
+// Writes bit-packed data LSB-first, the mirror image of `BitReader`.
+pub struct BitWriter<W: Write> {
+    data: W,
+    buffer: u64,
+    buffer_size: usize,
+    written: usize,
+}
+
+impl <W: Write> BitWriter<W> {
+    pub fn new(data: W) -> BitWriter<W> {
+        BitWriter {
+            data,
+            buffer: 0,
+            buffer_size: 0,
+            written: 0,
+        }
+    }
+
+    // The bytes are written as they are, so this fails with `InvalidInput`
+    // when the writer is not on a byte boundary, see `align`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !self.buffer_size.is_multiple_of(8) {
+            return Err(unaligned());
+        }
+
+        self.write_buffer()?;
+        self.data.write_all(bytes)?;
+        self.written += bytes.len();
+
+        Ok(())
+    }
+
+    // Aligns to the next byte and writes out everything that has been
+    // buffered so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.align()?;
+        self.write_buffer()?;
+        self.data.flush()
+    }
+
+    // Number of bytes handed to the underlying writer so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Bits that haven't been written out are lost, call `flush` first.
+    pub fn into_inner(self) -> W {
+        self.data
+    }
+
+    // Writes out all the complete bytes in the buffer
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let bytes = self.buffer_size / 8;
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        self.data.write_all(&self.buffer.to_le_bytes()[..bytes])?;
+        self.written += bytes;
+
+        self.buffer = if bytes == 8 { 0 } else { self.buffer >> (bytes * 8) };
+        self.buffer_size -= bytes * 8;
+
+        Ok(())
+    }
+}
+
+impl <W: Write> WriteBits for BitWriter<W> {
+    // Writes the lowest `len` bits of `value` starting from the least
+    // significant one, this is how deflate stores numbers.
+    fn write_number(&mut self, value: u64, len: usize) -> io::Result<()> {
+        assert!(len <= 64);
+
+        if len == 0 { return Ok(()); }
+
+        // The buffer always has less than 32 bits in it, so longer numbers
+        // need to go in two pieces.
+        if len > 32 {
+            self.write_number(value, 32)?;
+            return self.write_number(value >> 32, len - 32);
+        }
+
+        self.buffer |= (value & ((1 << len) - 1)) << self.buffer_size;
+        self.buffer_size += len;
+
+        if self.buffer_size >= 32 {
+            self.write_buffer()?;
+        }
+
+        Ok(())
+    }
+
+    // Writes the lowest `len` bits of `value` starting from the most
+    // significant one, this is how deflate stores Huffman codes.
+    fn write_bits(&mut self, value: u64, len: usize) -> io::Result<()> {
+        assert!(len <= 64);
+
+        if len == 0 { return Ok(()); }
+
+        let reversed = value.reverse_bits() >> (64 - len);
+        self.write_number(reversed, len)
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        let padding = (8 - self.buffer_size % 8) % 8;
+        self.write_number(0, padding)
+    }
+}
+
+// Writes bit-packed data MSB-first, the mirror image of `MsbBitReader`.
+pub struct MsbBitWriter<W: Write> {
+    data: W,
+    // The last bit written is bit 0
+    buffer: u64,
+    buffer_size: usize,
+    written: usize,
+}
+
+impl <W: Write> MsbBitWriter<W> {
+    pub fn new(data: W) -> MsbBitWriter<W> {
+        MsbBitWriter {
+            data,
+            buffer: 0,
+            buffer_size: 0,
+            written: 0,
+        }
+    }
+
+    // The bytes are written as they are, so this fails with `InvalidInput`
+    // when the writer is not on a byte boundary, see `align`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !self.buffer_size.is_multiple_of(8) {
+            return Err(unaligned());
+        }
+
+        self.write_buffer()?;
+        self.data.write_all(bytes)?;
+        self.written += bytes.len();
+
+        Ok(())
+    }
+
+    // Aligns to the next byte and writes out everything that has been
+    // buffered so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.align()?;
+        self.write_buffer()?;
+        self.data.flush()
+    }
+
+    // Number of bytes handed to the underlying writer so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Writes out all the complete bytes in the buffer
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let bytes = self.buffer_size / 8;
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let remaining = self.buffer_size - bytes * 8;
+        let buf = (self.buffer >> remaining).to_be_bytes();
+        self.data.write_all(&buf[8 - bytes..])?;
+        self.written += bytes;
+
+        self.buffer &= (1 << remaining) - 1;
+        self.buffer_size = remaining;
+
+        Ok(())
+    }
+}
+
+impl <W: Write> WriteBits for MsbBitWriter<W> {
+    // Numbers are stored MSB-first too, so this is the same as
+    // `write_number`.
+    fn write_bits(&mut self, value: u64, len: usize) -> io::Result<()> {
+        self.write_number(value, len)
+    }
+
+    fn write_number(&mut self, value: u64, len: usize) -> io::Result<()> {
+        assert!(len <= 64);
+
+        if len == 0 { return Ok(()); }
+
+        if len > 32 {
+            self.write_number(value >> 32, len - 32)?;
+            return self.write_number(value, 32);
+        }
+
+        self.buffer = (self.buffer << len) | (value & ((1 << len) - 1));
+        self.buffer_size += len;
+
+        if self.buffer_size >= 32 {
+            self.write_buffer()?;
+        }
+
+        Ok(())
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        let padding = (8 - self.buffer_size % 8) % 8;
+        self.write_number(0, padding)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::bit_reader::{
+        BitReader,
+        MsbBitReader,
+        ReadBits,
+    };
+
+    #[test]
+    fn test_write_number() {
+        let mut out = vec![];
+        {
+            let mut writer = BitWriter::new(&mut out);
+            writer.write_number(0b1, 1).unwrap();
+            writer.write_number(0b01, 2).unwrap();
+            writer.write_number(0b10110, 5).unwrap();
+            writer.write_number(0x6B9C, 16).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert_eq!(out, vec![0b10110011, 0x9C, 0x6B]);
+    }
+
+    #[test]
+    fn test_write_bits() {
+        let mut out = vec![];
+        {
+            let mut writer = BitWriter::new(&mut out);
+            writer.write_bits(0b110, 3).unwrap();
+            writer.write_bits(0b10, 2).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert_eq!(out, vec![0b00001011]);
+    }
+
+    #[test]
+    fn test_align() {
+        let mut out = vec![];
+        {
+            let mut writer = BitWriter::new(&mut out);
+            writer.write_number(0b111, 3).unwrap();
+            writer.align().unwrap();
+            writer.write_bytes(&[0xAB, 0xCD]).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(writer.written(), 3);
+        }
+
+        assert_eq!(out, vec![0b111, 0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn test_write_bytes_unaligned() {
+        let mut out = vec![];
+        let mut writer = BitWriter::new(&mut out);
+        writer.write_number(0b1, 1).unwrap();
+        assert_eq!(writer.write_bytes(&[0xAB]).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+
+        let mut writer = MsbBitWriter::new(&mut out);
+        writer.write_number(0b1, 1).unwrap();
+        assert_eq!(writer.write_bytes(&[0xAB]).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_write_number_long() {
+        let mut out = vec![];
+        {
+            let mut writer = BitWriter::new(&mut out);
+            writer.write_number(0xF, 4).unwrap();
+            writer.write_number(0x123456789ABCDEF0, 64).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert_eq!(out, vec![0x0F, 0xEF, 0xCD, 0xAB, 0x89, 0x67, 0x45, 0x23,
+                             0x01]);
+    }
+
+    // (value, length, whether it's written with `write_bits`)
+    const FIELDS: [(u64, usize, bool); 10] = [
+        (0b1, 1, false),
+        (0b110, 3, true),
+        (0x1FF, 9, false),
+        (0x6B9C, 16, true),
+        (0, 0, false),
+        (0x2A, 7, false),
+        (0x123456789ABCDEF0, 64, false),
+        (0x3FFFFFFFF, 34, true),
+        (0xABCDEF, 24, false),
+        (0b10, 2, true),
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        let mut out = vec![];
+        {
+            let mut writer = BitWriter::new(&mut out);
+            for &(value, len, bits) in FIELDS.iter() {
+                if bits {
+                    writer.write_bits(value, len).unwrap();
+                } else {
+                    writer.write_number(value, len).unwrap();
+                }
+            }
+            writer.flush().unwrap();
+        }
+
+        let mut reader = BitReader::from_slice(&out);
+        for &(value, len, bits) in FIELDS.iter() {
+            let actual = if bits {
+                reader.read_bits(len).unwrap()
+            } else {
+                reader.read_number(len).unwrap()
+            };
+            assert_eq!(actual, value);
+        }
+        assert_eq!(reader.read_remaining_byte().unwrap(), 0);
+        assert!(reader.at_end().unwrap());
+    }
+
+    #[test]
+    fn test_msb_write_number() {
+        let mut out = vec![];
+        {
+            let mut writer = MsbBitWriter::new(&mut out);
+            writer.write_number(0b101, 3).unwrap();
+            writer.write_number(0b1001111, 7).unwrap();
+            writer.write_bits(0b11, 2).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(writer.written(), 2);
+        }
+
+        assert_eq!(out, vec![0b1011_0011, 0b1111_0000]);
+    }
+
+    #[test]
+    fn test_msb_lzw_codes() {
+        let mut out = vec![];
+        {
+            let mut writer = MsbBitWriter::new(&mut out);
+            writer.write_number(256, 9).unwrap();
+            writer.write_number(65, 9).unwrap();
+            writer.write_number(257, 9).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert_eq!(out, vec![0x80, 0x10, 0x60, 0x20]);
+    }
+
+    #[test]
+    fn test_msb_round_trip() {
+        let mut out = vec![];
+        {
+            let mut writer = MsbBitWriter::new(&mut out);
+            for &(value, len, _) in FIELDS.iter() {
+                writer.write_number(value, len).unwrap();
+            }
+            writer.align().unwrap();
+            writer.write_bytes(&[0xAB]).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let mut reader = MsbBitReader::from_slice(&out);
+        for &(value, len, _) in FIELDS.iter() {
+            assert_eq!(reader.read_number(len).unwrap(), value);
+        }
+        assert_eq!(reader.read_remaining_byte().unwrap(), 0);
+        assert_eq!(reader.read_number(8).unwrap(), 0xAB);
+        assert!(reader.at_end().unwrap());
+    }
+}
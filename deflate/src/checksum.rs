@@ -0,0 +1,315 @@
+// Checksums used by the zlib (RFC1950) and gzip (RFC1952) formats.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use crate::error::DeflateError;
+
+pub trait Checksum {
+    fn update(&mut self, data: &[u8]);
+    fn value(&self) -> u32;
+}
+
+// RFC1952 ~ 8. Appendix: Sample CRC Code
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0 }
+    }
+
+    // Updates the CRC as if the `length` bytes that `other` was computed
+    // on had been passed to `update`, the same as zlib's crc32_combine.
+    // Appending a byte is a linear operation on the CRC, so appending
+    // `length` zeros is a 32x32 bit matrix that we get by squaring.
+    pub fn combine(&mut self, other: &Crc32, mut length: u64) {
+        if length == 0 {
+            return;
+        }
+
+        // The operator for a single zero bit
+        let mut odd = [0u32; 32];
+        odd[0] = 0xEDB88320;
+        for (n, row) in odd.iter_mut().enumerate().skip(1) {
+            *row = 1 << (n - 1);
+        }
+
+        // Two and then four zero bits
+        let mut even = gf2_matrix_square(&odd);
+        odd = gf2_matrix_square(&even);
+
+        let mut crc = self.crc;
+        loop {
+            // Each round doubles the number of zero bytes
+            even = gf2_matrix_square(&odd);
+            if length & 1 != 0 {
+                crc = gf2_matrix_times(&even, crc);
+            }
+            length >>= 1;
+            if length == 0 {
+                break;
+            }
+
+            odd = gf2_matrix_square(&even);
+            if length & 1 != 0 {
+                crc = gf2_matrix_times(&odd, crc);
+            }
+            length >>= 1;
+            if length == 0 {
+                break;
+            }
+        }
+
+        self.crc = crc ^ other.crc;
+    }
+}
+
+fn gf2_matrix_times(matrix: &[u32; 32], mut vector: u32) -> u32 {
+    let mut sum = 0;
+    let mut i = 0;
+    while vector != 0 {
+        if vector & 1 != 0 {
+            sum ^= matrix[i];
+        }
+        vector >>= 1;
+        i += 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(matrix: &[u32; 32]) -> [u32; 32] {
+    let mut square = [0; 32];
+    for (n, row) in square.iter_mut().enumerate() {
+        *row = gf2_matrix_times(matrix, matrix[n]);
+    }
+    square
+}
+
+impl Checksum for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        let mut c = !self.crc;
+        for &byte in data {
+            c = CRC32_TABLE[((c ^ byte as u32) & 0xFF) as usize] ^ (c >> 8);
+        }
+        self.crc = !c;
+    }
+
+    fn value(&self) -> u32 {
+        self.crc
+    }
+}
+
+// RFC1950 ~ 8.2
+const ADLER32_BASE: u32 = 65521;
+
+// Largest n such that 255n(n+1)/2 + (n+1)(BASE-1) fits in a u32, so we only
+// need to reduce once every this many bytes.
+const ADLER32_MAX_RUN: usize = 5552;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+}
+
+impl Checksum for Adler32 {
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER32_MAX_RUN) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER32_BASE;
+            self.b %= ADLER32_BASE;
+        }
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+// Computes the checksum of everything written through it
+pub(crate) struct ChecksumWriter<'a, C: Checksum> {
+    out: &'a mut dyn Write,
+    checksum: C,
+}
+
+impl <'a, C: Checksum> ChecksumWriter<'a, C> {
+    pub fn new(out: &'a mut dyn Write, checksum: C) -> ChecksumWriter<'a, C> {
+        ChecksumWriter { out, checksum }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.checksum.value()
+    }
+}
+
+impl <'a, C: Checksum> Write for ChecksumWriter<'a, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Returned, wrapped in `DeflateError::Checksum`, when the data we
+// decompressed does not match the checksums stored in the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumMismatch {
+    Adler32 { expected: u32, actual: u32 },
+    Crc32 { expected: u32, actual: u32 },
+    // The gzip ISIZE field, the uncompressed size modulo 2^32
+    Size { expected: u32, actual: u32 },
+    // The optional CRC16 of the gzip header
+    HeaderCrc16 { expected: u32, actual: u32 },
+}
+
+impl ChecksumMismatch {
+    pub(crate) fn check(self) -> Result<(), DeflateError> {
+        let matches = match self {
+            ChecksumMismatch::Adler32 { expected, actual }
+                | ChecksumMismatch::Crc32 { expected, actual }
+                | ChecksumMismatch::Size { expected, actual }
+                | ChecksumMismatch::HeaderCrc16 { expected, actual } =>
+                    expected == actual,
+        };
+
+        if matches {
+            Ok(())
+        } else {
+            Err(DeflateError::Checksum(self))
+        }
+    }
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, expected, actual) = match *self {
+            ChecksumMismatch::Adler32 { expected, actual } =>
+                ("Adler-32", expected, actual),
+            ChecksumMismatch::Crc32 { expected, actual } =>
+                ("CRC-32", expected, actual),
+            ChecksumMismatch::Size { expected, actual } =>
+                ("Size", expected, actual),
+            ChecksumMismatch::HeaderCrc16 { expected, actual } =>
+                ("Header CRC-16", expected, actual),
+        };
+        write!(f, "{} mismatch: expected {:#010x} but found {:#010x}.",
+               name, expected, actual)
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.value()
+    }
+
+    fn adler32(data: &[u8]) -> u32 {
+        let mut adler = Adler32::new();
+        adler.update(data);
+        adler.value()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"),
+                   0x414FA339);
+    }
+
+    #[test]
+    fn test_crc32_incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_crc32_combine() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+
+        for split in 0..=data.len() {
+            let mut first = Crc32::new();
+            first.update(&data[..split]);
+            let mut second = Crc32::new();
+            second.update(&data[split..]);
+
+            first.combine(&second, (data.len() - split) as u64);
+            assert_eq!(first.value(), 0x414FA339);
+        }
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        let mut zeros = Crc32::new();
+        zeros.update(&vec![0; 100_000]);
+        crc.combine(&zeros, 100_000);
+
+        let mut expected = Crc32::new();
+        expected.update(b"1234");
+        expected.update(&vec![0; 100_000]);
+        assert_eq!(crc, expected);
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        // Long enough to require more than one reduction
+        assert_eq!(adler32(&vec![0xFF; 100_000]), 0x149A302C);
+    }
+}
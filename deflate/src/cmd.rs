@@ -1,51 +1,551 @@
-#![feature(reverse_bits)]
-
-mod deflate;
-mod bit_reader;
-mod gzip;
+extern crate deflate;
+extern crate clap;
 
-use crate::gzip::rfc1952;
-use crate::bit_reader::BitReader;
+use deflate::{
+    rfc1952_header,
+    rfc1952_members,
+    BitReader,
+    CompressionLevel,
+    CompressWriter,
+    Format,
+    GzipHeader,
+    InflateOptions,
+    Inflater,
+    ParallelGzipWriter,
+    ParallelOptions,
+};
 
-extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
 
 use std::io;
 use std::io::{
     BufReader,
     BufWriter,
+    ErrorKind,
+    Read,
+    Write,
 };
 
+use std::fs;
 use std::fs::File;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Compress,
+    Decompress,
+    Test,
+    List,
+}
+
+struct Settings {
+    mode: Mode,
+    format: Format,
+    level: CompressionLevel,
+    // Write to stdout and leave the input alone
+    to_stdout: bool,
+    keep: bool,
+    force: bool,
+    // Save the name and time stamp in the gzip header when compressing, use
+    // them for the output file when decompressing.
+    name: bool,
+    threads: usize,
+}
+
+impl Settings {
+    fn from(matches: &ArgMatches) -> io::Result<Settings> {
+        let mode = if matches.is_present("decompress") {
+            Mode::Decompress
+        } else if matches.is_present("test") {
+            Mode::Test
+        } else if matches.is_present("list") {
+            Mode::List
+        } else {
+            Mode::Compress
+        };
+
+        let format = match matches.value_of("format") {
+            Some("zlib") => Format::Zlib,
+            Some("raw") => Format::Raw,
+            _ => Format::Gzip,
+        };
+
+        let level = if matches.is_present("fast") {
+            CompressionLevel::Fast
+        } else if matches.is_present("best") {
+            CompressionLevel::Best
+        } else {
+            CompressionLevel::Default
+        };
+
+        let threads = match matches.value_of("threads") {
+            Some(threads) => threads.parse().ok()
+                .filter(|&threads| threads > 0)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput,
+                    format!("Invalid number of threads: {}", threads)))?,
+            None => ParallelOptions::default().threads,
+        };
+
+        Ok(Settings {
+            mode,
+            format,
+            level,
+            to_stdout: matches.is_present("stdout"),
+            keep: matches.is_present("keep"),
+            force: matches.is_present("force"),
+            // Like gzip, names are saved by default but only restored when
+            // asked to.
+            name: if mode == Mode::Compress {
+                !matches.is_present("no-name")
+            } else {
+                matches.is_present("name")
+            },
+            threads,
+        })
+    }
+}
 
-fn main() -> io::Result<()> {
-    let matches = App::new("Uncompress gzip archives.")
+fn main() {
+    let matches = App::new("Compress or uncompress gzip, zlib and deflate files.")
         .version("1.0")
         .author("Agi Sferro <agi@sferro.dev>")
+        .arg(Arg::with_name("decompress")
+                .short("d")
+                .long("decompress")
+                .help("Decompress")
+                .conflicts_with_all(&["test", "list"]))
+        .arg(Arg::with_name("test")
+                .short("t")
+                .long("test")
+                .help("Test the integrity of compressed files")
+                .conflicts_with("list"))
+        .arg(Arg::with_name("list")
+                .short("l")
+                .long("list")
+                .help("List the contents of compressed files"))
+        .arg(Arg::with_name("stdout")
+                .short("c")
+                .long("stdout")
+                .help("Write to stdout, keep the input files"))
         .arg(Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .value_name("FILE")
-                .help("Output file, defaults to stdout")
+                .help("Write everything to FILE, keep the input files")
+                .takes_value(true)
+                .conflicts_with("stdout"))
+        .arg(Arg::with_name("keep")
+                .short("k")
+                .long("keep")
+                .help("Keep the input files"))
+        .arg(Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("Overwrite existing output files"))
+        .arg(Arg::with_name("name")
+                .short("N")
+                .long("name")
+                .help("Restore the original name and time stamp when decompressing"))
+        .arg(Arg::with_name("no-name")
+                .short("n")
+                .long("no-name")
+                .help("Don't save the original name and time stamp when compressing")
+                .conflicts_with("name"))
+        .arg(Arg::with_name("format")
+                .short("F")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Format of the compressed data")
+                .possible_values(&["gzip", "zlib", "raw"])
+                .default_value("gzip"))
+        .arg(Arg::with_name("fast")
+                .short("1")
+                .long("fast")
+                .help("Compress faster"))
+        .arg(Arg::with_name("best")
+                .short("9")
+                .long("best")
+                .help("Compress better")
+                .conflicts_with("fast"))
+        .arg(Arg::with_name("threads")
+                .short("p")
+                .long("threads")
+                .value_name("N")
+                .help("Number of threads for gzip compression, defaults to the number of cores")
                 .takes_value(true))
-        .arg(Arg::with_name("INPUT")
-                .help("Sets the input file to use")
-                .required(true)
-                .index(1))
+        .arg(Arg::with_name("FILE")
+                .help("Files to process, reads stdin when missing or -")
+                .multiple(true))
         .get_matches();
 
-    let input = matches.value_of("INPUT").unwrap();
-    let output = matches.value_of("output");
+    if let Err(e) = run(&matches) {
+        eprintln!("deflate-cmd: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> io::Result<()> {
+    let settings = Settings::from(matches)?;
+    let inputs: Vec<&str> = match matches.values_of("FILE") {
+        Some(values) => values.collect(),
+        None => vec!["-"],
+    };
+
+    // Everything goes to the same file, like with `-c`
+    let mut output = match matches.value_of("output") {
+        Some(file_name) if settings.mode == Mode::Compress
+                || settings.mode == Mode::Decompress =>
+            Some(BufWriter::new(File::create(file_name)?)),
+        _ => None,
+    };
+
+    if settings.mode == Mode::List {
+        println!("{:>12} {:>12} {:>6}  {:<16}  name",
+                 "compressed", "uncompressed", "ratio", "modified");
+    }
 
-    let source = Box::new(BufReader::new(File::open(input)?));
-    let mut reader = BitReader::new(source);
+    // Keep going after a failure like gzip does, but remember it for the
+    // exit status.
+    let mut failed = false;
+    for input in inputs {
+        let result = match output {
+            Some(ref mut output) => process(input, &settings, Some(output)),
+            None => process(input, &settings, None),
+        };
 
-    if let Some(file_name) = output {
-        let mut result = Box::new(BufWriter::new(File::open(file_name)?));
-        rfc1952(&mut reader, &mut result)?;
+        if let Err(e) = result {
+            eprintln!("deflate-cmd: {}: {}", input, e);
+            failed = true;
+        }
+    }
+
+    if let Some(mut output) = output {
+        output.flush()?;
+    }
+
+    if failed {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn process(input: &str, settings: &Settings, output: Option<&mut dyn Write>)
+        -> io::Result<()> {
+    match settings.mode {
+        Mode::List => return list(input, settings.format),
+        Mode::Test => {
+            decompress(open(input)?, &mut io::sink(), settings.format)?;
+            return Ok(());
+        },
+        Mode::Compress | Mode::Decompress => {},
+    }
+
+    if let Some(output) = output {
+        return transform(input, settings, output);
+    }
+
+    if input == "-" || settings.to_stdout {
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        transform(input, settings, &mut out)?;
+        return out.flush();
+    }
+
+    let (path, modified) = match settings.mode {
+        Mode::Compress => compressed_path(input, settings.format)?,
+        _ => decompressed_path(input, settings)?,
+    };
+
+    if path.exists() && !settings.force {
+        return Err(io::Error::new(ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display())));
+    }
+
+    let result = write_file(input, settings, &path, modified);
+    if result.is_err() {
+        // Don't leave half a file behind
+        let _ = fs::remove_file(&path);
+        return result;
+    }
+
+    if !settings.keep {
+        fs::remove_file(input)?;
+    }
+
+    Ok(())
+}
+
+fn write_file(input: &str, settings: &Settings, path: &Path,
+              modified: Option<SystemTime>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    transform(input, settings, &mut out)?;
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    if let Some(modified) = modified {
+        file.set_modified(modified)?;
+    }
+
+    Ok(())
+}
+
+fn transform(input: &str, settings: &Settings, out: &mut dyn Write)
+        -> io::Result<()> {
+    let data = open(input)?;
+    match settings.mode {
+        Mode::Decompress => decompress(data, out, settings.format).map(|_| ()),
+        _ => compress(input, data, out, settings),
+    }
+}
+
+fn open(input: &str) -> io::Result<Box<dyn Read>> {
+    if input == "-" {
+        Ok(Box::new(io::stdin()))
     } else {
-        rfc1952(&mut reader, &mut io::stdout())?;
+        Ok(Box::new(BufReader::new(File::open(input)?)))
+    }
+}
+
+fn compress(input: &str, mut data: Box<dyn Read>, out: &mut dyn Write,
+            settings: &Settings) -> io::Result<()> {
+    let header = header(input, settings)?;
+
+    if settings.format == Format::Gzip && settings.threads > 1 {
+        let options = ParallelOptions {
+            threads: settings.threads,
+            ..ParallelOptions::default()
+        };
+        let mut writer = ParallelGzipWriter::with_options(
+            out, settings.level, &header, options)?;
+        io::copy(&mut data, &mut writer)?;
+        writer.finish()?;
+        return Ok(());
     }
 
+    let mut writer = CompressWriter::with_header(
+        out, settings.format, settings.level, &header)?;
+    io::copy(&mut data, &mut writer)?;
+    writer.finish()?;
+
     Ok(())
 }
+
+// Returns the size of the decompressed data
+fn decompress<R: Read>(data: R, out: &mut dyn Write, format: Format)
+        -> io::Result<u64> {
+    // Like gzip, decompress all the members in the file
+    let options = InflateOptions {
+        multi_member: true,
+        ..InflateOptions::default()
+    };
+
+    let mut inflater = Inflater::with_options(data, format, options);
+    io::copy(&mut inflater, out)
+}
+
+fn header(input: &str, settings: &Settings) -> io::Result<GzipHeader> {
+    if input == "-" || !settings.name {
+        return Ok(GzipHeader::default());
+    }
+
+    let path = Path::new(input);
+    Ok(GzipHeader {
+        name: path.file_name().map(|name| name.to_string_lossy().into_owned()),
+        mtime: fs::metadata(path)?.modified().map(mtime).unwrap_or(0),
+        ..GzipHeader::default()
+    })
+}
+
+// Seconds since the epoch as stored in the gzip header, 0 when the time
+// doesn't fit.
+fn mtime(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).ok()
+        .and_then(|duration| {
+            let seconds = duration.as_secs();
+            if seconds <= u32::MAX as u64 { Some(seconds as u32) } else { None }
+        })
+        .unwrap_or(0)
+}
+
+fn suffix(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => ".gz",
+        Format::Zlib => ".zz",
+        Format::Raw => ".deflate",
+    }
+}
+
+// The output keeps the time stamp of the input, like with gzip
+fn compressed_path(input: &str, format: Format)
+        -> io::Result<(PathBuf, Option<SystemTime>)> {
+    if input.ends_with(suffix(format)) {
+        return Err(io::Error::new(ErrorKind::InvalidInput,
+            format!("already has {} suffix", suffix(format))));
+    }
+
+    let modified = fs::metadata(input)?.modified().ok();
+    Ok((PathBuf::from(format!("{}{}", input, suffix(format))), modified))
+}
+
+fn strip_suffix(input: &str, format: Format) -> Option<String> {
+    if format == Format::Gzip && input.ends_with(".tgz") {
+        return Some(format!("{}.tar", &input[..input.len() - 4]));
+    }
+
+    input.strip_suffix(suffix(format))
+        .filter(|stripped| !stripped.is_empty() && !stripped.ends_with('/'))
+        .map(|stripped| stripped.to_string())
+}
+
+// With `-N` the name and time stamp come from the gzip header, when they are
+// there, otherwise the suffix is removed from the input name.
+fn decompressed_path(input: &str, settings: &Settings)
+        -> io::Result<(PathBuf, Option<SystemTime>)> {
+    let mut path = strip_suffix(input, settings.format)
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput,
+            "unknown suffix -- ignored"))?;
+    let mut modified = fs::metadata(input)?.modified().ok();
+
+    if settings.name && settings.format == Format::Gzip {
+        let header = rfc1952_header(&mut BitReader::new(open(input)?))?;
+
+        // Only the file name is used, the header can't send the output to
+        // another directory.
+        let name = header.name.as_ref()
+            .and_then(|name| Path::new(name).file_name())
+            .filter(|name| *name != "." && *name != "..");
+        if let Some(name) = name {
+            path.set_file_name(name);
+        }
+
+        if header.mtime != 0 {
+            modified = Some(UNIX_EPOCH + Duration::from_secs(header.mtime as u64));
+        }
+    }
+
+    Ok((path, modified))
+}
+
+// Prints the compressed and decompressed size, and for gzip the time stamp
+// and original name of the first member. The whole file is decompressed to
+// get the size, the ISIZE field is only right for single member files
+// smaller than 4G.
+fn list(input: &str, format: Format) -> io::Result<()> {
+    let mut compressed = 0;
+    let (size, header) = if format == Format::Gzip {
+        let options = InflateOptions::default();
+        let members = rfc1952_members(&mut BitReader::new(open(input)?),
+                                      &mut io::sink(), &options)?;
+        for member in &members {
+            compressed += member.compressed_size;
+        }
+
+        let size = members.iter().map(|member| member.size as u64).sum();
+        (size, members.into_iter().next().map(|member| member.header))
+    } else {
+        let mut data = CountingReader { data: open(input)?, count: 0 };
+        let size = decompress(&mut data, &mut io::sink(), format)?;
+        compressed = data.count;
+        (size, None)
+    };
+
+    let name = header.as_ref()
+        .and_then(|header| header.name.clone())
+        .or_else(|| strip_suffix(input, format))
+        .unwrap_or_else(|| input.to_string());
+    let modified = header.as_ref()
+        .filter(|header| header.mtime != 0)
+        .map_or_else(String::new, |header| format_time(header.mtime));
+
+    println!("{:>12} {:>12} {:>5.1}%  {:<16}  {}",
+             compressed, size, ratio(compressed, size), modified, name);
+
+    Ok(())
+}
+
+// How much smaller the compressed data is, in percent
+fn ratio(compressed: u64, size: u64) -> f64 {
+    if size == 0 {
+        return 0.0;
+    }
+
+    100.0 * (1.0 - compressed as f64 / size as f64)
+}
+
+// Formats seconds since the epoch as a UTC date, using the days to civil
+// date conversion from http://howardhinnant.github.io/date_algorithms.html
+fn format_time(time: u32) -> String {
+    let days = (time / 86400) as i64 + 719_468;
+    let seconds = time % 86400;
+
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era
+        - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day,
+            seconds / 3600, seconds % 3600 / 60)
+}
+
+// Counts the bytes read from the compressed data
+struct CountingReader<R: Read> {
+    data: R,
+    count: u64,
+}
+
+impl <R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_time(1_234_567_890), "2009-02-13 23:31");
+        assert_eq!(format_time(u32::MAX), "2106-02-07 06:28");
+    }
+
+    #[test]
+    fn test_strip_suffix() {
+        assert_eq!(strip_suffix("a.txt.gz", Format::Gzip),
+                   Some("a.txt".to_string()));
+        assert_eq!(strip_suffix("dir/a.tgz", Format::Gzip),
+                   Some("dir/a.tar".to_string()));
+        assert_eq!(strip_suffix("a.zz", Format::Zlib), Some("a".to_string()));
+        assert_eq!(strip_suffix("a.deflate", Format::Raw),
+                   Some("a".to_string()));
+        assert_eq!(strip_suffix("a.txt", Format::Gzip), None);
+        assert_eq!(strip_suffix(".gz", Format::Gzip), None);
+        assert_eq!(strip_suffix("dir/.gz", Format::Gzip), None);
+    }
+
+    #[test]
+    fn test_ratio() {
+        assert_eq!(ratio(0, 0), 0.0);
+        assert_eq!(ratio(25, 100), 75.0);
+    }
+}
@@ -0,0 +1,1094 @@
+// This module implements the compression side of deflate from RFC1951
+// A copy of it is available at https://tools.ietf.org/html/rfc1951#section-1.1
+
+use std::io;
+use std::io::Write;
+
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::mem;
+
+use crate::bit_writer::{
+    BitWriter,
+    WriteBits,
+};
+use crate::checksum::{
+    Adler32,
+    Checksum,
+    Crc32,
+};
+use crate::gzip::{
+    header_bytes,
+    GzipHeader,
+};
+use crate::inflater::Format;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionLevel {
+    // Only stored blocks, no compression at all
+    Stored,
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+// How hard the LZ77 matcher tries for each level, these mirror the values
+// used by zlib for levels 1, 6 and 9.
+struct Parameters {
+    // Maximum number of hash chain entries to visit for each match
+    max_chain: usize,
+    // Stop searching once we find a match this long
+    nice_length: usize,
+    // Only look for a better match at the next byte when the current match
+    // is shorter than this, 0 disables lazy matching.
+    lazy_length: usize,
+}
+
+impl CompressionLevel {
+    fn parameters(self) -> Parameters {
+        match self {
+            CompressionLevel::Stored => Parameters {
+                max_chain: 0, nice_length: 0, lazy_length: 0 },
+            CompressionLevel::Fast => Parameters {
+                max_chain: 4, nice_length: 8, lazy_length: 0 },
+            CompressionLevel::Default => Parameters {
+                max_chain: 128, nice_length: 128, lazy_length: 16 },
+            CompressionLevel::Best => Parameters {
+                max_chain: 4096, nice_length: MAX_MATCH, lazy_length: MAX_MATCH },
+        }
+    }
+}
+
+// 3.2.5 - Maximum distance a back reference can reach
+const WINDOW_SIZE: usize = 32768;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const HASH_BITS: usize = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+
+// 3.2.4 - Stored blocks can hold at most 65535 bytes
+const MAX_STORED_LENGTH: usize = 65535;
+
+// Number of symbols we collect before emitting a block
+const BLOCK_SYMBOLS: usize = 16384;
+
+// Input compressed at a time by `CompressWriter`, the block size of pigz
+const WRITER_BLOCK_SIZE: usize = 128 * 1024;
+
+const END_OF_BLOCK: usize = 256;
+
+// 3.2.5
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289,
+    16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// 3.2.7
+const CODE_LENGTH_ORDER: [usize; 19] =
+        [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symbol {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+fn length_code(length: usize) -> usize {
+    LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap()
+}
+
+fn distance_code(distance: usize) -> usize {
+    DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap()
+}
+
+// Compresses `data` into a raw deflate stream and writes it to `out`.
+// Returns the number of compressed bytes written.
+pub fn rfc1951_compress(data: &[u8], out: &mut dyn Write,
+                        level: CompressionLevel) -> io::Result<usize> {
+    let mut writer = BitWriter::new(out);
+    compress(&mut writer, data, level)?;
+    writer.flush()?;
+    Ok(writer.written())
+}
+
+// Compresses `data` into a zlib stream (RFC1950) and writes it to `out`.
+// Returns the number of compressed bytes written.
+pub fn rfc1950_compress(data: &[u8], out: &mut dyn Write,
+                        level: CompressionLevel) -> io::Result<usize> {
+    zlib_compress(data, out, level, None)
+}
+
+// Same as `rfc1950_compress`, but matches can refer to the preset
+// `dictionary` which the decoder needs to have too.
+pub fn rfc1950_compress_with_dictionary(data: &[u8], out: &mut dyn Write,
+                                        level: CompressionLevel,
+                                        dictionary: &[u8])
+        -> io::Result<usize> {
+    zlib_compress(data, out, level, Some(dictionary))
+}
+
+fn zlib_compress(data: &[u8], out: &mut dyn Write, level: CompressionLevel,
+                 dictionary: Option<&[u8]>) -> io::Result<usize> {
+    let mut writer = BitWriter::new(out);
+    writer.write_bytes(&zlib_header(level, dictionary.is_some()))?;
+
+    match dictionary {
+        Some(dictionary) => {
+            // DICTID
+            let mut adler32 = Adler32::new();
+            adler32.update(dictionary);
+            writer.write_bytes(&adler32.value().to_be_bytes())?;
+
+            // Only the last window of the dictionary can be referenced
+            let start = dictionary.len().saturating_sub(WINDOW_SIZE);
+            let mut buffer = dictionary[start..].to_vec();
+            buffer.extend_from_slice(data);
+            compress_from(&mut writer, &buffer, dictionary.len() - start,
+                          level, true)?;
+        },
+        None => compress(&mut writer, data, level)?,
+    }
+    writer.align()?;
+
+    let mut adler32 = Adler32::new();
+    adler32.update(data);
+    writer.write_bytes(&adler32.value().to_be_bytes())?;
+
+    writer.flush()?;
+    Ok(writer.written())
+}
+
+// 2.2 - CMF and FLG
+fn zlib_header(level: CompressionLevel, has_dictionary: bool) -> [u8; 2] {
+    // CM = 8 (deflate) and CINFO = 7 (32K window)
+    let cmf: u16 = 0x78;
+    let flevel: u16 = match level {
+        CompressionLevel::Stored => 0,
+        CompressionLevel::Fast => 1,
+        CompressionLevel::Default => 2,
+        CompressionLevel::Best => 3,
+    };
+    let fdict: u16 = if has_dictionary { 1 } else { 0 };
+    let mut flg = (flevel << 6) | (fdict << 5);
+    flg += (31 - ((cmf << 8) + flg) % 31) % 31;
+
+    [cmf as u8, flg as u8]
+}
+
+// Compresses everything written to it into a raw deflate, zlib or gzip
+// stream without keeping the whole input around. The input is compressed a
+// block at a time with the 32K before it as a dictionary, and every block
+// but the last ends with a sync flush. `finish` needs to be called at the
+// end to write the last block and the trailer.
+pub struct CompressWriter<W: Write> {
+    writer: BitWriter<W>,
+    format: Format,
+    level: CompressionLevel,
+    // Input that hasn't been compressed yet
+    block: Vec<u8>,
+    // The last 32K of input before `block`
+    window: Vec<u8>,
+    adler32: Adler32,
+    crc32: Crc32,
+    size: u64,
+}
+
+impl <W: Write> CompressWriter<W> {
+    pub fn new(out: W, format: Format, level: CompressionLevel)
+            -> io::Result<CompressWriter<W>> {
+        CompressWriter::with_header(out, format, level, &GzipHeader::default())
+    }
+
+    // `header` is only used for gzip
+    pub fn with_header(out: W, format: Format, level: CompressionLevel,
+                       header: &GzipHeader) -> io::Result<CompressWriter<W>> {
+        let mut writer = BitWriter::new(out);
+        match format {
+            Format::Raw => {},
+            Format::Zlib => writer.write_bytes(&zlib_header(level, false))?,
+            Format::Gzip => writer.write_bytes(&header_bytes(header, level)?)?,
+        }
+
+        Ok(CompressWriter {
+            writer,
+            format,
+            level,
+            block: Vec::with_capacity(WRITER_BLOCK_SIZE),
+            window: vec![],
+            adler32: Adler32::new(),
+            crc32: Crc32::new(),
+            size: 0,
+        })
+    }
+
+    // Number of compressed bytes written to `out` so far
+    pub fn written(&self) -> usize {
+        self.writer.written()
+    }
+
+    // Compresses what's left and writes the trailer. Returns the underlying
+    // writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // The last block is the only final one, even when it's empty
+        self.compress_block(true)?;
+        self.writer.align()?;
+
+        match self.format {
+            Format::Raw => {},
+            Format::Zlib => {
+                self.writer.write_bytes(&self.adler32.value().to_be_bytes())?;
+            },
+            Format::Gzip => {
+                self.writer.write_bytes(&self.crc32.value().to_le_bytes())?;
+                // ISIZE is the input size modulo 2^32
+                self.writer.write_bytes(&(self.size as u32).to_le_bytes())?;
+            },
+        }
+
+        self.writer.flush()?;
+        Ok(self.writer.into_inner())
+    }
+
+    fn compress_block(&mut self, is_final: bool) -> io::Result<()> {
+        let start = self.window.len();
+        let mut data = mem::take(&mut self.window);
+        data.extend_from_slice(&self.block);
+        compress_from(&mut self.writer, &data, start, self.level, is_final)?;
+
+        match self.format {
+            Format::Raw => {},
+            Format::Zlib => self.adler32.update(&self.block),
+            Format::Gzip => self.crc32.update(&self.block),
+        }
+        self.size += self.block.len() as u64;
+
+        let window_start = data.len().saturating_sub(WINDOW_SIZE);
+        self.window = data[window_start..].to_vec();
+        self.block.clear();
+
+        Ok(())
+    }
+}
+
+impl <W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // A full block is only compressed once we know it's not the last one
+        if self.block.len() == WRITER_BLOCK_SIZE {
+            self.compress_block(false)?;
+        }
+
+        let length = cmp::min(buf.len(), WRITER_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..length]);
+
+        Ok(length)
+    }
+
+    // Like zlib's Z_SYNC_FLUSH, compresses what's buffered so that everything
+    // written so far can be decompressed from the output.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.compress_block(false)?;
+        }
+
+        // The sync flush leaves the writer on a byte boundary
+        self.writer.flush()
+    }
+}
+
+pub(crate) fn compress<W: Write>(writer: &mut BitWriter<W>, data: &[u8],
+                      level: CompressionLevel) -> io::Result<()> {
+    compress_from(writer, data, 0, level, true)
+}
+
+// Compresses `data[start..]`, what comes before `start` is only there for
+// matches to refer to. When this is not the end of the stream, the output
+// finishes with an empty stored block like zlib's Z_SYNC_FLUSH, so that
+// whatever follows starts on a byte boundary.
+pub(crate) fn compress_from<W: Write>(writer: &mut BitWriter<W>, data: &[u8],
+                                      start: usize, level: CompressionLevel,
+                                      is_final: bool) -> io::Result<()> {
+    if level == CompressionLevel::Stored {
+        // Stored blocks end on a byte boundary already
+        return write_stored(writer, &data[start..], is_final);
+    }
+
+    let parameters = level.parameters();
+    let mut matcher = Matcher::new(data);
+    let mut symbols = Vec::with_capacity(BLOCK_SYMBOLS);
+
+    for i in 0..start {
+        matcher.insert(i);
+    }
+
+    let mut block_start = start;
+    let mut i = start;
+    let mut next_match = None;
+
+    while i < data.len() {
+        let (length, distance) = match next_match.take() {
+            Some(m) => m,
+            None => matcher.find(i, &parameters),
+        };
+        matcher.insert(i);
+
+        if length >= MIN_MATCH && length < parameters.lazy_length
+                && i + 1 < data.len() {
+            // Lazy matching: if the next byte starts a longer match we are
+            // better off emitting a literal here.
+            let next = matcher.find(i + 1, &parameters);
+            if next.0 > length {
+                symbols.push(Symbol::Literal(data[i]));
+                i += 1;
+                next_match = Some(next);
+                continue;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            symbols.push(Symbol::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+            for j in i + 1 .. i + length {
+                matcher.insert(j);
+            }
+            i += length;
+        } else {
+            symbols.push(Symbol::Literal(data[i]));
+            i += 1;
+        }
+
+        if symbols.len() >= BLOCK_SYMBOLS {
+            write_block(writer, &symbols, &data[block_start..i], false)?;
+            symbols.clear();
+            block_start = i;
+        }
+    }
+
+    write_block(writer, &symbols, &data[block_start..], is_final)?;
+
+    if !is_final {
+        write_stored_block(writer, &[], false)?;
+    }
+
+    Ok(())
+}
+
+// 3.2.4
+fn write_stored<W: Write>(writer: &mut BitWriter<W>, data: &[u8],
+                          is_final: bool) -> io::Result<()> {
+    let mut chunks = data.chunks(MAX_STORED_LENGTH).peekable();
+
+    if chunks.peek().is_none() {
+        return write_stored_block(writer, &[], is_final);
+    }
+
+    while let Some(chunk) = chunks.next() {
+        let last = is_final && chunks.peek().is_none();
+        write_stored_block(writer, chunk, last)?;
+    }
+
+    Ok(())
+}
+
+fn write_stored_block<W: Write>(writer: &mut BitWriter<W>, data: &[u8],
+                                is_final: bool) -> io::Result<()> {
+    writer.write_number(is_final as u64, 1)?;
+    writer.write_number(0b00, 2)?;
+    writer.align()?;
+
+    writer.write_number(data.len() as u64, 16)?;
+    writer.write_number(!(data.len() as u16) as u64, 16)?;
+    writer.write_bytes(data)
+}
+
+// Writes `symbols` as a single block picking whichever encoding is smallest,
+// `raw` is the uncompressed data covered by the block.
+fn write_block<W: Write>(writer: &mut BitWriter<W>, symbols: &[Symbol],
+                         raw: &[u8], is_final: bool) -> io::Result<()> {
+    let mut literal_frequencies = [0u32; 286];
+    let mut distance_frequencies = [0u32; 30];
+
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Literal(x) => literal_frequencies[x as usize] += 1,
+            Symbol::Match { length, distance } => {
+                literal_frequencies[257 + length_code(length as usize)] += 1;
+                distance_frequencies[distance_code(distance as usize)] += 1;
+            },
+        }
+    }
+    literal_frequencies[END_OF_BLOCK] += 1;
+
+    let fixed = HuffmanEncoder::fixed_literal();
+    let fixed_distance = HuffmanEncoder::fixed_distance();
+    let fixed_size = 3
+        + data_size(&literal_frequencies, &distance_frequencies,
+                    &fixed.lengths, &fixed_distance.lengths);
+
+    let dynamic = DynamicHeader::new(&literal_frequencies,
+                                     &distance_frequencies);
+    let dynamic_size = 3 + dynamic.size()
+        + data_size(&literal_frequencies, &distance_frequencies,
+                    &dynamic.literal.lengths, &dynamic.distance.lengths);
+
+    // Every stored block needs a header, up to 7 bits of padding and the
+    // LEN/NLEN pair.
+    let stored_blocks = cmp::max(1, raw.len().div_ceil(MAX_STORED_LENGTH));
+    let stored_size = raw.len() * 8 + stored_blocks * (3 + 7 + 32);
+
+    if stored_size <= fixed_size && stored_size <= dynamic_size {
+        return write_stored(writer, raw, is_final);
+    }
+
+    writer.write_number(is_final as u64, 1)?;
+
+    if fixed_size <= dynamic_size {
+        writer.write_number(0b01, 2)?;
+        write_symbols(writer, symbols, &fixed, &fixed_distance)
+    } else {
+        writer.write_number(0b10, 2)?;
+        dynamic.write(writer)?;
+        write_symbols(writer, symbols, &dynamic.literal, &dynamic.distance)
+    }
+}
+
+// Size in bits of the block data (excluding the header) using the given
+// code lengths.
+fn data_size(literal_frequencies: &[u32], distance_frequencies: &[u32],
+             literal_lengths: &[u8], distance_lengths: &[u8]) -> usize {
+    let mut size = 0;
+    for (i, &frequency) in literal_frequencies.iter().enumerate() {
+        let extra = if i > END_OF_BLOCK { LENGTH_EXTRA[i - 257] } else { 0 };
+        size += frequency as usize
+            * (literal_lengths[i] as usize + extra as usize);
+    }
+    for (i, &frequency) in distance_frequencies.iter().enumerate() {
+        size += frequency as usize
+            * (distance_lengths[i] as usize + DISTANCE_EXTRA[i] as usize);
+    }
+    size
+}
+
+fn write_symbols<W: Write>(writer: &mut BitWriter<W>, symbols: &[Symbol],
+                           literal: &HuffmanEncoder,
+                           distance: &HuffmanEncoder) -> io::Result<()> {
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Literal(x) => literal.write(writer, x as usize)?,
+            Symbol::Match { length, distance: dist } => {
+                let length = length as usize;
+                let code = length_code(length);
+                literal.write(writer, 257 + code)?;
+                writer.write_number((length - LENGTH_BASE[code] as usize) as u64,
+                                    LENGTH_EXTRA[code] as usize)?;
+
+                let dist = dist as usize;
+                let code = distance_code(dist);
+                distance.write(writer, code)?;
+                writer.write_number((dist - DISTANCE_BASE[code] as usize) as u64,
+                                    DISTANCE_EXTRA[code] as usize)?;
+            },
+        }
+    }
+
+    literal.write(writer, END_OF_BLOCK)
+}
+
+// 3.2.7 - The code lengths for the literal and distance alphabets, run-length
+// encoded with the code length alphabet.
+struct DynamicHeader {
+    literal: HuffmanEncoder,
+    distance: HuffmanEncoder,
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+    code_length: HuffmanEncoder,
+    // (symbol, extra bits value, extra bits length)
+    code_lengths: Vec<(usize, u64, usize)>,
+}
+
+impl DynamicHeader {
+    fn new(literal_frequencies: &[u32],
+           distance_frequencies: &[u32]) -> DynamicHeader {
+        let literal = HuffmanEncoder::new(
+            build_code_lengths(literal_frequencies, 15));
+        let distance = HuffmanEncoder::new(
+            build_code_lengths(distance_frequencies, 15));
+
+        let hlit = cmp::max(257, last_used(&literal.lengths));
+        let hdist = cmp::max(1, last_used(&distance.lengths));
+
+        let mut lengths = literal.lengths[..hlit].to_vec();
+        lengths.extend_from_slice(&distance.lengths[..hdist]);
+
+        let code_lengths = run_length_encode(&lengths);
+
+        let mut frequencies = [0u32; 19];
+        for &(symbol, _, _) in &code_lengths {
+            frequencies[symbol] += 1;
+        }
+        let code_length = HuffmanEncoder::new(build_code_lengths(&frequencies, 7));
+
+        let hclen = cmp::max(4, CODE_LENGTH_ORDER.iter()
+            .rposition(|&i| code_length.lengths[i] != 0)
+            .map(|i| i + 1)
+            .unwrap_or(0));
+
+        DynamicHeader {
+            literal,
+            distance,
+            hlit,
+            hdist,
+            hclen,
+            code_length,
+            code_lengths,
+        }
+    }
+
+    // Size of the header in bits
+    fn size(&self) -> usize {
+        let mut size = 5 + 5 + 4 + 3 * self.hclen;
+        for &(symbol, _, extra_length) in &self.code_lengths {
+            size += self.code_length.lengths[symbol] as usize + extra_length;
+        }
+        size
+    }
+
+    fn write<W: Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
+        writer.write_number((self.hlit - 257) as u64, 5)?;
+        writer.write_number((self.hdist - 1) as u64, 5)?;
+        writer.write_number((self.hclen - 4) as u64, 4)?;
+
+        for &i in &CODE_LENGTH_ORDER[..self.hclen] {
+            writer.write_number(self.code_length.lengths[i] as u64, 3)?;
+        }
+
+        for &(symbol, extra, extra_length) in &self.code_lengths {
+            self.code_length.write(writer, symbol)?;
+            writer.write_number(extra, extra_length)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn last_used(lengths: &[u8]) -> usize {
+    lengths.iter().rposition(|&l| l != 0).map(|i| i + 1).unwrap_or(0)
+}
+
+// 3.2.7 - Uses codes 16, 17 and 18 to compress runs of code lengths
+fn run_length_encode(lengths: &[u8]) -> Vec<(usize, u64, usize)> {
+    let mut result = vec![];
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let value = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == value).count();
+        i += run;
+
+        if value == 0 {
+            while run >= 11 {
+                let count = cmp::min(run, 138);
+                result.push((18, (count - 11) as u64, 7));
+                run -= count;
+            }
+            if run >= 3 {
+                result.push((17, (run - 3) as u64, 3));
+                run = 0;
+            }
+        } else {
+            result.push((value as usize, 0, 0));
+            run -= 1;
+            while run >= 3 {
+                let count = cmp::min(run, 6);
+                result.push((16, (count - 3) as u64, 2));
+                run -= count;
+            }
+        }
+
+        for _ in 0..run {
+            result.push((value as usize, 0, 0));
+        }
+    }
+
+    result
+}
+
+// Builds length-limited Huffman code lengths for the given symbol
+// frequencies. The resulting code is always complete, as some decoders
+// (zlib included) reject incomplete codes.
+fn build_code_lengths(frequencies: &[u32], max_length: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; frequencies.len()];
+
+    let mut used: Vec<usize> = (0..frequencies.len())
+        .filter(|&i| frequencies[i] > 0)
+        .collect();
+
+    // A single code would be incomplete, so we pad the alphabet with unused
+    // symbols up to two codes.
+    let mut padding = 0;
+    while used.len() < 2 {
+        if !used.contains(&padding) {
+            used.push(padding);
+        }
+        padding += 1;
+    }
+
+    // Standard Huffman construction, nodes after `frequencies.len()` are
+    // internal nodes.
+    let mut parents = vec![0usize; frequencies.len() * 2];
+    let mut heap = BinaryHeap::new();
+    for &i in &used {
+        heap.push(Reverse((frequencies[i] as u64, i)));
+    }
+
+    let mut next = frequencies.len();
+    while heap.len() > 1 {
+        let Reverse((a_frequency, a)) = heap.pop().unwrap();
+        let Reverse((b_frequency, b)) = heap.pop().unwrap();
+        parents[a] = next;
+        parents[b] = next;
+        heap.push(Reverse((a_frequency + b_frequency, next)));
+        next += 1;
+    }
+    let root = next - 1;
+
+    for &i in &used {
+        let mut depth = 0;
+        let mut node = i;
+        while node != root {
+            node = parents[node];
+            depth += 1;
+        }
+        lengths[i] = cmp::min(depth, max_length) as u8;
+    }
+
+    // Clamping the lengths might have over-subscribed the code, so we make
+    // the shortest codes longer until the Kraft inequality holds again.
+    // Lengths are measured in units of 2^-max_length.
+    let capacity = 1u64 << max_length;
+    let mut kraft: u64 = used.iter()
+        .map(|&i| 1u64 << (max_length - lengths[i] as usize))
+        .sum();
+
+    while kraft > capacity {
+        let i = *used.iter()
+            .filter(|&&i| (lengths[i] as usize) < max_length)
+            .max_by_key(|&&i| (lengths[i], Reverse(frequencies[i])))
+            .unwrap();
+        lengths[i] += 1;
+        kraft -= 1 << (max_length - lengths[i] as usize);
+    }
+
+    // And then use any leftover space to shorten the longest codes.
+    while kraft < capacity {
+        let i = *used.iter()
+            .filter(|&&i| kraft + (1 << (max_length - lengths[i] as usize))
+                             <= capacity)
+            .max_by_key(|&&i| (lengths[i], frequencies[i]))
+            .unwrap();
+        kraft += 1 << (max_length - lengths[i] as usize);
+        lengths[i] -= 1;
+    }
+
+    lengths
+}
+
+struct HuffmanEncoder {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+}
+
+impl HuffmanEncoder {
+    // RFC1951 ~ 3.2.2
+    fn new(lengths: Vec<u8>) -> HuffmanEncoder {
+        let max_length = *lengths.iter().max().unwrap_or(&0) as usize;
+
+        let mut bl_count = vec![0u16; max_length + 1];
+        for &length in &lengths {
+            if length != 0 {
+                bl_count[length as usize] += 1;
+            }
+        }
+
+        let mut next_code = vec![0u16; max_length + 1];
+        let mut code = 0;
+        for bits in 1..=max_length {
+            code = (code + bl_count[bits - 1]) << 1;
+            next_code[bits] = code;
+        }
+
+        let codes = lengths.iter().map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next_code[length as usize];
+            next_code[length as usize] += 1;
+            code
+        }).collect();
+
+        HuffmanEncoder { lengths, codes }
+    }
+
+    // 3.2.6
+    fn fixed_literal() -> HuffmanEncoder {
+        let mut lengths = vec![8; 288];
+        for length in &mut lengths[144..256] { *length = 9; }
+        for length in &mut lengths[256..280] { *length = 7; }
+        HuffmanEncoder::new(lengths)
+    }
+
+    fn fixed_distance() -> HuffmanEncoder {
+        HuffmanEncoder::new(vec![5; 30])
+    }
+
+    fn write<W: Write>(&self, writer: &mut BitWriter<W>,
+                       symbol: usize) -> io::Result<()> {
+        writer.write_bits(self.codes[symbol] as u64,
+                          self.lengths[symbol] as usize)
+    }
+}
+
+// Finds LZ77 back references using hash chains of 3-byte prefixes
+struct Matcher<'a> {
+    data: &'a [u8],
+    // Most recent position for each hash value
+    head: Vec<usize>,
+    // Previous position with the same hash, indexed by position in the window
+    previous: Vec<usize>,
+}
+
+const NO_POSITION: usize = usize::MAX;
+
+impl <'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Matcher<'a> {
+        Matcher {
+            data,
+            head: vec![NO_POSITION; HASH_SIZE],
+            previous: vec![NO_POSITION; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.data[position..position + MIN_MATCH];
+        let value = ((bytes[0] as usize) << 16)
+            | ((bytes[1] as usize) << 8)
+            | bytes[2] as usize;
+        (value.wrapping_mul(2654435761) >> 8) & (HASH_SIZE - 1)
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return;
+        }
+
+        let hash = self.hash(position);
+        self.previous[position & WINDOW_MASK] = self.head[hash];
+        self.head[hash] = position;
+    }
+
+    // Returns the longest (length, distance) match for the data at
+    // `position`, the length is 0 when no match is found.
+    fn find(&self, position: usize, parameters: &Parameters) -> (usize, usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return (0, 0);
+        }
+
+        let max_length = cmp::min(MAX_MATCH, self.data.len() - position);
+        let current = &self.data[position..position + max_length];
+
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(position)];
+        let mut chain = parameters.max_chain;
+
+        while candidate != NO_POSITION && chain > 0 {
+            if candidate >= position || position - candidate > WINDOW_SIZE {
+                break;
+            }
+
+            let length = current.iter()
+                .zip(&self.data[candidate..])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if length > best.0 {
+                best = (length, position - candidate);
+                if length >= parameters.nice_length || length == max_length {
+                    break;
+                }
+            }
+
+            candidate = self.previous[candidate & WINDOW_MASK];
+            chain -= 1;
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{
+        Cursor,
+        Read,
+    };
+
+    use crate::bit_reader::BitReader;
+    use crate::deflate::{
+        rfc1950,
+        rfc1950_with_options,
+        rfc1951,
+        InflateOptions,
+    };
+    use crate::gzip::rfc1952_compress;
+    use crate::inflater::Inflater;
+    use crate::test_data::pseudo_random;
+
+    const FORMATS: [Format; 3] = [Format::Raw, Format::Zlib, Format::Gzip];
+
+    const LEVELS: [CompressionLevel; 4] = [
+        CompressionLevel::Stored,
+        CompressionLevel::Fast,
+        CompressionLevel::Default,
+        CompressionLevel::Best,
+    ];
+
+    fn round_trip(data: &[u8], level: CompressionLevel) -> usize {
+        let mut compressed = vec![];
+        let written = rfc1951_compress(data, &mut compressed, level).unwrap();
+        assert_eq!(written, compressed.len());
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(compressed)));
+        let mut decompressed = Cursor::new(vec![]);
+        rfc1951(&mut reader, &mut decompressed).unwrap();
+
+        assert_eq!(decompressed.into_inner(), data);
+        written
+    }
+
+    fn text(length: usize) -> Vec<u8> {
+        let words = ["deflate ", "inflate ", "huffman ", "stream ", "block ",
+                     "the ", "a ", "window ", "code\n"];
+        let random = pseudo_random(length);
+        let mut result = vec![];
+        let mut i = 0;
+        while result.len() < length {
+            result.extend_from_slice(
+                words[random[i] as usize % words.len()].as_bytes());
+            i += 1;
+        }
+        result.truncate(length);
+        result
+    }
+
+    #[test]
+    fn test_round_trip_empty() {
+        for &level in &LEVELS {
+            round_trip(&[], level);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_short() {
+        for &level in &LEVELS {
+            round_trip(b"a", level);
+            round_trip(b"TestingTesting", level);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_repetitive() {
+        let data = vec![0x42; 100_000];
+        for &level in &LEVELS {
+            let size = round_trip(&data, level);
+            if level != CompressionLevel::Stored {
+                assert!(size < 1000);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_text() {
+        let data = text(200_000);
+        let stored = round_trip(&data, CompressionLevel::Stored);
+        let fast = round_trip(&data, CompressionLevel::Fast);
+        let default = round_trip(&data, CompressionLevel::Default);
+        let best = round_trip(&data, CompressionLevel::Best);
+
+        assert!(fast < stored / 2);
+        assert!(default <= fast);
+        assert!(best <= default);
+    }
+
+    #[test]
+    fn test_round_trip_random() {
+        let data = pseudo_random(150_000);
+        for &level in &LEVELS {
+            let size = round_trip(&data, level);
+            // Incompressible data should fall back to stored blocks
+            assert!(size < data.len() + 100);
+        }
+    }
+
+    #[test]
+    fn test_rfc1950_round_trip() {
+        let data = text(50_000);
+        for &level in &LEVELS {
+            let mut compressed = vec![];
+            rfc1950_compress(&data, &mut compressed, level).unwrap();
+
+            let header = ((compressed[0] as u16) << 8) + compressed[1] as u16;
+            assert_eq!(header % 31, 0);
+
+            let mut reader = BitReader::new(Box::new(Cursor::new(compressed)));
+            let mut decompressed = Cursor::new(vec![]);
+            rfc1950(&mut reader, &mut decompressed).unwrap();
+            assert_eq!(decompressed.into_inner(), data);
+        }
+    }
+
+    #[test]
+    fn test_build_code_lengths() {
+        // Skewed frequencies would make some codes longer than 7 bits
+        let frequencies: Vec<u32> = (0..19).map(|i| 1 << i).collect();
+        let lengths = build_code_lengths(&frequencies, 7);
+
+        assert!(lengths.iter().all(|&l| l > 0 && l <= 7));
+        let kraft: u32 = lengths.iter().map(|&l| 1 << (7 - l)).sum();
+        assert_eq!(kraft, 1 << 7);
+    }
+
+    #[test]
+    fn test_build_code_lengths_single_symbol() {
+        let mut frequencies = vec![0; 30];
+        frequencies[5] = 10;
+        let lengths = build_code_lengths(&frequencies, 15);
+
+        assert_eq!(lengths[5], 1);
+        assert_eq!(lengths.iter().filter(|&&l| l != 0).count(), 2);
+    }
+
+    #[test]
+    fn test_run_length_encode() {
+        let mut lengths = vec![8; 10];
+        lengths.extend_from_slice(&[0; 150]);
+        lengths.extend_from_slice(&[5, 0, 0, 0]);
+
+        assert_eq!(run_length_encode(&lengths), vec![
+            (8, 0, 0), (16, 3, 2), (16, 0, 2),
+            (18, 127, 7), (18, 1, 7),
+            (5, 0, 0), (17, 0, 3)]);
+    }
+
+    #[test]
+    fn test_writer_single_block() {
+        // Less than a block comes out the same as the one-shot functions
+        let data = text(10_000);
+        for &format in &FORMATS {
+            for &level in &LEVELS {
+                let mut expected = vec![];
+                match format {
+                    Format::Raw => rfc1951_compress(&data, &mut expected, level),
+                    Format::Zlib => rfc1950_compress(&data, &mut expected, level),
+                    Format::Gzip => rfc1952_compress(&data, &mut expected, level,
+                                                     &GzipHeader::default()),
+                }.unwrap();
+
+                let mut writer = CompressWriter::new(vec![], format, level)
+                    .unwrap();
+                writer.write_all(&data).unwrap();
+                assert_eq!(writer.finish().unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_writer_round_trip() {
+        let data = text(3 * WRITER_BLOCK_SIZE + 1000);
+        for &format in &FORMATS {
+            for &level in &[CompressionLevel::Stored, CompressionLevel::Fast] {
+                let mut writer = CompressWriter::new(vec![], format, level)
+                    .unwrap();
+                for (i, chunk) in data.chunks(10_000).enumerate() {
+                    writer.write_all(chunk).unwrap();
+                    if i == 3 {
+                        writer.flush().unwrap();
+                        assert!(writer.written() > 0);
+                    }
+                }
+                let written = writer.written();
+                let compressed = writer.finish().unwrap();
+                assert!(compressed.len() > written);
+
+                let mut decompressed = vec![];
+                Inflater::new(Cursor::new(compressed), format)
+                    .read_to_end(&mut decompressed).unwrap();
+                assert_eq!(decompressed, data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_writer_empty() {
+        for &format in &FORMATS {
+            let compressed = CompressWriter::new(vec![], format,
+                                                 CompressionLevel::Default)
+                .unwrap().finish().unwrap();
+
+            let mut decompressed = vec![];
+            Inflater::new(Cursor::new(compressed), format)
+                .read_to_end(&mut decompressed).unwrap();
+            assert!(decompressed.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_rfc1950_dictionary_round_trip() {
+        let dictionary = b"ABCDEFGHILMNOPQRSTUVZ0123456789\n".repeat(2000);
+        let data = b"ABCDEFGHILMNOPQRSTUVZ0123456789\n".repeat(10);
+
+        let mut plain = vec![];
+        rfc1950_compress(&data, &mut plain, CompressionLevel::Default)
+            .unwrap();
+
+        for &level in &LEVELS {
+            let mut compressed = vec![];
+            rfc1950_compress_with_dictionary(&data, &mut compressed, level,
+                                             &dictionary).unwrap();
+            if level != CompressionLevel::Stored {
+                assert!(compressed.len() < plain.len());
+            }
+
+            let options = InflateOptions {
+                dictionaries: vec![dictionary.clone()],
+                ..InflateOptions::default()
+            };
+            let mut reader = BitReader::new(Box::new(Cursor::new(compressed)));
+            let mut decompressed = vec![];
+            rfc1950_with_options(&mut reader, &mut decompressed, &options)
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+}
@@ -10,12 +10,127 @@ use std::io::{
 };
 
 use std::cmp;
-use std::mem;
+use std::convert::TryInto;
+use std::sync::OnceLock;
 
 use crate::bit_reader::{
     ReadBits,
     BitReader,
 };
+use crate::checksum::{
+    Adler32,
+    Checksum,
+    ChecksumMismatch,
+    ChecksumWriter,
+};
+use crate::error::{
+    DeflateError,
+    Limit,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InflateOptions {
+    // Whether to check the Adler-32, CRC-32 and size trailers. When this is
+    // off the trailers are optional and mismatches are ignored.
+    pub verify_checksums: bool,
+    // Whether to decompress every member of a gzip file instead of stopping
+    // after the first one.
+    pub multi_member: bool,
+    // Untrusted input can expand to a lot more data than it's worth keeping
+    // around. Decompression stops with `DeflateError::LimitExceeded` once
+    // the output is bigger than `max_output` bytes or more than `max_ratio`
+    // times the compressed input read so far.
+    pub max_output: Option<u64>,
+    pub max_ratio: Option<u64>,
+    // Nothing after the end of the stream is read, except to look for more
+    // gzip members. With this set, a zlib trailer that can't be read or
+    // data after the final block that isn't another gzip member is taken to
+    // be padding and ignored instead of being an error. A zlib trailer that
+    // doesn't match is still an error when checksums are verified.
+    pub ignore_trailing_data: bool,
+    // Recovery mode for truncated or corrupt data: instead of failing, the
+    // decoders hand out everything decoded up to the point where the data
+    // stops making sense and report the error in `Inflated::error`. Errors
+    // in the zlib or gzip header and the limits above still fail.
+    // `PushInflater` can only tell the input is truncated in `finish`.
+    pub recover: bool,
+    // RFC1950 ~ 2.2 - Preset dictionaries for zlib streams with FDICT set,
+    // the stream picks one by its Adler-32.
+    pub dictionaries: Vec<Vec<u8>>,
+}
+
+impl Default for InflateOptions {
+    fn default() -> InflateOptions {
+        InflateOptions {
+            verify_checksums: true,
+            multi_member: false,
+            max_output: None,
+            max_ratio: None,
+            ignore_trailing_data: false,
+            recover: false,
+            dictionaries: vec![],
+        }
+    }
+}
+
+// What the `_with_options` decoders return
+#[derive(Debug)]
+pub struct Inflated {
+    // Bytes of input that belong to the stream, counting from where the
+    // reader was when decoding started. Anything after it is not part of
+    // the stream, even if it was read.
+    pub consumed: u64,
+    // Size of the decompressed data
+    pub size: usize,
+    // In recovery mode, why decoding stopped early. `consumed` is then the
+    // position where the error was found and `size` covers the output
+    // decoded before it.
+    pub error: Option<DeflateError>,
+}
+
+// Keeps track of the limits in `InflateOptions` over a whole stream, the
+// members of a gzip file all count towards the same limits.
+pub(crate) struct Limits {
+    max_output: Option<u64>,
+    max_ratio: Option<u64>,
+    // Position of the start of the stream in the input
+    start: u64,
+    output: u64,
+}
+
+impl Limits {
+    pub fn new(options: &InflateOptions, start: u64) -> Limits {
+        Limits {
+            max_output: options.max_output,
+            max_ratio: options.max_ratio,
+            start,
+            output: 0,
+        }
+    }
+
+    // Accounts for `length` more bytes of output, having read the input up
+    // to `position`. This needs to be called before the output is handed
+    // out.
+    pub fn add(&mut self, position: u64, length: usize)
+            -> Result<(), DeflateError> {
+        self.output += length as u64;
+
+        if let Some(limit) = self.max_output {
+            if self.output > limit {
+                return Err(DeflateError::LimitExceeded(Limit::Output(limit)));
+            }
+        }
+
+        if let Some(limit) = self.max_ratio {
+            let input = cmp::max(position.saturating_sub(self.start), 1);
+            if self.output > input.saturating_mul(limit) {
+                return Err(DeflateError::LimitExceeded(Limit::Ratio(limit)));
+            }
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 enum EncodingType {
@@ -35,9 +150,44 @@ impl EncodingType {
     }
 }
 
-// This looks like a rust bug? This function is clearly used
-#[allow(dead_code)]
-pub fn rfc1950(data: &mut BitReader, out: &mut Write) -> io::Result<usize> {
+pub fn rfc1950(data: &mut BitReader, out: &mut dyn Write) -> Result<usize, DeflateError> {
+    rfc1950_with_options(data, out, &InflateOptions::default())
+        .map(|inflated| inflated.size)
+}
+
+pub fn rfc1950_with_options(data: &mut BitReader, out: &mut dyn Write,
+                            options: &InflateOptions)
+        -> Result<Inflated, DeflateError> {
+    let start = data.position();
+    let mut limits = Limits::new(options, start);
+    let dictionary = read_zlib_header(data, options)?;
+
+    let mut out = ChecksumWriter::new(out, Adler32::new());
+    let mut inflated = inflate_with_limits(data, &mut out, &mut limits,
+                                           options.recover, dictionary)?;
+    if inflated.error.is_some() {
+        inflated.consumed = data.position() - start;
+        return Ok(inflated);
+    }
+
+    let end = match check_zlib_trailer(data, out.value(), options) {
+        Ok(end) => end,
+        Err(e) if options.recover => {
+            inflated.error = Some(e);
+            data.position()
+        },
+        Err(e) => return Err(e),
+    };
+
+    inflated.consumed = end - start;
+    Ok(inflated)
+}
+
+// 2.2 - Data format. Returns the preset dictionary the stream needs, which
+// is empty when FDICT isn't set.
+pub(crate) fn read_zlib_header<'a>(data: &mut BitReader,
+                                   options: &'a InflateOptions)
+        -> Result<&'a [u8], DeflateError> {
     let compression_method = data.read_number(4)?;
     let compression_info = data.read_number(4)?;
     let check_bits = data.read_number(5)?;
@@ -51,103 +201,473 @@ pub fn rfc1950(data: &mut BitReader, out: &mut Write) -> io::Result<usize> {
         + ((preset_dictionary as u16) << 5)
         +  (check_bits as u16);
 
-    assert!(checksum % 31 == 0);
-    assert!(compression_method == 8);
+    if !checksum.is_multiple_of(31) {
+        return Err(DeflateError::ZlibHeaderChecksum);
+    }
+
+    if compression_method != 8 {
+        return Err(DeflateError::UnsupportedCompressionMethod(
+            compression_method as u8));
+    }
 
-    if checksum % 31 != 0 || compression_method != 8 {
-        // return Err(Error::new(ErrorKind::Other, "Header checksum doesn't mach."));
-        panic!();
+    // CINFO is the base-2 logarithm of the window size minus eight
+    if compression_info > 7 {
+        return Err(DeflateError::InvalidWindowSize(compression_info as u8));
     }
 
-    if preset_dictionary > 0 {
-        // TODO: checksum
-        let _adler32 = data.read_number(32)?;
+    if preset_dictionary == 0 {
+        return Ok(&[]);
     }
 
-    rfc1951(data, out)
+    // DICTID is the Adler-32 of the dictionary, stored MSB first
+    let id = (data.read_number(32)? as u32).swap_bytes();
+    options.dictionaries.iter()
+        .find(|dictionary| {
+            let mut adler32 = Adler32::new();
+            adler32.update(dictionary);
+            adler32.value() == id
+        })
+        .map(|dictionary| &dictionary[..])
+        .ok_or(DeflateError::MissingDictionary(id))
+}
+
+// Returns the position of the end of the stream, which is before the
+// trailer if the trailer is ignored.
+pub(crate) fn check_zlib_trailer(data: &mut BitReader, actual: u32,
+                                 options: &InflateOptions)
+        -> Result<u64, DeflateError> {
+    let end = data.position();
+
+    // 2.2 - ADLER32 is stored MSB first
+    let adler32 = data.read_remaining_byte()
+        .and_then(|_| data.read_number(32))
+        .map(|x| (x as u32).swap_bytes());
+
+    match adler32 {
+        Ok(expected) if expected == actual => Ok(data.position()),
+        Ok(expected) if options.verify_checksums =>
+            Err(ChecksumMismatch::Adler32 { expected, actual }.into()),
+        // Whatever follows the final block is not the trailer
+        _ if options.ignore_trailing_data => Ok(end),
+        Ok(_) => Ok(data.position()),
+        Err(e) if options.verify_checksums => Err(e.into()),
+        Err(_) => Ok(end),
+    }
 }
 
 // 3.2.5 - This is the maximum distance that each compressed block can
 // reference, note that distance lookbacks can cross block boundaries.
-const MAX_LOOKBACK: usize = 32768;
+pub(crate) const MAX_LOOKBACK: usize = 32768;
 
-pub fn rfc1951(data: &mut BitReader, out: &mut Write) -> io::Result<usize> {
-    let mut decoded = vec![];
+pub fn rfc1951(data: &mut BitReader, out: &mut dyn Write)
+        -> Result<usize, DeflateError> {
+    rfc1951_with_options(data, out, &InflateOptions::default())
+        .map(|inflated| inflated.size)
+}
+
+// Only the limits in `options` apply to raw deflate data, which has no
+// trailer and ends with the final block.
+pub fn rfc1951_with_options(data: &mut BitReader, out: &mut dyn Write,
+                            options: &InflateOptions)
+        -> Result<Inflated, DeflateError> {
+    let mut limits = Limits::new(options, data.position());
+    inflate_with_limits(data, out, &mut limits, options.recover, &[])
+}
+
+// Decodes the deflate data of a stream. When `recover` is set, errors in
+// the data end up in `Inflated::error` after flushing the partial output.
+pub(crate) fn inflate_with_limits(data: &mut BitReader, out: &mut dyn Write,
+                                  limits: &mut Limits, recover: bool,
+                                  dictionary: &[u8])
+        -> Result<Inflated, DeflateError> {
+    let start = data.position();
+    let mut inflate = Inflate::new();
+    inflate.keep_partial = recover;
+    inflate.set_dictionary(dictionary);
     let mut length = 0;
+    let mut error = None;
 
     loop {
-        let bfinal = data.read_bits(1)?;
-        let btype = data.read_bits(2)?;
-        let fixed_literal_code = generate_fixed_huffman();
-        let fixed_distance_code = generate_fixed_distance_code();
-
-        match EncodingType::from(btype).unwrap() {
-            EncodingType::NoCompression => {
-                decoded.append(&mut read_no_compression(data)?);
-            },
-            EncodingType::FixedHuffman => {
-                let adapter = HuffmanAdapter::new(data,
-                    &fixed_literal_code, Some(&fixed_distance_code));
-                read_huffman(adapter, &mut decoded)?;
-            },
-            EncodingType::DynamicHuffman => {
-                let (literal_code, distance_code) =
-                        read_huffman_code(data)?;
-                let adapter = HuffmanAdapter::new(data, &literal_code,
-                                                  Some(&distance_code));
-                read_huffman(adapter, &mut decoded)?;
-            },
+        match inflate.fill(data, MAX_LOOKBACK) {
+            Ok(()) => {},
+            Err(e) if recover => error = Some(e),
+            Err(e) => return Err(e),
         }
+        limits.add(data.position(), inflate.pending_len())?;
+
+        let (first, second) = inflate.pending();
+        out.write_all(first)?;
+        out.write_all(second)?;
 
-        if decoded.len() > 2 * MAX_LOOKBACK {
-            let extra_length = decoded.len() - MAX_LOOKBACK;
-            let written = out.write(&decoded[..extra_length]);
+        let consumed = first.len() + second.len();
+        inflate.consume(consumed);
+        length += consumed;
 
-            if let Ok(count) = written {
-              length += count;
-              decoded.drain(0..count);
+        if inflate.is_done() || error.is_some() {
+            break;
+        }
+    }
+
+    Ok(Inflated {
+        consumed: data.position() - start,
+        size: length,
+        error,
+    })
+}
+
+enum State {
+    BlockHeader,
+    // Number of bytes left in the stored block
+    Stored(usize),
+    FixedHuffman,
+    DynamicHuffman(HuffmanCode, HuffmanCode),
+    Done,
+}
+
+// 3.2.5 - The longest match
+const MAX_LENGTH: usize = 258;
+
+// The window is kept in a ring buffer together with the output that the
+// caller hasn't consumed yet. This needs to be a power of two.
+const RING_SIZE: usize = 4 * MAX_LOOKBACK;
+const RING_MASK: usize = RING_SIZE - 1;
+
+// The most output that can be pending before a step, a step adds at most a
+// match on top of this.
+const MAX_PENDING: usize = RING_SIZE - MAX_LOOKBACK - MAX_LENGTH;
+
+struct Window {
+    ring: Box<[u8; RING_SIZE]>,
+    // Total number of bytes written and consumed so far, the positions in
+    // the ring are these modulo RING_SIZE.
+    written: usize,
+    consumed: usize,
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            ring: vec![0; RING_SIZE].into_boxed_slice().try_into().unwrap(),
+            written: 0,
+            consumed: 0,
+        }
+    }
+
+    fn pending_len(&self) -> usize {
+        self.written - self.consumed
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.ring[self.written & RING_MASK] = byte;
+        self.written += 1;
+    }
+
+    // Makes the end of `dictionary` available to matches without it being
+    // part of the output
+    fn preset(&mut self, dictionary: &[u8]) {
+        let start = dictionary.len().saturating_sub(MAX_LOOKBACK);
+        for &byte in &dictionary[start..] {
+            self.push(byte);
+        }
+        self.consumed = self.written;
+    }
+
+    // 3.2.3 - Copies `length` bytes starting `distance` bytes back, the two
+    // ranges can overlap in which case the bytes are repeated.
+    fn copy_match(&mut self, distance: usize, length: usize)
+            -> Result<(), DeflateError> {
+        if self.written < distance {
+            return Err(DeflateError::InvalidDistance {
+                distance,
+                available: self.written,
+            });
+        }
+
+        let mut from = (self.written - distance) & RING_MASK;
+        let mut to = self.written & RING_MASK;
+
+        if distance >= length && from + length <= RING_SIZE
+                && to + length <= RING_SIZE {
+            self.ring.copy_within(from .. from + length, to);
+        } else {
+            for _ in 0..length {
+                self.ring[to] = self.ring[from];
+                from = (from + 1) & RING_MASK;
+                to = (to + 1) & RING_MASK;
             }
         }
 
-        if bfinal > 0 {
-            length += decoded.len();
-            out.write_all(&decoded[..])?;
-            break;
+        self.written += length;
+        Ok(())
+    }
+
+    // Reads up to `length` bytes straight from `data`
+    fn read_from(&mut self, data: &mut BitReader, length: usize)
+            -> io::Result<usize> {
+        let start = self.written & RING_MASK;
+        let end = cmp::min(start + length, RING_SIZE);
+
+        let read = data.read(&mut self.ring[start..end])?;
+        self.written += read;
+
+        Ok(read)
+    }
+
+    // The last `length` bytes written, consumed or not
+    fn last(&self, length: usize) -> Vec<u8> {
+        assert!(length <= self.written && length <= RING_SIZE);
+
+        (self.written - length .. self.written)
+            .map(|i| self.ring[i & RING_MASK])
+            .collect()
+    }
+
+    // The pending bytes, in two pieces when they wrap around the ring
+    fn pending(&self) -> (&[u8], &[u8]) {
+        let start = self.consumed & RING_MASK;
+        let end = start + self.pending_len();
+
+        if end <= RING_SIZE {
+            (&self.ring[start..end], &[])
+        } else {
+            (&self.ring[start..], &self.ring[..end - RING_SIZE])
         }
     }
+}
 
-    Ok(length)
+// The decoder state for a single deflate stream, this decodes the stream
+// a little at a time so that callers don't need to hold the whole output in
+// memory.
+pub(crate) struct Inflate {
+    state: State,
+    final_block: bool,
+    window: Window,
+    // Whether a step that fails keeps the symbols it decoded before the
+    // error, for when the step isn't going to be retried.
+    pub keep_partial: bool,
 }
 
-#[derive(Debug)]
-struct Code {
-    length: usize,
-    data: Vec<i64>,
+impl Inflate {
+    pub fn new() -> Inflate {
+        Inflate {
+            state: State::BlockHeader,
+            final_block: false,
+            window: Window::new(),
+            keep_partial: false,
+        }
+    }
+
+    // RFC1950 ~ 2.2 - Needs to be called before decoding anything
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.window.preset(dictionary);
+    }
+
+    // Decodes until at least `wanted` bytes are pending or the end of the
+    // stream is reached, this can overshoot by up to a match length. The
+    // ring buffer limits how much output can be pending, so `wanted` might
+    // not be reached.
+    pub fn fill(&mut self, data: &mut BitReader, wanted: usize)
+            -> Result<(), DeflateError> {
+        self.fill_impl(data, wanted, false)
+    }
+
+    // Like `fill`, but also stops at the end of each block
+    pub fn fill_block(&mut self, data: &mut BitReader, wanted: usize)
+            -> Result<(), DeflateError> {
+        self.fill_impl(data, wanted, true)
+    }
+
+    fn fill_impl(&mut self, data: &mut BitReader, wanted: usize,
+                 stop_at_blocks: bool) -> Result<(), DeflateError> {
+        let wanted = cmp::min(wanted, MAX_PENDING);
+
+        while self.window.pending_len() < wanted && !self.is_finished() {
+            let pending = self.window.pending_len();
+            self.step(data, wanted - pending)?;
+
+            if stop_at_blocks && self.at_block_boundary() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decodes a block header, a single symbol or up to `limit` bytes of a
+    // stored block. When this fails the state and the output are left
+    // untouched, so that the step can be retried once more input is
+    // available.
+    pub fn step(&mut self, data: &mut BitReader, limit: usize)
+            -> Result<(), DeflateError> {
+        assert!(self.window.pending_len() <= MAX_PENDING);
+
+        let written = self.window.written;
+        let result = self.step_impl(data, limit);
+
+        if result.is_err() && !self.keep_partial {
+            self.window.written = written;
+        }
+
+        result
+    }
+
+    fn step_impl(&mut self, data: &mut BitReader, limit: usize)
+            -> Result<(), DeflateError> {
+        match self.state {
+            State::BlockHeader => {
+                if self.final_block {
+                    self.state = State::Done;
+                    return Ok(());
+                }
+
+                let final_block = data.read_bits(1)? > 0;
+                let btype = data.read_bits(2)?;
+
+                // 3.2.7 - The whole dynamic header is read in one go
+                let encoding_type = EncodingType::from(btype)
+                    .ok_or(DeflateError::ReservedBlockType)?;
+
+                self.state = match encoding_type {
+                    EncodingType::NoCompression =>
+                        State::Stored(read_no_compression_length(data)?),
+                    EncodingType::FixedHuffman =>
+                        State::FixedHuffman,
+                    EncodingType::DynamicHuffman => {
+                        let (literal_code, distance_code) =
+                            read_huffman_code(data)?;
+                        State::DynamicHuffman(literal_code, distance_code)
+                    },
+                };
+                self.final_block = final_block;
+            },
+            State::Stored(remaining) => {
+                let limit = cmp::min(limit, MAX_PENDING - self.window.pending_len());
+                let length = cmp::min(remaining, cmp::max(limit, 1));
+
+                // Take whatever is available right now, the rest of the
+                // block will be read by the next steps.
+                let read = self.window.read_from(data, length)?;
+
+                if read == 0 && length > 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof,
+                        "Stored block is truncated.").into());
+                }
+
+                self.state = if remaining == read {
+                    State::BlockHeader
+                } else {
+                    State::Stored(remaining - read)
+                };
+            },
+            State::FixedHuffman => {
+                let (literal_code, distance_code) = fixed_codes();
+                let adapter = HuffmanAdapter::new(data, literal_code,
+                                                  Some(distance_code));
+                if !read_huffman_symbols(adapter, &mut self.window, limit)? {
+                    self.state = State::BlockHeader;
+                }
+            },
+            State::DynamicHuffman(ref literal_code, ref distance_code) => {
+                let adapter = HuffmanAdapter::new(data, literal_code,
+                                                  Some(distance_code));
+                if !read_huffman_symbols(adapter, &mut self.window, limit)? {
+                    self.state = State::BlockHeader;
+                }
+            },
+            State::Done => {},
+        }
+
+        Ok(())
+    }
+
+    // Whether the next step reads the header of a new block, decoding can
+    // resume from here with just the window.
+    pub fn at_block_boundary(&self) -> bool {
+        matches!(self.state, State::BlockHeader) && !self.final_block
+    }
+
+    // The data that matches of the next block can refer to
+    pub fn window(&self) -> Vec<u8> {
+        self.window.last(cmp::min(self.window.written, MAX_LOOKBACK))
+    }
+
+    // Whether the final block has been decoded, there might still be
+    // pending output.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    // Decoded bytes that haven't been consumed yet, in two pieces when they
+    // wrap around the window.
+    pub fn pending(&self) -> (&[u8], &[u8]) {
+        self.window.pending()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.window.pending_len()
+    }
+
+    pub fn consume(&mut self, length: usize) {
+        assert!(length <= self.window.pending_len());
+        self.window.consumed += length;
+    }
+
+    // Copies pending bytes into `buf` and consumes them
+    pub fn read_pending(&mut self, buf: &mut [u8]) -> usize {
+        let (first, second) = self.window.pending();
+
+        let first_length = cmp::min(first.len(), buf.len());
+        buf[..first_length].copy_from_slice(&first[..first_length]);
+
+        let second_length = cmp::min(second.len(), buf.len() - first_length);
+        buf[first_length .. first_length + second_length]
+            .copy_from_slice(&second[..second_length]);
+
+        self.consume(first_length + second_length);
+        first_length + second_length
+    }
+
+    // Whether the final block has been decoded and consumed entirely
+    pub fn is_done(&self) -> bool {
+        self.is_finished() && self.window.pending_len() == 0
+    }
+}
+
+// Lookup tables are indexed by this many bits of input, longer codes go
+// through a second table.
+const PRIMARY_BITS: usize = 9;
+const PRIMARY_MASK: usize = (1 << PRIMARY_BITS) - 1;
+
+// Table entries pack the symbol in the low 16 bits and the code length in
+// the next 8. Entries for codes longer than PRIMARY_BITS instead hold the
+// offset and the number of index bits of the second table. An empty entry
+// means that the code is not in use.
+const SUBTABLE: u32 = 1 << 24;
+
+fn entry_length(entry: u32) -> usize {
+    ((entry >> 16) & 0xFF) as usize
+}
+
+fn entry_value(entry: u32) -> usize {
+    (entry & 0xFFFF) as usize
 }
 
-#[derive(Debug)]
 struct HuffmanCode {
-    codes: Vec<Code>,
-    min_length: usize,
+    table: Vec<u32>,
     max_length: usize,
 }
 
+// 3.2.6 - The fixed codes are the same for every block, so they are only
+// generated once.
+fn fixed_codes() -> &'static (HuffmanCode, HuffmanCode) {
+    static FIXED_CODES: OnceLock<(HuffmanCode, HuffmanCode)> = OnceLock::new();
+    FIXED_CODES.get_or_init(||
+        (generate_fixed_huffman(), generate_fixed_distance_code()))
+}
+
 // Fixed distance codes are just 5-bit integers
 fn generate_fixed_distance_code() -> HuffmanCode {
-    let mut code_5_bits = vec![-1; 32];
-    for i in 0 .. 32 {
-        code_5_bits[i] = i as i64;
-    }
-
-    let codes = vec![
-        Code { length: 5, data: code_5_bits },
-    ];
-
-    HuffmanCode {
-        codes,
-        min_length: 5,
-        max_length: 5,
-    }
+    generate_codes(&[5; 32]).unwrap()
 }
 
 // Fixed huffman table
@@ -162,62 +682,51 @@ fn generate_fixed_distance_code() -> HuffmanCode {
 //   280 - 287     8          11000000 through
 //                            11000111
 fn generate_fixed_huffman() -> HuffmanCode {
-    let mut mapped = 0;
-
-    let mut code_8_bits = vec![-1; 256];
-    for i in 0b00110000 ..= 0b10111111 {
-        code_8_bits[i] = mapped;
-        mapped += 1;
-    }
-
-    let mut code_9_bits = vec![-1; 512];
-    for i in 0b110010000 ..= 0b111111111 {
-        code_9_bits[i] = mapped;
-        mapped += 1;
-    }
-
-    let mut code_7_bits = vec![-1; 128];
-    for i in 0b0000000 ..= 0b0010111 {
-        code_7_bits[i] = mapped;
-        mapped += 1;
-    }
-
-    for i in 0b11000000 ..= 0b11000111 {
-        code_8_bits[i] = mapped;
-        mapped += 1;
+    let mut code_lengths = [0; 288];
+    for (i, length) in code_lengths.iter_mut().enumerate() {
+        *length = match i {
+            0 ..= 143 => 8,
+            144 ..= 255 => 9,
+            256 ..= 279 => 7,
+            _ => 8,
+        };
     }
 
-    let codes = vec![
-        Code { length: 7, data: code_7_bits.to_vec() },
-        Code { length: 8, data: code_8_bits.to_vec() },
-        Code { length: 9, data: code_9_bits.to_vec() },
-    ];
-
-    HuffmanCode {
-        codes,
-        min_length: 7,
-        max_length: 9,
-    }
+    generate_codes(&code_lengths).unwrap()
 }
 
 // RFC1951 ~ 3.2.7
 fn read_huffman_code(data: &mut BitReader)
-        -> io::Result<(HuffmanCode, HuffmanCode)> {
+        -> Result<(HuffmanCode, HuffmanCode), DeflateError> {
     let hlit = data.read_number(5)? as usize + 257;
     let hdist = data.read_number(5)? as usize + 1;
     let hclen = data.read_number(4)? as usize + 4;
 
+    // Only 286 literal/length and 30 distance codes are defined
+    if hlit > 286 || hdist > 30 {
+        return Err(DeflateError::InvalidCodeLengths);
+    }
+
     let code_lengths = read_code_lengths(data, hclen)?;
 
-    let codes = generate_codes(&code_lengths);
+    let codes = generate_codes(&code_lengths)?;
 
     let mut adapter = HuffmanAdapter::new(data, &codes, None);
 
-    let literal_code_lengths = read_compressed_code_lengths(&mut adapter, hlit)?;
-    let literal_codes = generate_codes(&literal_code_lengths[..]);
+    // The literal and distance code lengths are a single sequence, a repeat
+    // can carry over from one to the other.
+    let code_lengths = read_compressed_code_lengths(&mut adapter,
+                                                    hlit + hdist)?;
+    let (literal_code_lengths, distance_code_lengths) =
+        code_lengths.split_at(hlit);
 
-    let distance_code_lengths = read_compressed_code_lengths(&mut adapter, hdist)?;
-    let distance_codes = generate_codes(&distance_code_lengths[..]);
+    // Without the end of block code the block can never end
+    if literal_code_lengths[256] == 0 {
+        return Err(DeflateError::InvalidCodeLengths);
+    }
+
+    let literal_codes = generate_codes(literal_code_lengths)?;
+    let distance_codes = generate_codes(distance_code_lengths)?;
 
     Ok((literal_codes, distance_codes))
 }
@@ -236,114 +745,142 @@ fn read_code_lengths(data: &mut BitReader, length: usize)
 
 // RFC1951 ~ 3.2.7
 fn read_compressed_code_lengths(data: &mut HuffmanAdapter, length: usize)
-        -> io::Result<Vec<u8>> {
-    let mut i = 0;
-    let mut result = vec![0; length];
-    let mut prev_code = 0;
+        -> Result<Vec<u8>, DeflateError> {
+    let mut result = Vec::with_capacity(length);
 
-    while i < length {
+    while result.len() < length {
         let code = data.next_code()?;
-        match code {
-            0 ... 15 => {
-                prev_code = code as u8;
-                result[i] = prev_code;
-                i += 1;
-            },
+        let (value, repeat) = match code {
+            0 ..= 15 => (code as u8, 1),
             16 => {
-                let length = data.read_number(2)? + 3;
-                for _ in 0..length {
-                    result[i] = prev_code;
-                    i += 1;
-                }
-            },
-            17 => {
-                let length = data.read_number(3)? + 3;
-                for _ in 0..length {
-                    result[i] = 0;
-                    i += 1;
-                }
-            },
-            18 => {
-                let length = data.read_number(7)? + 11;
-                for _ in 0..length {
-                    result[i] = 0;
-                    i += 1;
-                }
+                // Repeats the previous length, so there needs to be one
+                let previous = *result.last()
+                    .ok_or(DeflateError::InvalidCodeLengths)?;
+                (previous, data.read_number(2)? + 3)
             },
-            _ => {
-                // return Err(Error::new(ErrorKind::Other, "Unknown Huffman Code"));
-                panic!();
-            }
+            17 => (0, data.read_number(3)? + 3),
+            18 => (0, data.read_number(7)? + 11),
+            _ => return Err(DeflateError::InvalidHuffmanCode),
+        };
+
+        if result.len() + repeat as usize > length {
+            return Err(DeflateError::InvalidCodeLengths);
+        }
+
+        for _ in 0..repeat {
+            result.push(value);
         }
     }
+
     Ok(result)
 }
 
 // RFC1951 ~ 3.2.2
-fn generate_codes(code_lengths: &[u8]) -> HuffmanCode {
+fn generate_codes(code_lengths: &[u8]) -> Result<HuffmanCode, DeflateError> {
     // Step 1
-    let mut bl_count = vec![];
-    let mut min_length = code_lengths.len();
-    let mut max_length = 0;
-    for x in code_lengths {
-        let length = *x as usize;
-        if length < min_length && length != 0 { min_length = length }
-        if length > max_length { max_length = length }
-        if bl_count.len() <= length {
-            bl_count.resize(length + 1, 0);
+    let mut bl_count = [0; 16];
+    for &length in code_lengths {
+        bl_count[length as usize] += 1;
+    }
+    // 0 lengths are unused codes
+    bl_count[0] = 0;
+
+    let max_length = (0..16).rev()
+        .find(|&length| bl_count[length] > 0)
+        .unwrap_or(0);
+
+    // Each code of length n takes 2^-n of the code space, this needs to add
+    // up to exactly 1. The exception is a code with a single symbol, which
+    // has one bit that is never used.
+    let mut left: i64 = 1;
+    for &count in &bl_count[1..] {
+        left = (left << 1) - count as i64;
+        if left < 0 {
+            return Err(DeflateError::OversubscribedCodeLengths);
         }
+    }
 
-        // 0 lengths are unused codes
-        if length != 0 {
-            bl_count[length] += 1;
-        }
+    if left > 0 && max_length > 1 {
+        return Err(DeflateError::InvalidCodeLengths);
     }
 
     // Step 2
-    let mut next_code = vec![0; bl_count.len()];
+    let mut next_code = [0; 16];
     let mut code = 0;
-    for bits in 1..bl_count.len() {
+    for bits in 1..16 {
         code = (code + bl_count[bits - 1]) << 1;
         next_code[bits] = code;
     }
 
-    // Step 3
-    let max_code_lengths = *code_lengths.iter().max().unwrap_or(&0);
-    let mut codes = vec![vec![]; (max_code_lengths + 1) as usize];
+    // Step 3, the bits come in starting from the most significant one of
+    // the code so we index the tables by the reversed code.
+    let mut codes = vec![0; code_lengths.len()];
+    for (n, &length) in code_lengths.iter().enumerate() {
+        let length = length as usize;
+        if length == 0 { continue; }
 
-    for n in 0..code_lengths.len() {
-        let len = code_lengths[n] as usize;
-        if len == 0 { continue; }
+        codes[n] = (next_code[length] as u32).reverse_bits() >> (32 - length);
+        next_code[length] += 1;
+    }
 
-        if codes[len].len() == 0 {
-            codes[len] = vec![-1; 1 << len];
+    // Codes sharing the same first PRIMARY_BITS bits go in the same second
+    // table, which needs to be big enough for the longest of them.
+    let mut subtable_bits = [0; 1 << PRIMARY_BITS];
+    for (n, &length) in code_lengths.iter().enumerate() {
+        let length = length as usize;
+        if length > PRIMARY_BITS {
+            let prefix = codes[n] as usize & PRIMARY_MASK;
+            subtable_bits[prefix] = cmp::max(subtable_bits[prefix],
+                                             length - PRIMARY_BITS);
         }
-        codes[len][next_code[len]] = n as i64;
-        next_code[len] += 1;
     }
 
-    let mut result_codes = vec![];
-    for i in 1 ..= max_code_lengths as usize {
-        if codes[i].len() == 0 {
-            continue;
+    let mut table = vec![0; 1 << PRIMARY_BITS];
+    for (prefix, &bits) in subtable_bits.iter().enumerate() {
+        if bits > 0 {
+            table[prefix] = SUBTABLE | ((bits as u32) << 16)
+                | table.len() as u32;
+            table.resize(table.len() + (1 << bits), 0);
         }
+    }
 
-        let data = mem::replace(&mut codes[i], vec![]);
-        result_codes.push(Code { length: i, data });
+    for (n, &length) in code_lengths.iter().enumerate() {
+        let length = length as usize;
+        let code = codes[n] as usize;
+        if length == 0 { continue; }
+
+        // The bits after the code can be anything, so the entry is repeated
+        // for all their values.
+        if length <= PRIMARY_BITS {
+            let entry = ((length as u32) << 16) | n as u32;
+            for i in (code .. 1 << PRIMARY_BITS).step_by(1 << length) {
+                table[i] = entry;
+            }
+        } else {
+            let subtable = table[code & PRIMARY_MASK];
+            let offset = entry_value(subtable);
+            let bits = entry_length(subtable);
+
+            let entry = (((length - PRIMARY_BITS) as u32) << 16) | n as u32;
+            let step = 1 << (length - PRIMARY_BITS);
+            for i in (code >> PRIMARY_BITS .. 1 << bits).step_by(step) {
+                table[offset + i] = entry;
+            }
+        }
     }
 
-    HuffmanCode { codes: result_codes, min_length, max_length }
+    Ok(HuffmanCode { table, max_length })
 }
 
-struct HuffmanAdapter<'a> {
-    data: &'a mut BitReader,
+struct HuffmanAdapter<'a, 'b> {
+    data: &'a mut BitReader<'b>,
     coder: &'a HuffmanCode,
     distance_coder: Option<&'a HuffmanCode>,
 }
 
-impl <'a> HuffmanAdapter<'a> {
-    fn new(data: &'a mut BitReader, coder: &'a HuffmanCode,
-           distance_coder: Option<&'a HuffmanCode>) -> HuffmanAdapter<'a> {
+impl <'a, 'b> HuffmanAdapter<'a, 'b> {
+    fn new(data: &'a mut BitReader<'b>, coder: &'a HuffmanCode,
+           distance_coder: Option<&'a HuffmanCode>) -> HuffmanAdapter<'a, 'b> {
         HuffmanAdapter {
             data, coder, distance_coder
         }
@@ -353,35 +890,50 @@ impl <'a> HuffmanAdapter<'a> {
         self.data.read_number(len)
     }
 
-    fn next_code(&mut self) -> io::Result<u16> {
-        self.next_code_impl(&self.coder)
+    fn next_code(&mut self) -> Result<u16, DeflateError> {
+        self.next_code_impl(self.coder)
     }
 
-    fn next_distance(&mut self) -> io::Result<u16> {
+    fn next_distance(&mut self) -> Result<u16, DeflateError> {
         let distance_coder = &self.distance_coder
-            .ok_or(Error::new(ErrorKind::Other,
-                   "This Adapter does not have a distance coder."))?;
+            .ok_or(DeflateError::MissingDistanceCode)?;
         self.next_code_impl(distance_coder)
     }
 
-    fn next_code_impl(&mut self, coder: &HuffmanCode) -> io::Result<u16> {
-        let mut x = 0;
+    fn next_code_impl(&mut self, coder: &HuffmanCode)
+            -> Result<u16, DeflateError> {
+        let (bits, available) = self.data.peek_number(coder.max_length)?;
+        let bits = bits as usize;
+
+        let mut entry = coder.table[bits & PRIMARY_MASK];
         let mut length = 0;
-        for code in &coder.codes {
-            let diff = code.length - length;
-            x = (x << diff) + self.data.read_bits(diff)? as usize;
-            length += diff;
 
-            if code.data[x] != -1 {
-                return Ok(code.data[x] as u16);
-            }
+        if entry & SUBTABLE != 0 {
+            let index = (bits >> PRIMARY_BITS) & ((1 << entry_length(entry)) - 1);
+            entry = coder.table[entry_value(entry) + index];
+            length = PRIMARY_BITS;
+        }
+
+        length += entry_length(entry);
+
+        // At the end of the input the missing bits read as zeros, so there's
+        // no telling whether the code is valid.
+        if (entry_length(entry) == 0 && available < coder.max_length)
+                || length > available {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                "Unexpected end of Huffman code.").into());
         }
 
-        // return Err(Error::new(ErrorKind::Other, "Unknown Huffman Code"));
-        panic!();
+        if entry_length(entry) == 0 {
+            return Err(DeflateError::InvalidHuffmanCode);
+        }
+
+        self.data.skip_bits(length);
+        Ok(entry_value(entry) as u16)
     }
 
-    fn read_distance(&mut self, code: u16) -> io::Result<(usize, usize)> {
+    fn read_distance(&mut self, code: u16)
+            -> Result<(usize, usize), DeflateError> {
         //      Extra               Extra               Extra
         // Code Bits Length(s) Code Bits Lengths   Code Bits Length(s)
         // ---- ---- ------     ---- ---- -------   ---- ---- -------
@@ -425,7 +977,7 @@ impl <'a> HuffmanAdapter<'a> {
             283 => (5, 195),
             284 => (5, 227),
             285 => (0, 258),
-            _ => return Err(Error::new(ErrorKind::Other, "Unexpected code length.")),
+            _ => return Err(DeflateError::InvalidLengthCode(code)),
         };
 
         let length = partial_length + self.data.read_number(extra_bits)? as usize;
@@ -475,7 +1027,7 @@ impl <'a> HuffmanAdapter<'a> {
            27 => (12, 12289),
            28 => (13, 16385),
            29 => (13, 24577),
-           _ => return Err(Error::new(ErrorKind::Other, "Unexpected distance length.")),
+           _ => return Err(DeflateError::InvalidDistanceCode(distance_code)),
         };
 
         let distance = base_distance
@@ -484,65 +1036,45 @@ impl <'a> HuffmanAdapter<'a> {
     }
 }
 
-fn read_huffman(mut data: HuffmanAdapter, out: &mut Vec<u8>) -> io::Result<()> {
-    loop {
-        let code = data.next_code();
-        if let Ok(x) = code {
-            if x < 256 {
-                out.push(x as u8);
-            } else if x == 256 {
-                return Ok(());
-            } else {
-                let (mut length, distance) = data.read_distance(x)?;
-                if out.len() < distance {
-                    panic!("Buffer is not big enough. distance = {} buffer = {}", distance, out.len());
-                    // return Err(Error::new(ErrorKind::Other, "distance value invalid."));
-                }
-
-                let start = out.len() - distance;
+// Reads literals and matches into `out` until about `limit` bytes have been
+// written, returns false at the end of the block. If this fails the caller
+// needs to roll back `out`, as the symbols before the failure are kept.
+fn read_huffman_symbols(mut data: HuffmanAdapter, out: &mut Window,
+                        limit: usize) -> Result<bool, DeflateError> {
+    let start = out.written;
+    let limit = cmp::min(limit, MAX_PENDING - out.pending_len());
 
-                // If the buffer is not long enough, we will just repeat
-                // the characters until we fill the specified length
-                let end = cmp::min(out.len(), start + length);
-
-                out.reserve(length);
-                let match_ = &out[start..end].to_vec();
-
-                loop {
-                    // If this is the last repeated section we need to clip
-                    // the match to make it fit in the buffer.
-                    let bound = cmp::min(match_.len(), length);
-                    out.append(&mut (&match_[0..bound]).to_vec());
-
-                    if length > match_.len() {
-                        length -= match_.len();
-                    } else {
-                        break;
-                    }
-                }
-            }
+    loop {
+        let x = data.next_code()?;
+        if x < 256 {
+            out.push(x as u8);
+        } else if x == 256 {
+            return Ok(false);
         } else {
-            return code.map(|_| ());
-        };
+            let (length, distance) = data.read_distance(x)?;
+            out.copy_match(distance, length)?;
+        }
+
+        if out.written - start >= limit {
+            return Ok(true);
+        }
     }
 }
 
-fn read_no_compression(data: &mut BitReader) -> io::Result<Vec<u8>> {
+// 3.2.4 - Reads the header of a stored block and returns its length
+fn read_no_compression_length(data: &mut BitReader)
+        -> Result<usize, DeflateError> {
     // Round to nearest byte
     data.read_remaining_byte()?;
 
     let len = data.read_number(16)? as u16;
-    let check_len = !(data.read_number(16)? as u16);
+    let nlen = data.read_number(16)? as u16;
 
-    if len != check_len {
-        // return Err(Error::new(ErrorKind::Other, "Length checksum doesn't mach."));
-        panic!();
+    if len != !nlen {
+        return Err(DeflateError::StoredLengthMismatch { len, nlen });
     }
 
-    let mut data_buf = vec![0; len as usize];
-    data.read_exact(&mut data_buf)?;
-
-    Ok(data_buf)
+    Ok(len as usize)
 }
 
 #[cfg(test)]
@@ -553,6 +1085,64 @@ mod test {
         Cursor,
     };
 
+    use crate::bit_writer::{
+        BitWriter,
+        WriteBits,
+    };
+    use crate::compress::{
+        rfc1950_compress,
+        CompressionLevel,
+    };
+
+    #[test]
+    fn test_generate_codes_long() {
+        // Codes of every length up to 15 bits, so some of them go through
+        // the second level tables.
+        let mut code_lengths: Vec<u8> = (1..16).collect();
+        code_lengths.push(15);
+        let codes = generate_codes(&code_lengths).unwrap();
+
+        let mut data = vec![];
+        {
+            let mut writer = BitWriter::new(&mut data);
+            // Symbol 15, 0, 14, 3 and 9
+            writer.write_bits(0b111111111111111, 15).unwrap();
+            writer.write_bits(0b0, 1).unwrap();
+            writer.write_bits(0b111111111111110, 15).unwrap();
+            writer.write_bits(0b1110, 4).unwrap();
+            writer.write_bits(0b1111111110, 10).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        let mut adapter = HuffmanAdapter::new(&mut reader, &codes, None);
+
+        for &expected in &[15, 0, 14, 3, 9] {
+            assert_eq!(adapter.next_code().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_truncated_huffman_code() {
+        let codes = generate_fixed_huffman();
+        // The first 7 bits of a 9-bit code
+        let mut reader = BitReader::new(Box::new(Cursor::new(vec![0x7F])));
+        reader.read_bits(1).unwrap();
+
+        let mut adapter = HuffmanAdapter::new(&mut reader, &codes, None);
+        assert!(adapter.next_code().unwrap_err().is_unexpected_eof());
+    }
+
+    #[test]
+    fn test_missing_distance_code() {
+        let codes = generate_fixed_huffman();
+        let mut reader = BitReader::new(Box::new(Cursor::new(vec![0x00])));
+
+        let mut adapter = HuffmanAdapter::new(&mut reader, &codes, None);
+        assert!(matches!(adapter.next_distance(),
+                         Err(DeflateError::MissingDistanceCode)));
+    }
+
     #[test]
     fn test_fixed_huffman_decode() {
         let data = vec![
@@ -565,4 +1155,330 @@ mod test {
         assert_eq!(String::from_utf8(data.into_inner()).unwrap().as_str(),
             "TestingTesting");
     }
+
+    fn raw(data: Vec<u8>) -> Result<Vec<u8>, DeflateError> {
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        let mut out = Cursor::new(vec![]);
+        rfc1951(&mut reader, &mut out)?;
+        Ok(out.into_inner())
+    }
+
+    #[test]
+    fn test_reserved_block_type() {
+        // BFINAL = 1, BTYPE = 11
+        match raw(vec![0x07]).unwrap_err() {
+            DeflateError::ReservedBlockType => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_stored_length_mismatch() {
+        match raw(vec![0x01, 0x05, 0x00, 0x00, 0x00]).unwrap_err() {
+            DeflateError::StoredLengthMismatch { len: 5, nlen: 0 } => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_distance_too_far_back() {
+        let mut data = vec![];
+        {
+            let mut writer = BitWriter::new(&mut data);
+            // Final block with fixed codes
+            writer.write_number(0b011, 3).unwrap();
+            // Length 3, distance 1, before anything was written
+            writer.write_bits(0b0000001, 7).unwrap();
+            writer.write_bits(0b00000, 5).unwrap();
+            writer.flush().unwrap();
+        }
+
+        match raw(data).unwrap_err() {
+            DeflateError::InvalidDistance { distance: 1, available: 0 } => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_invalid_code_lengths() {
+        match generate_codes(&[1, 1, 1]) {
+            Err(DeflateError::OversubscribedCodeLengths) => {},
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+        match generate_codes(&[2, 2, 2]) {
+            Err(DeflateError::InvalidCodeLengths) => {},
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+        // RFC1951 ~ 3.2.7 - A single distance code of one bit is allowed
+        assert!(generate_codes(&[0, 1]).is_ok());
+    }
+
+    #[test]
+    fn test_repeat_without_previous_length() {
+        // Only 0 and 16 have a code, 0 and 1 respectively
+        let mut lengths = [0; 19];
+        lengths[0] = 1;
+        lengths[16] = 1;
+        let codes = generate_codes(&lengths).unwrap();
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(vec![0x01])));
+        let mut adapter = HuffmanAdapter::new(&mut reader, &codes, None);
+        match read_compressed_code_lengths(&mut adapter, 10) {
+            Err(DeflateError::InvalidCodeLengths) => {},
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_invalid_zlib_header() {
+        match zlib(&[0x78, 0x9D], &InflateOptions::default()).unwrap_err() {
+            DeflateError::ZlibHeaderChecksum => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+        // CINFO = 8, a 64K window
+        match zlib(&[0x88, 0x1C], &InflateOptions::default()).unwrap_err() {
+            DeflateError::InvalidWindowSize(8) => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    fn limit_error(result: Result<Vec<u8>, DeflateError>) -> Option<Limit> {
+        match result {
+            Err(DeflateError::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_rfc1950_limits() {
+        let data = vec![0; 1_000_000];
+        let mut compressed = vec![];
+        rfc1950_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+
+        let options = |max_output, max_ratio| InflateOptions {
+            max_output,
+            max_ratio,
+            ..InflateOptions::default()
+        };
+
+        assert_eq!(limit_error(zlib(&compressed, &options(Some(1000), None))),
+                   Some(Limit::Output(1000)));
+        assert_eq!(zlib(&compressed, &options(Some(1_000_000), None)).unwrap(),
+                   data);
+
+        assert_eq!(limit_error(zlib(&compressed, &options(None, Some(100)))),
+                   Some(Limit::Ratio(100)));
+        assert_eq!(zlib(&compressed, &options(None, Some(2000))).unwrap(),
+                   data);
+    }
+
+    // "TestingTesting" as a zlib stream
+    const ZLIB_DATA: [u8; 17] = [
+        0x78, 0x9C, 0x0B, 0x49, 0x2D, 0x2E, 0xC9, 0xCC, 0x4B, 0x0F, 0x81,
+        0x50, 0x00, 0x2A, 0x8E, 0x05, 0xBD];
+
+    fn zlib(data: &[u8], options: &InflateOptions)
+            -> Result<Vec<u8>, DeflateError> {
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.to_vec())));
+        let mut out = Cursor::new(vec![]);
+        rfc1950_with_options(&mut reader, &mut out, options)?;
+        Ok(out.into_inner())
+    }
+
+    #[test]
+    fn test_rfc1950_checksum() {
+        assert_eq!(zlib(&ZLIB_DATA, &InflateOptions::default()).unwrap(),
+                   b"TestingTesting");
+    }
+
+    #[test]
+    fn test_rfc1950_checksum_mismatch() {
+        let mut data = ZLIB_DATA.to_vec();
+        data[16] ^= 0xFF;
+
+        match zlib(&data, &InflateOptions::default()).unwrap_err() {
+            DeflateError::Checksum(mismatch) =>
+                assert_eq!(mismatch, ChecksumMismatch::Adler32 {
+                    expected: 0x2A8E0542, actual: 0x2A8E05BD }),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        assert_eq!(zlib(&data, &lenient).unwrap(), b"TestingTesting");
+    }
+
+    #[test]
+    fn test_rfc1950_missing_checksum() {
+        let data = &ZLIB_DATA[..13];
+
+        assert!(zlib(data, &InflateOptions::default()).is_err());
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        assert_eq!(zlib(data, &lenient).unwrap(), b"TestingTesting");
+    }
+
+    #[test]
+    fn test_consumed() {
+        let mut data = ZLIB_DATA.to_vec();
+        data.extend_from_slice(b"\r\nendstream");
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.clone())));
+        let inflated = rfc1950_with_options(&mut reader, &mut io::sink(),
+                                            &InflateOptions::default())
+            .unwrap();
+        assert_eq!((inflated.consumed, inflated.size), (17, 14));
+
+        // The same stream without the zlib header and trailer
+        let mut reader = BitReader::new(Box::new(Cursor::new(
+            data[2..].to_vec())));
+        let inflated = rfc1951_with_options(&mut reader, &mut io::sink(),
+                                            &InflateOptions::default())
+            .unwrap();
+        assert_eq!((inflated.consumed, inflated.size), (11, 14));
+    }
+
+    #[test]
+    fn test_rfc1950_ignore_trailing_data() {
+        let options = InflateOptions {
+            ignore_trailing_data: true,
+            ..InflateOptions::default()
+        };
+
+        // The trailer is missing
+        let data = &ZLIB_DATA[..13];
+        assert!(zlib(data, &InflateOptions::default()).is_err());
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.to_vec())));
+        let mut out = vec![];
+        let inflated = rfc1950_with_options(&mut reader, &mut out, &options)
+            .unwrap();
+        assert_eq!((inflated.consumed, inflated.size), (13, 14));
+        assert_eq!(out, b"TestingTesting");
+
+        // Something else follows the final block, it can only be told from
+        // a corrupt trailer when checksums are not verified
+        let mut data = ZLIB_DATA[..13].to_vec();
+        data.extend_from_slice(b"\r\nendstream");
+        assert!(zlib(&data, &options).is_err());
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..options.clone()
+        };
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        let inflated = rfc1950_with_options(&mut reader, &mut io::sink(),
+                                            &lenient).unwrap();
+        assert_eq!(inflated.consumed, 13);
+
+        // A matching trailer is still part of the stream
+        let mut reader = BitReader::new(Box::new(Cursor::new(
+            ZLIB_DATA.to_vec())));
+        let inflated = rfc1950_with_options(&mut reader, &mut io::sink(),
+                                            &options).unwrap();
+        assert_eq!(inflated.consumed, 17);
+    }
+
+    #[test]
+    fn test_rfc1950_ignore_trailing_data_mismatch() {
+        let mut data = ZLIB_DATA.to_vec();
+        data[16] ^= 0xFF;
+
+        let options = InflateOptions {
+            ignore_trailing_data: true,
+            ..InflateOptions::default()
+        };
+        match zlib(&data, &options) {
+            Err(DeflateError::Checksum(
+                ChecksumMismatch::Adler32 { .. })) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    fn recover() -> InflateOptions {
+        InflateOptions {
+            recover: true,
+            ..InflateOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_recover_truncated() {
+        let data: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        let mut compressed = vec![];
+        rfc1950_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        assert!(zlib(&compressed, &InflateOptions::default()).is_err());
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(
+            compressed.clone())));
+        let mut out = vec![];
+        let inflated = rfc1950_with_options(&mut reader, &mut out, &recover())
+            .unwrap();
+
+        assert!(inflated.error.unwrap().is_unexpected_eof());
+        assert_eq!(inflated.consumed, compressed.len() as u64);
+        assert_eq!(inflated.size, out.len());
+        // Everything up to the last complete symbol is there
+        assert!(out.len() > data.len() / 3);
+        assert!(data.starts_with(&out));
+    }
+
+    #[test]
+    fn test_recover_corrupt() {
+        let mut data = vec![];
+        {
+            let mut writer = BitWriter::new(&mut data);
+            // Final block with fixed codes
+            writer.write_number(0b011, 3).unwrap();
+            // The literal 'A', then length 3 at distance 5
+            writer.write_bits(0x30 + 0x41, 8).unwrap();
+            writer.write_bits(0b0000001, 7).unwrap();
+            writer.write_bits(0b00100, 5).unwrap();
+            writer.write_number(0, 1).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        let mut out = vec![];
+        let inflated = rfc1951_with_options(&mut reader, &mut out, &recover())
+            .unwrap();
+
+        assert_eq!(out, b"A");
+        match inflated.error {
+            Some(DeflateError::InvalidDistance { distance: 5, available: 1 })
+                => {},
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    // "TestingTesting" as a zlib stream with the preset dictionary "Testing"
+    const ZLIB_DICTIONARY_DATA: [u8; 14] = [
+        0x78, 0xF9, 0x0B, 0x3E, 0x02, 0xDF, 0x0B, 0x41, 0xA1, 0x00, 0x2A,
+        0x8E, 0x05, 0xBD];
+
+    #[test]
+    fn test_rfc1950_dictionary() {
+        match zlib(&ZLIB_DICTIONARY_DATA, &InflateOptions::default()) {
+            Err(DeflateError::MissingDictionary(0x0B3E02DF)) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let options = InflateOptions {
+            dictionaries: vec![b"Other".to_vec(), b"Testing".to_vec()],
+            ..InflateOptions::default()
+        };
+        assert_eq!(zlib(&ZLIB_DICTIONARY_DATA, &options).unwrap(),
+                   b"TestingTesting");
+    }
 }
@@ -0,0 +1,193 @@
+// Everything that can go wrong while decompressing.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::checksum::ChecksumMismatch;
+
+#[derive(Debug)]
+pub enum DeflateError {
+    // Reading the input or writing the output failed, this includes running
+    // out of input too early.
+    Io(io::Error),
+    // RFC1950 ~ 2.2 - FCHECK doesn't match the rest of the header
+    ZlibHeaderChecksum,
+    // RFC1950 ~ 2.2 - CINFO is above 7, i.e. a window bigger than 32K
+    InvalidWindowSize(u8),
+    // RFC1950 ~ 2.2 - FDICT is set but none of the preset dictionaries has
+    // this Adler-32
+    MissingDictionary(u32),
+    // Only 8, which is deflate, is defined for zlib and gzip
+    UnsupportedCompressionMethod(u8),
+    // RFC1952 ~ 2.3.1
+    InvalidGzipMagic,
+    ReservedGzipFlags(u8),
+    InvalidExtraField,
+    // RFC1951 ~ 3.2.3 - BTYPE 11
+    ReservedBlockType,
+    // RFC1951 ~ 3.2.4 - NLEN is not the one's complement of LEN
+    StoredLengthMismatch { len: u16, nlen: u16 },
+    // RFC1951 ~ 3.2.7 - The code lengths don't describe a usable code, e.g.
+    // a repeat goes past the end or the code is incomplete.
+    InvalidCodeLengths,
+    // There are more codes of some length than the bits can represent
+    OversubscribedCodeLengths,
+    // A code that is not assigned to any symbol
+    InvalidHuffmanCode,
+    // RFC1951 ~ 3.2.5 - Literal/length symbols 286 and 287
+    InvalidLengthCode(u16),
+    // RFC1951 ~ 3.2.5 - Distance symbols 30 and 31
+    InvalidDistanceCode(u16),
+    // A match that reaches before the start of the output
+    InvalidDistance { distance: usize, available: usize },
+    // A distance read with a code that only has literals and lengths, e.g.
+    // the code length code
+    MissingDistanceCode,
+    Checksum(ChecksumMismatch),
+    // LZW literals are between 2 and 8 bits
+    InvalidLzwCodeSize(u8),
+    // An LZW code that is not in the table yet
+    InvalidLzwCode(u16),
+    // APPNOTE 4.3.16 - Not a zip archive, or one cut short
+    MissingEndOfCentralDirectory,
+    // A zip record doesn't start with the signature it should have
+    InvalidZipSignature(u32),
+    // APPNOTE 4.4.5 - Anything but stored and deflated
+    UnsupportedZipMethod(u16),
+    EncryptedZipEntry,
+    // A serialized `GzipIndex` that is from something else, or that has no
+    // checkpoint to start from
+    InvalidGzipIndex,
+    // One of the limits in `InflateOptions` was crossed, most likely a
+    // decompression bomb.
+    LimitExceeded(Limit),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    // `InflateOptions::max_output`
+    Output(u64),
+    // `InflateOptions::max_ratio`
+    Ratio(u64),
+}
+
+impl DeflateError {
+    // Whether decoding failed only because the input ended early
+    pub fn is_unexpected_eof(&self) -> bool {
+        match self {
+            DeflateError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DeflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeflateError::Io(e) => write!(f, "{}", e),
+            DeflateError::ZlibHeaderChecksum =>
+                write!(f, "Header checksum doesn't match."),
+            DeflateError::InvalidWindowSize(info) =>
+                write!(f, "Invalid window size {}.", info),
+            DeflateError::MissingDictionary(id) =>
+                write!(f, "Missing preset dictionary {:#010x}.", id),
+            DeflateError::UnsupportedCompressionMethod(method) =>
+                write!(f, "Unknown compression method {}.", method),
+            DeflateError::InvalidGzipMagic =>
+                write!(f, "Missing gzip magic number."),
+            DeflateError::ReservedGzipFlags(flags) =>
+                write!(f, "Reserved header flags are set: {:#04x}.", flags),
+            DeflateError::InvalidExtraField =>
+                write!(f, "Invalid extra field."),
+            DeflateError::ReservedBlockType =>
+                write!(f, "Reserved block type."),
+            DeflateError::StoredLengthMismatch { len, nlen } =>
+                write!(f, "Length checksum doesn't match: LEN = {:#06x} \
+                           NLEN = {:#06x}.", len, nlen),
+            DeflateError::InvalidCodeLengths =>
+                write!(f, "Invalid code lengths."),
+            DeflateError::OversubscribedCodeLengths =>
+                write!(f, "Over-subscribed code lengths."),
+            DeflateError::InvalidHuffmanCode =>
+                write!(f, "Unknown Huffman code."),
+            DeflateError::InvalidLengthCode(code) =>
+                write!(f, "Invalid length code {}.", code),
+            DeflateError::InvalidDistanceCode(code) =>
+                write!(f, "Invalid distance code {}.", code),
+            DeflateError::InvalidDistance { distance, available } =>
+                write!(f, "Distance {} is too far back, only {} bytes are \
+                           available.", distance, available),
+            DeflateError::MissingDistanceCode =>
+                write!(f, "There is no distance code to read from."),
+            DeflateError::Checksum(mismatch) => write!(f, "{}", mismatch),
+            DeflateError::InvalidLzwCodeSize(size) =>
+                write!(f, "Invalid LZW code size {}.", size),
+            DeflateError::InvalidLzwCode(code) =>
+                write!(f, "Invalid LZW code {}.", code),
+            DeflateError::MissingEndOfCentralDirectory =>
+                write!(f, "Missing end of central directory record."),
+            DeflateError::InvalidZipSignature(signature) =>
+                write!(f, "Invalid zip record signature {:#010x}.", signature),
+            DeflateError::UnsupportedZipMethod(method) =>
+                write!(f, "Unsupported zip compression method {}.", method),
+            DeflateError::EncryptedZipEntry =>
+                write!(f, "Encrypted zip entries are not supported."),
+            DeflateError::InvalidGzipIndex =>
+                write!(f, "Invalid gzip index."),
+            DeflateError::LimitExceeded(Limit::Output(limit)) =>
+                write!(f, "Decompressed data is bigger than {} bytes.", limit),
+            DeflateError::LimitExceeded(Limit::Ratio(limit)) =>
+                write!(f, "Decompressed data is more than {} times bigger \
+                           than the compressed data.", limit),
+        }
+    }
+}
+
+impl error::Error for DeflateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DeflateError::Io(e) => Some(e),
+            DeflateError::Checksum(mismatch) => Some(mismatch),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DeflateError {
+    fn from(error: io::Error) -> DeflateError {
+        DeflateError::Io(error)
+    }
+}
+
+impl From<ChecksumMismatch> for DeflateError {
+    fn from(mismatch: ChecksumMismatch) -> DeflateError {
+        DeflateError::Checksum(mismatch)
+    }
+}
+
+// So that decoders can be used where an `io::Error` is expected, e.g. from
+// `Read`. Malformed data becomes `InvalidData`.
+impl From<DeflateError> for io::Error {
+    fn from(error: DeflateError) -> io::Error {
+        match error {
+            DeflateError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_into_io_error() {
+        let error: io::Error = DeflateError::ReservedBlockType.into();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error: io::Error = DeflateError::Io(
+            io::Error::new(io::ErrorKind::UnexpectedEof, "eof")).into();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -16,9 +16,29 @@ use crate::bit_reader::{
     ReadBits,
 };
 
-use crate::deflate::rfc1951;
+use crate::bit_writer::{
+    BitWriter,
+    WriteBits,
+};
+use crate::checksum::{
+    Checksum,
+    ChecksumMismatch,
+    ChecksumWriter,
+    Crc32,
+};
+use crate::compress::{
+    compress,
+    CompressionLevel,
+};
+use crate::deflate::{
+    inflate_with_limits,
+    Inflated,
+    InflateOptions,
+    Limits,
+};
+use crate::error::DeflateError;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 // 2.3.1
 enum Flag {
     Text,
@@ -28,23 +48,35 @@ enum Flag {
     Comment,
 }
 
+const FLAGS: [Flag; 5] =
+    [Flag::Text, Flag::Hcrc, Flag::Extra, Flag::Name, Flag::Comment];
+
 impl Flag {
     fn from(data: u8) -> HashSet<Flag> {
-        let mut result = HashSet::new();
+        FLAGS.iter()
+            .filter(|flag| data & flag.bit() > 0)
+            .cloned()
+            .collect()
+    }
 
-        if data & 0b0000_0001 > 0 { result.insert(Flag::Text); }
-        if data & 0b0000_0010 > 0 { result.insert(Flag::Hcrc); }
-        if data & 0b0000_0100 > 0 { result.insert(Flag::Extra); }
-        if data & 0b0000_1000 > 0 { result.insert(Flag::Name); }
-        if data & 0b0001_0000 > 0 { result.insert(Flag::Comment); }
+    fn bit(self) -> u8 {
+        match self {
+            Flag::Text    => 0b0000_0001,
+            Flag::Hcrc    => 0b0000_0010,
+            Flag::Extra   => 0b0000_0100,
+            Flag::Name    => 0b0000_1000,
+            Flag::Comment => 0b0001_0000,
+        }
+    }
 
-        result
+    fn to_byte(flags: &HashSet<Flag>) -> u8 {
+        flags.iter().fold(0, |result, flag| result | flag.bit())
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 // 2.3.1
-enum Os {
+pub enum Os {
     FatFilesystem,
     Amiga,
     Vms,
@@ -83,71 +115,736 @@ impl Os {
             _ => { return None; },
         })
     }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Os::FatFilesystem  => 0,
+            Os::Amiga          => 1,
+            Os::Vms            => 2,
+            Os::Unix           => 3,
+            Os::VmCms          => 4,
+            Os::AtariTos       => 5,
+            Os::HpfsFilesystem => 6,
+            Os::Macintosh      => 7,
+            Os::ZSystem        => 8,
+            Os::CpM            => 9,
+            Os::Tops20         => 10,
+            Os::NtfsFilesystem => 11,
+            Os::Qdos           => 12,
+            Os::AcornRiscos    => 13,
+            Os::Unknown        => 255,
+        }
+    }
 }
 
-// 2.3
-pub fn rfc1952(data: &mut BitReader, out: &mut Write) -> io::Result<usize> {
-    if data.read_number(16)? != 0x8B1F {
-        return Err(Error::new(ErrorKind::Other, "Missing gzip magic number"));
+impl Default for Os {
+    // The file system we are running on, like gzip does
+    fn default() -> Os {
+        if cfg!(unix) {
+            Os::Unix
+        } else if cfg!(windows) {
+            Os::NtfsFilesystem
+        } else {
+            Os::Unknown
+        }
+    }
+}
+
+// 2.3.1 - Metadata stored in the header of a gzip member
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GzipHeader {
+    // Original file name, without the directory
+    pub name: Option<String>,
+    pub comment: Option<String>,
+    // Modification time of the original file in seconds since the epoch,
+    // 0 means that no time stamp is available.
+    pub mtime: u32,
+    pub os: Os,
+    // Extra flags, 2 for maximum compression and 4 for the fastest one. When
+    // writing this is derived from the compression level.
+    pub xfl: u8,
+    pub extra: Vec<ExtraField>,
+}
+
+// 2.3.1.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraField {
+    // The SI1 and SI2 subfield identifiers
+    pub id: [u8; 2],
+    pub data: Vec<u8>,
+}
+
+// Reads the header fields while keeping track of their CRC, which is needed
+// for FHCRC.
+struct HeaderReader<'a, 'b> {
+    data: &'a mut BitReader<'b>,
+    crc32: Crc32,
+}
+
+impl <'a, 'b> HeaderReader<'a, 'b> {
+    fn read_bytes(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; length];
+        self.data.read_exact(&mut buf)?;
+        self.crc32.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // FNAME and FCOMMENT are zero-terminated ISO 8859-1 strings, but most
+    // tools just store whatever bytes the file system uses, which nowadays
+    // is mostly UTF-8.
+    fn read_string(&mut self) -> io::Result<String> {
+        let mut bytes = vec![];
+        loop {
+            let byte = self.read_u8()?;
+            if byte == 0x00 {
+                break;
+            }
+            bytes.push(byte);
+        }
+
+        Ok(String::from_utf8(bytes)
+            .unwrap_or_else(|e| e.as_bytes().iter()
+                .map(|&b| b as char)
+                .collect()))
+    }
+
+    // 2.3.1.1
+    fn read_extra(&mut self) -> Result<Vec<ExtraField>, DeflateError> {
+        let length = self.read_u16()? as usize;
+        extra_fields(&self.read_bytes(length)?)
+    }
+}
+
+// 2.3.1.1 - Subfields of the extra field, each an ID, a length and the
+// data. ZIP uses the same layout for its extra fields.
+pub(crate) fn extra_fields(mut data: &[u8])
+        -> Result<Vec<ExtraField>, DeflateError> {
+    let mut result = vec![];
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(DeflateError::InvalidExtraField);
+        }
+
+        let id = [data[0], data[1]];
+        let length = u16::from_le_bytes([data[2], data[3]]) as usize;
+        data = &data[4..];
+
+        // The subfield is longer than the extra field
+        if data.len() < length {
+            return Err(DeflateError::InvalidExtraField);
+        }
+
+        result.push(ExtraField { id, data: data[..length].to_vec() });
+        data = &data[length..];
+    }
+
+    Ok(result)
+}
+
+// 2.3 - Reads the header of a gzip member, leaving `data` at the start of
+// the compressed blocks.
+pub fn rfc1952_header(data: &mut BitReader)
+        -> Result<GzipHeader, DeflateError> {
+    read_header(data, true)
+}
+
+pub(crate) fn read_header(data: &mut BitReader, verify_checksum: bool)
+        -> Result<GzipHeader, DeflateError> {
+    let mut reader = HeaderReader { data, crc32: Crc32::new() };
+
+    if reader.read_u16()? != 0x8B1F {
+        return Err(DeflateError::InvalidGzipMagic);
     }
 
-    if data.read_number(8)? != 0x08 {
+    let compression_method = reader.read_u8()?;
+    if compression_method != 0x08 {
         // 0x08 is DEFLATE RFC1951, which is the only compression method we
         // implement.
-        return Err(Error::new(ErrorKind::Other, "Unknown compression method."));
+        return Err(DeflateError::UnsupportedCompressionMethod(
+            compression_method));
     }
 
-    let flags = Flag::from(data.read_number(8)? as u8);
-    let _time = data.read_number(32)?;
-    let _xfl = data.read_number(8)?;
+    let flags_byte = reader.read_u8()?;
+    if flags_byte & 0b1110_0000 != 0 {
+        return Err(DeflateError::ReservedGzipFlags(flags_byte));
+    }
+
+    let flags = Flag::from(flags_byte);
+    let mtime = reader.read_u32()?;
+    let xfl = reader.read_u8()?;
 
-    let _os = Os::from(data.read_number(8)? as u8)
-        .ok_or(Error::new(ErrorKind::Other, "Unknown OS"))?;
+    // Values not listed in the RFC are treated as unknown
+    let os = Os::from(reader.read_u8()?).unwrap_or(Os::Unknown);
 
+    let mut extra = vec![];
     if flags.contains(&Flag::Extra) {
-        // TODO:
-        unimplemented!();
+        extra = reader.read_extra()?;
     }
 
-    let _name;
+    let mut name = None;
     if flags.contains(&Flag::Name) {
-        _name = read_name(data)?;
-    } else {
-        _name = "unknown".to_string();
+        name = Some(reader.read_string()?);
     }
 
+    let mut comment = None;
     if flags.contains(&Flag::Comment) {
-        // TODO:
-        unimplemented!();
+        comment = Some(reader.read_string()?);
     }
 
     if flags.contains(&Flag::Hcrc) {
-        // TODO:
-        unimplemented!();
+        // The CRC16 is the two least significant bytes of the CRC32 of all
+        // the bytes preceding it.
+        let actual = reader.crc32.value() & 0xFFFF;
+        let expected = reader.read_u16()? as u32;
+
+        if verify_checksum {
+            ChecksumMismatch::HeaderCrc16 { expected, actual }.check()?;
+        }
+    }
+
+    Ok(GzipHeader {
+        name,
+        comment,
+        mtime,
+        os,
+        xfl,
+        extra,
+    })
+}
+
+// 2.3
+pub fn rfc1952(data: &mut BitReader, out: &mut dyn Write)
+        -> Result<usize, DeflateError> {
+    rfc1952_with_options(data, out, &InflateOptions::default())
+        .map(|inflated| inflated.size)
+}
+
+pub fn rfc1952_with_options(data: &mut BitReader, out: &mut dyn Write,
+                            options: &InflateOptions)
+        -> Result<Inflated, DeflateError> {
+    let start = data.position();
+    let (members, error) = members(data, out, options, options.multi_member)?;
+
+    let last = &members[members.len() - 1];
+    Ok(Inflated {
+        consumed: last.offset + last.compressed_size - start,
+        size: members.iter().map(|member| member.size).sum(),
+        error,
+    })
+}
+
+// A gzip member as found in the input, see `rfc1952_members`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipMember {
+    // Offset of the first byte of the member from the start of the input
+    pub offset: u64,
+    pub header: GzipHeader,
+    // Size of the decompressed data of this member
+    pub size: usize,
+    // Size of the member in the input, header and trailer included
+    pub compressed_size: u64,
+}
+
+// 2.2 - A gzip file consists of a series of members, e.g. when files are
+// concatenated with `cat a.gz b.gz`. Decompresses all of them one after the
+// other into `out`, checking each trailer, and returns where each member
+// starts.
+pub fn rfc1952_members(data: &mut BitReader, out: &mut dyn Write,
+                       options: &InflateOptions)
+        -> Result<Vec<GzipMember>, DeflateError> {
+    match members(data, out, options, true)? {
+        // In recovery mode the output is there, but we have no way to tell
+        // which member is incomplete.
+        (_, Some(e)) => Err(e),
+        (members, None) => Ok(members),
     }
+}
+
+// In recovery mode, decoding stops at the first member with an error which
+// is returned together with the members so far.
+fn members(data: &mut BitReader, out: &mut dyn Write, options: &InflateOptions,
+           multi_member: bool)
+        -> Result<(Vec<GzipMember>, Option<DeflateError>), DeflateError> {
+    let mut limits = Limits::new(options, data.position());
+    let mut members = vec![];
+
+    while members.is_empty() || (multi_member && !data.at_end()?) {
+        let offset = data.position();
+        let header = match read_header(data, options.verify_checksums) {
+            Ok(header) => header,
+            // Whatever follows the last member is not another member
+            Err(_) if options.ignore_trailing_data && !members.is_empty() =>
+                break,
+            Err(e) => return Err(e),
+        };
+
+        let (member, error) = member(data, out, options, &mut limits, offset,
+                                     header)?;
+        members.push(member);
+
+        if error.is_some() {
+            return Ok((members, error));
+        }
+    }
+
+    Ok((members, None))
+}
+
+// Decompresses what follows the header of a member
+fn member(data: &mut BitReader, out: &mut dyn Write, options: &InflateOptions,
+          limits: &mut Limits, offset: u64, header: GzipHeader)
+        -> Result<(GzipMember, Option<DeflateError>), DeflateError> {
+    let mut out = ChecksumWriter::new(out, Crc32::new());
+    let inflated = inflate_with_limits(data, &mut out, limits,
+                                       options.recover, &[])?;
+
+    let mut error = inflated.error;
+    if error.is_none() {
+        match check_trailer(data, out.value(), inflated.size, options) {
+            Ok(()) => {},
+            Err(e) if options.recover => error = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let member = GzipMember {
+        offset,
+        header,
+        size: inflated.size,
+        compressed_size: data.position() - offset,
+    };
+    Ok((member, error))
+}
+
+// 2.3 - The CRC32 and ISIZE fields at the end of each member
+pub(crate) fn check_trailer(data: &mut BitReader, crc32: u32, size: usize,
+                            options: &InflateOptions)
+        -> Result<(), DeflateError> {
+    let trailer = data.read_remaining_byte()
+        .and_then(|_| Ok((data.read_number(32)?, data.read_number(32)?)));
+
+    match trailer {
+        Ok((expected_crc32, expected_size)) if options.verify_checksums => {
+            ChecksumMismatch::Crc32 {
+                expected: expected_crc32 as u32,
+                actual: crc32,
+            }.check()?;
+
+            // ISIZE is the input size modulo 2^32
+            ChecksumMismatch::Size {
+                expected: expected_size as u32,
+                actual: size as u32,
+            }.check()
+        },
+        Err(e) if options.verify_checksums => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// 2.3 - Compresses `data` into a single gzip member and writes it to `out`.
+// Returns the number of compressed bytes written.
+pub fn rfc1952_compress(data: &[u8], out: &mut dyn Write,
+                        level: CompressionLevel,
+                        header: &GzipHeader) -> io::Result<usize> {
+    let mut writer = BitWriter::new(out);
+    writer.write_bytes(&header_bytes(header, level)?)?;
 
-    let decompressed_size = rfc1951(data, out)?;
+    compress(&mut writer, data, level)?;
+    writer.align()?;
 
-    data.read_remaining_byte()?;
+    let mut crc32 = Crc32::new();
+    crc32.update(data);
+    writer.write_bytes(&crc32.value().to_le_bytes())?;
+    // ISIZE is the input size modulo 2^32
+    writer.write_bytes(&(data.len() as u32).to_le_bytes())?;
 
-    // TODO: checksum
-    let _crc32 = data.read_number(32)?;
-    let size = data.read_number(32)?;
+    writer.flush()?;
+    Ok(writer.written())
+}
+
+pub(crate) fn header_bytes(header: &GzipHeader, level: CompressionLevel)
+        -> io::Result<Vec<u8>> {
+    let mut flags = HashSet::new();
+    if !header.extra.is_empty() { flags.insert(Flag::Extra); }
+    if header.name.is_some() { flags.insert(Flag::Name); }
+    if header.comment.is_some() { flags.insert(Flag::Comment); }
+
+    // 0x08 is DEFLATE RFC1951
+    let mut result = vec![0x1F, 0x8B, 0x08, Flag::to_byte(&flags)];
+    result.extend_from_slice(&header.mtime.to_le_bytes());
+
+    let xfl = match level {
+        CompressionLevel::Best => 2,
+        CompressionLevel::Fast => 4,
+        _ => 0,
+    };
+    result.push(xfl);
+    result.push(header.os.to_byte());
+
+    if !header.extra.is_empty() {
+        let mut extra = vec![];
+        for field in &header.extra {
+            if field.data.len() > 0xFFFF {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                    "Extra subfield is too long."));
+            }
+            extra.extend_from_slice(&field.id);
+            extra.extend_from_slice(&(field.data.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&field.data);
+        }
 
-    if decompressed_size != size as usize {
-        return Err(Error::new(ErrorKind::Other, "Input size does not match."));
+        if extra.len() > 0xFFFF {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                "Extra field is too long."));
+        }
+        result.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        result.extend_from_slice(&extra);
     }
 
-    Ok(decompressed_size)
+    for value in [&header.name, &header.comment].iter()
+            .filter_map(|field| field.as_ref()) {
+        if value.bytes().any(|b| b == 0x00) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                "Header fields cannot contain a NUL byte."));
+        }
+        result.extend_from_slice(value.as_bytes());
+        result.push(0x00);
+    }
+
+    Ok(result)
 }
 
-pub fn read_name(data: &mut BitReader) -> io::Result<String> {
-    let mut name_bytes = vec![];
-    let mut buf = [0xFF];
-    while buf[0] != 0x00 {
-        data.read_exact(&mut buf)?;
-        name_bytes.push(buf[0]);
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::error::Limit;
+
+    #[test]
+    fn test_flags() {
+        let flags = Flag::from(0b0001_1001);
+        assert_eq!(flags.len(), 3);
+        assert!(flags.contains(&Flag::Text));
+        assert!(flags.contains(&Flag::Name));
+        assert!(flags.contains(&Flag::Comment));
+        assert_eq!(Flag::to_byte(&flags), 0b0001_1001);
+    }
+
+    #[test]
+    fn test_os() {
+        for i in 0..=255 {
+            if let Some(os) = Os::from(i) {
+                assert_eq!(os.to_byte(), i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let header = GzipHeader {
+            name: Some("expected.txt".to_string()),
+            mtime: 0x5C60741C,
+            os: Os::Unix,
+            ..GzipHeader::default()
+        };
+
+        let mut expected = vec![
+            0x1F, 0x8B, 0x08, 0x08, 0x1C, 0x74, 0x60, 0x5C, 0x00, 0x03];
+        expected.extend_from_slice(b"expected.txt\0");
+
+        assert_eq!(header_bytes(&header, CompressionLevel::Default).unwrap(),
+                   expected);
+    }
+
+    #[test]
+    fn test_rfc1952_round_trip() {
+        let data = b"ABCDEFGHILMNOPQRSTUVZ0123456789\n".repeat(100);
+        let header = GzipHeader {
+            name: Some("data.txt".to_string()),
+            comment: Some("A comment".to_string()),
+            mtime: 1_550_000_000,
+            extra: vec![ExtraField { id: *b"AP", data: vec![1, 2, 3] }],
+            ..GzipHeader::default()
+        };
+
+        let mut compressed = vec![];
+        rfc1952_compress(&data, &mut compressed, CompressionLevel::Best,
+                         &header).unwrap();
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(
+            compressed.clone())));
+        let mut decompressed = Cursor::new(vec![]);
+        rfc1952(&mut reader, &mut decompressed).unwrap();
+
+        assert_eq!(decompressed.into_inner(), data);
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(compressed)));
+        assert_eq!(rfc1952_header(&mut reader).unwrap(), GzipHeader {
+            xfl: 2,
+            ..header
+        });
+    }
+
+    // A member for "TestingTesting" with every optional header field
+    fn full_header_member() -> Vec<u8> {
+        let mut header = vec![
+            0x1F, 0x8B, 0x08, 0b0001_1110, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF,
+            // XLEN, then a subfield with SI1 = 'A', SI2 = 'b' and LEN = 2
+            0x06, 0x00, 0x41, 0x62, 0x02, 0x00, 0xCA, 0xFE];
+        header.extend_from_slice(b"name\0");
+        header.extend_from_slice(b"caf\xE9\0");
+
+        let mut crc32 = Crc32::new();
+        crc32.update(&header);
+        header.extend_from_slice(&(crc32.value() as u16).to_le_bytes());
+
+        header.extend_from_slice(&[
+            0x0B, 0x49, 0x2D, 0x2E, 0xC9, 0xCC, 0x4B, 0x0F, 0x81, 0x50, 0x00,
+            0xB3, 0x46, 0xB0, 0x38, 0x0E, 0x00, 0x00, 0x00]);
+        header
+    }
+
+    #[test]
+    fn test_rfc1952_header() {
+        let data = full_header_member();
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+
+        assert_eq!(rfc1952_header(&mut reader).unwrap(), GzipHeader {
+            name: Some("name".to_string()),
+            // Not valid UTF-8, so it's read as ISO 8859-1
+            comment: Some("caf\u{E9}".to_string()),
+            mtime: 0,
+            os: Os::Unknown,
+            xfl: 0,
+            extra: vec![ExtraField { id: *b"Ab", data: vec![0xCA, 0xFE] }],
+        });
+    }
+
+    #[test]
+    fn test_rfc1952_full_header() {
+        let data = full_header_member();
+        assert_eq!(gzip(&data, &InflateOptions::default()).unwrap(),
+                   b"TestingTesting");
+    }
+
+    #[test]
+    fn test_rfc1952_header_crc_mismatch() {
+        let mut data = full_header_member();
+        // Corrupts the comment
+        data[25] = b'E';
+
+        let error = gzip(&data, &InflateOptions::default()).unwrap_err();
+        match mismatch(&error) {
+            Some(ChecksumMismatch::HeaderCrc16 { .. }) => {},
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        assert_eq!(gzip(&data, &lenient).unwrap(), b"TestingTesting");
+    }
+
+    #[test]
+    fn test_rfc1952_reserved_flags() {
+        let mut data = compressed(b"TestingTesting");
+        data[3] |= 0b0010_0000;
+
+        match gzip(&data, &InflateOptions::default()).unwrap_err() {
+            DeflateError::ReservedGzipFlags(flags) =>
+                assert_eq!(flags, 0b0010_0000),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    fn gzip(data: &[u8], options: &InflateOptions)
+            -> Result<Vec<u8>, DeflateError> {
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.to_vec())));
+        let mut out = Cursor::new(vec![]);
+        rfc1952_with_options(&mut reader, &mut out, options)?;
+        Ok(out.into_inner())
+    }
+
+    fn compressed(data: &[u8]) -> Vec<u8> {
+        let mut result = vec![];
+        rfc1952_compress(data, &mut result, CompressionLevel::Default,
+                         &GzipHeader::default()).unwrap();
+        result
+    }
+
+    fn mismatch(error: &DeflateError) -> Option<ChecksumMismatch> {
+        match error {
+            DeflateError::Checksum(mismatch) => Some(*mismatch),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_rfc1952_crc32_mismatch() {
+        let mut data = compressed(b"TestingTesting");
+        let crc32_offset = data.len() - 8;
+        data[crc32_offset] ^= 0x01;
+
+        let error = gzip(&data, &InflateOptions::default()).unwrap_err();
+        match mismatch(&error) {
+            Some(ChecksumMismatch::Crc32 { expected, actual }) =>
+                assert_eq!(expected ^ actual, 0x01),
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        assert_eq!(gzip(&data, &lenient).unwrap(), b"TestingTesting");
+    }
+
+    #[test]
+    fn test_rfc1952_size_mismatch() {
+        let mut data = compressed(b"TestingTesting");
+        let size_offset = data.len() - 4;
+        data[size_offset] = 15;
+
+        let error = gzip(&data, &InflateOptions::default()).unwrap_err();
+        assert_eq!(mismatch(&error),
+                   Some(ChecksumMismatch::Size { expected: 15, actual: 14 }));
+    }
+
+    #[test]
+    fn test_rfc1952_truncated_trailer() {
+        let data = compressed(b"TestingTesting");
+        let truncated = &data[..data.len() - 8];
+
+        assert!(gzip(truncated, &InflateOptions::default()).is_err());
+
+        let lenient = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        assert_eq!(gzip(truncated, &lenient).unwrap(), b"TestingTesting");
+    }
+
+    fn multi_member() -> InflateOptions {
+        InflateOptions {
+            multi_member: true,
+            ..InflateOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_rfc1952_multi_member() {
+        let first = compressed(b"Testing");
+        let mut data = first.clone();
+        data.extend_from_slice(&compressed(b""));
+        data.extend_from_slice(&compressed(b"Testing"));
+
+        assert_eq!(gzip(&data, &multi_member()).unwrap(), b"TestingTesting");
+        // By default only the first member is decompressed
+        assert_eq!(gzip(&data, &InflateOptions::default()).unwrap(),
+                   b"Testing");
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        let mut out = vec![];
+        let members = rfc1952_members(&mut reader, &mut out, &multi_member())
+            .unwrap();
+
+        let offsets: Vec<_> = members.iter()
+            .map(|member| (member.offset, member.size))
+            .collect();
+        assert_eq!(offsets, vec![
+            (0, 7),
+            (first.len() as u64, 0),
+            (first.len() as u64 + 20, 7),
+        ]);
+    }
+
+    #[test]
+    fn test_rfc1952_multi_member_limit() {
+        let mut data = compressed(b"Testing");
+        data.extend_from_slice(&compressed(b"Testing"));
+
+        // The limit covers all the members together
+        let options = InflateOptions {
+            max_output: Some(10),
+            ..multi_member()
+        };
+        match gzip(&data, &options).unwrap_err() {
+            DeflateError::LimitExceeded(Limit::Output(10)) => {},
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_rfc1952_multi_member_mismatch() {
+        let mut data = compressed(b"Testing");
+        data.extend_from_slice(&compressed(b"Testing"));
+        let crc32_offset = data.len() - 8;
+        data[crc32_offset] ^= 0x01;
+
+        let error = gzip(&data, &multi_member()).unwrap_err();
+        match mismatch(&error) {
+            Some(ChecksumMismatch::Crc32 { .. }) => {},
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rfc1952_multi_member_trailing_data() {
+        let mut data = compressed(b"Testing");
+        data.extend_from_slice(b"garbage");
+
+        assert!(gzip(&data, &multi_member()).is_err());
+
+        let options = InflateOptions {
+            ignore_trailing_data: true,
+            ..multi_member()
+        };
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.clone())));
+        let inflated = rfc1952_with_options(&mut reader, &mut io::sink(),
+                                            &options).unwrap();
+        assert_eq!((inflated.consumed, inflated.size),
+                   (data.len() as u64 - 7, 7));
+    }
+
+    #[test]
+    fn test_rfc1952_recover() {
+        let mut data = compressed(b"Testing");
+        data.extend_from_slice(&compressed(b"Testing"));
+        // Cut in the middle of the trailer of the second member
+        data.truncate(data.len() - 4);
+
+        let options = InflateOptions {
+            recover: true,
+            ..multi_member()
+        };
+        let mut reader = BitReader::new(Box::new(Cursor::new(data.clone())));
+        let mut out = vec![];
+        let inflated = rfc1952_with_options(&mut reader, &mut out, &options)
+            .unwrap();
+
+        assert_eq!(out, b"TestingTesting");
+        assert_eq!(inflated.size, 14);
+        assert!(inflated.error.unwrap().is_unexpected_eof());
+
+        let mut reader = BitReader::new(Box::new(Cursor::new(data)));
+        assert!(rfc1952_members(&mut reader, &mut io::sink(), &options)
+            .is_err());
     }
-    Ok(String::from_utf8(name_bytes)
-        .unwrap_or("UnparsableName".to_string()))
 }
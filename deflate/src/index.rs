@@ -0,0 +1,322 @@
+// Random access into gzip files. Deflate blocks can only be decoded in
+// order, but a block only depends on the 32K of output before it. Saving
+// that window every now and then at a block boundary lets decoding start
+// from there instead of from the beginning, like zlib's zran.c does.
+
+use std::io;
+use std::io::{
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+
+use crate::bit_reader::BitReader;
+use crate::checksum::{
+    ChecksumWriter,
+    Crc32,
+};
+use crate::deflate::{
+    Inflate,
+    InflateOptions,
+    MAX_LOOKBACK,
+};
+use crate::error::DeflateError;
+use crate::gzip::{
+    check_trailer,
+    read_header,
+};
+use crate::inflater::Inflater;
+
+// Identifies the serialized index, followed by a version number
+const MAGIC: &[u8; 4] = b"GZIX";
+const VERSION: u8 = 1;
+
+// A place in the gzip file where decoding can start from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipCheckpoint {
+    // Position of the first bit of a deflate block in the compressed data
+    pub bit_position: u64,
+    // Offset of the first byte decoded from the block in the decompressed
+    // data
+    pub output: u64,
+    // Up to 32K of output before the block, empty at the start of a member
+    pub window: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipIndex {
+    // Sorted by `output`, the first one is at the start of the data
+    checkpoints: Vec<GzipCheckpoint>,
+    // Size of the decompressed data
+    size: u64,
+}
+
+impl GzipIndex {
+    // Decompresses all the members of the gzip file in `data` to `out`,
+    // adding a checkpoint at the first block boundary after every `span`
+    // bytes of output. Each checkpoint takes up to 32K, so something in
+    // the order of a megabyte is a reasonable span. Positions are counted
+    // from where `data` starts.
+    pub fn build(data: &mut BitReader, out: &mut dyn Write, span: u64)
+            -> Result<GzipIndex, DeflateError> {
+        let options = InflateOptions::default();
+        let mut checkpoints: Vec<GzipCheckpoint> = vec![];
+        let mut output = 0;
+
+        loop {
+            read_header(data, options.verify_checksums)?;
+
+            let mut inflate = Inflate::new();
+            let mut out = ChecksumWriter::new(out, Crc32::new());
+            let mut size = 0;
+
+            while !inflate.is_done() {
+                if inflate.at_block_boundary() {
+                    let position = output + inflate.pending_len() as u64;
+                    let due = checkpoints.last()
+                        .is_none_or(|last| position - last.output >= span);
+
+                    if due {
+                        checkpoints.push(GzipCheckpoint {
+                            bit_position: data.bit_position(),
+                            output: position,
+                            window: inflate.window(),
+                        });
+                    }
+                }
+
+                inflate.fill_block(data, MAX_LOOKBACK)?;
+
+                let (first, second) = inflate.pending();
+                out.write_all(first)?;
+                out.write_all(second)?;
+
+                let length = inflate.pending_len();
+                inflate.consume(length);
+                output += length as u64;
+                size += length;
+            }
+
+            check_trailer(data, out.value(), size, &options)?;
+
+            if data.at_end()? {
+                break;
+            }
+        }
+
+        Ok(GzipIndex { checkpoints, size: output })
+    }
+
+    pub fn checkpoints(&self) -> &[GzipCheckpoint] {
+        &self.checkpoints
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Returns a reader for the decompressed data from `offset` on, `data`
+    // is the gzip file the index was built from. Decoding starts from the
+    // closest checkpoint before `offset`. The checksums are not verified.
+    pub fn seek<'a, R: Read + Seek + 'a>(&self, mut data: R, offset: u64)
+            -> Result<Inflater<'a>, DeflateError> {
+        let index = match self.checkpoints
+                .binary_search_by_key(&offset, |checkpoint| checkpoint.output) {
+            Ok(index) => index,
+            Err(0) => return Err(DeflateError::InvalidGzipIndex),
+            Err(index) => index - 1,
+        };
+        let checkpoint = &self.checkpoints[index];
+
+        data.seek(SeekFrom::Start(checkpoint.bit_position / 8))?;
+        let mut inflater = Inflater::resume(
+            data, (checkpoint.bit_position % 8) as usize, &checkpoint.window)?;
+        inflater.skip(offset - checkpoint.output)?;
+
+        Ok(inflater)
+    }
+
+    // Layout: the magic number and version, the decompressed size and the
+    // number of checkpoints, then for each checkpoint its bit position,
+    // output offset, window size and window. Numbers are little endian.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&self.size.to_le_bytes())?;
+        out.write_all(&(self.checkpoints.len() as u64).to_le_bytes())?;
+
+        for checkpoint in &self.checkpoints {
+            out.write_all(&checkpoint.bit_position.to_le_bytes())?;
+            out.write_all(&checkpoint.output.to_le_bytes())?;
+            out.write_all(&(checkpoint.window.len() as u32).to_le_bytes())?;
+            out.write_all(&checkpoint.window)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(data: &mut dyn Read) -> Result<GzipIndex, DeflateError> {
+        let mut magic = [0; 5];
+        data.read_exact(&mut magic)?;
+        if &magic[..4] != MAGIC || magic[4] != VERSION {
+            return Err(DeflateError::InvalidGzipIndex);
+        }
+
+        let size = read_u64(data)?;
+        let count = read_u64(data)?;
+
+        let mut checkpoints: Vec<GzipCheckpoint> = vec![];
+        for _ in 0..count {
+            let bit_position = read_u64(data)?;
+            let output = read_u64(data)?;
+
+            let mut length = [0; 4];
+            data.read_exact(&mut length)?;
+            let length = u32::from_le_bytes(length) as usize;
+
+            let sorted = checkpoints.last()
+                .is_none_or(|last| last.output <= output);
+            if length > MAX_LOOKBACK || output > size || !sorted {
+                return Err(DeflateError::InvalidGzipIndex);
+            }
+
+            let mut window = vec![0; length];
+            data.read_exact(&mut window)?;
+
+            checkpoints.push(GzipCheckpoint { bit_position, output, window });
+        }
+
+        Ok(GzipIndex { checkpoints, size })
+    }
+}
+
+fn read_u64(data: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    data.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::compress::CompressionLevel;
+    use crate::gzip::{
+        rfc1952_compress,
+        GzipHeader,
+    };
+    use crate::test_data::sample;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        rfc1952_compress(data, &mut out, CompressionLevel::default(),
+                         &GzipHeader::default()).unwrap();
+        out
+    }
+
+    fn build(compressed: &[u8], span: u64) -> (GzipIndex, Vec<u8>) {
+        let mut out = vec![];
+        let index = GzipIndex::build(&mut BitReader::from_slice(compressed),
+                                     &mut out, span).unwrap();
+        (index, out)
+    }
+
+    fn read_at(index: &GzipIndex, compressed: &[u8], offset: u64,
+               length: usize) -> Vec<u8> {
+        let inflater = index.seek(Cursor::new(compressed), offset).unwrap();
+        let mut out = vec![];
+        inflater.take(length as u64).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_build() {
+        let data = sample(1_000_000);
+        let compressed = gzip(&data);
+        let (index, out) = build(&compressed, 100_000);
+
+        assert_eq!(out, data);
+        assert_eq!(index.size(), data.len() as u64);
+
+        let checkpoints = index.checkpoints();
+        assert!(checkpoints.len() > 2);
+        assert_eq!(checkpoints[0].output, 0);
+        assert!(checkpoints[0].window.is_empty());
+        // Blocks don't end on byte boundaries
+        assert!(checkpoints.iter().any(|c| c.bit_position % 8 != 0));
+
+        for pair in checkpoints.windows(2) {
+            assert!(pair[1].output - pair[0].output >= 100_000);
+            assert_eq!(pair[1].window.len(), MAX_LOOKBACK);
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        let data = sample(1_000_000);
+        let compressed = gzip(&data);
+        let (index, _) = build(&compressed, 100_000);
+
+        for &offset in &[0, 1, 99_999, 100_000, 345_678, 999_000] {
+            assert_eq!(read_at(&index, &compressed, offset, 1000),
+                       &data[offset as usize..offset as usize + 1000]);
+        }
+
+        // Exactly at each checkpoint
+        for checkpoint in index.checkpoints() {
+            let offset = checkpoint.output as usize;
+            assert_eq!(read_at(&index, &compressed, checkpoint.output, 10),
+                       &data[offset..offset + 10]);
+        }
+
+        // Up to the end and past it
+        assert_eq!(read_at(&index, &compressed, 999_990, 100),
+                   &data[999_990..]);
+        assert_eq!(read_at(&index, &compressed, 2_000_000, 100), b"");
+    }
+
+    #[test]
+    fn test_multi_member() {
+        let first = sample(300_000);
+        let second = b"The second member.".repeat(10_000);
+
+        let mut compressed = gzip(&first);
+        compressed.extend(gzip(&second));
+
+        let mut data = first.clone();
+        data.extend(&second);
+
+        let (index, out) = build(&compressed, 50_000);
+        assert_eq!(out, data);
+
+        // Starting in the first member and reading into the second one
+        assert_eq!(read_at(&index, &compressed, 290_000, 20_000),
+                   &data[290_000..310_000]);
+        assert_eq!(read_at(&index, &compressed, 400_000, 1000),
+                   &data[400_000..401_000]);
+    }
+
+    #[test]
+    fn test_write_read() {
+        let compressed = gzip(&sample(500_000));
+        let (index, _) = build(&compressed, 100_000);
+
+        let mut serialized = vec![];
+        index.write(&mut serialized).unwrap();
+
+        let read = GzipIndex::read(&mut Cursor::new(&serialized)).unwrap();
+        assert_eq!(read, index);
+
+        serialized[0] = b'X';
+        match GzipIndex::read(&mut Cursor::new(&serialized)) {
+            Err(DeflateError::InvalidGzipIndex) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let truncated = &serialized[..serialized.len() - 1];
+        assert!(GzipIndex::read(&mut Cursor::new(truncated)).is_err());
+    }
+}
@@ -0,0 +1,940 @@
+// Streaming decoders, these inflate the data a piece at a time so that
+// callers don't need to hold the whole input or output in memory.
+
+use std::cmp;
+use std::io;
+use std::io::{
+    Read,
+    Write,
+};
+use std::mem;
+
+use crate::bit_reader::{
+    BitReader,
+    ReadBits,
+};
+use crate::checksum::{
+    Adler32,
+    Checksum,
+    Crc32,
+};
+use crate::deflate::{
+    check_zlib_trailer,
+    read_zlib_header,
+    Inflate,
+    InflateOptions,
+    Limits,
+    MAX_LOOKBACK,
+};
+use crate::error::DeflateError;
+use crate::gzip::{
+    check_trailer,
+    read_header,
+    GzipHeader,
+};
+
+// The framing around the deflate data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // RFC1951, no header or trailer
+    Raw,
+    // RFC1950
+    Zlib,
+    // RFC1952
+    Gzip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Header,
+    Body,
+    Trailer,
+    Done,
+    // Only padding is left after the last member
+    TrailingData,
+}
+
+// Reads the header and trailer around the deflate data and keeps track of
+// the checksums in between.
+struct Frame {
+    format: Format,
+    options: InflateOptions,
+    adler32: Adler32,
+    crc32: Crc32,
+    // Decompressed size of the current member
+    size: usize,
+    header: Option<GzipHeader>,
+    limits: Limits,
+}
+
+impl Frame {
+    fn new(format: Format, options: InflateOptions) -> Frame {
+        Frame {
+            format,
+            adler32: Adler32::new(),
+            crc32: Crc32::new(),
+            size: 0,
+            header: None,
+            limits: Limits::new(&options, 0),
+            options,
+        }
+    }
+
+    // Returns the preset dictionary for the data, if any
+    fn read_header(&mut self, data: &mut BitReader)
+            -> Result<&[u8], DeflateError> {
+        match self.format {
+            Format::Raw => {},
+            Format::Zlib => return read_zlib_header(data, &self.options),
+            Format::Gzip => {
+                self.header = Some(read_header(data,
+                    self.options.verify_checksums)?);
+            },
+        }
+        Ok(&[])
+    }
+
+    fn update(&mut self, decoded: &[u8]) {
+        match self.format {
+            Format::Raw => {},
+            Format::Zlib => self.adler32.update(decoded),
+            Format::Gzip => self.crc32.update(decoded),
+        }
+        self.size += decoded.len();
+    }
+
+    // Size of the trailer in bits, not counting the padding before it
+    fn trailer_bits(&self) -> u64 {
+        match self.format {
+            Format::Raw => 0,
+            Format::Zlib => 32,
+            Format::Gzip => 64,
+        }
+    }
+
+    // Whether a stream without its trailer, or with only part of it, is an
+    // error, see `check_zlib_trailer` and `check_trailer`
+    fn requires_trailer(&self) -> bool {
+        match self.format {
+            Format::Raw => false,
+            Format::Zlib => self.options.verify_checksums
+                && !self.options.ignore_trailing_data,
+            Format::Gzip => self.options.verify_checksums,
+        }
+    }
+
+    fn read_trailer(&mut self, data: &mut BitReader)
+            -> Result<(), DeflateError> {
+        match self.format {
+            Format::Raw => {},
+            Format::Zlib => {
+                check_zlib_trailer(data, self.adler32.value(), &self.options)?;
+            },
+            Format::Gzip => check_trailer(data, self.crc32.value(), self.size,
+                                          &self.options)?,
+        }
+
+        self.adler32 = Adler32::new();
+        self.crc32 = Crc32::new();
+        self.size = 0;
+        Ok(())
+    }
+
+    // RFC1952 ~ 2.2 - Whether another member could follow this one
+    fn multi_member(&self) -> bool {
+        self.format == Format::Gzip && self.options.multi_member
+    }
+
+    // Whether a header that can't be read is padding after the last member
+    // rather than an error, the first member always needs to be there.
+    fn ignores_header_error(&self) -> bool {
+        self.options.ignore_trailing_data && self.header.is_some()
+    }
+}
+
+// A pull-based decoder, the data is inflated as it's read
+pub struct Inflater<'a> {
+    data: BitReader<'a>,
+    frame: Frame,
+    stage: Stage,
+    inflate: Inflate,
+    // In recovery mode, an error that is reported once the output decoded
+    // before it has been read
+    error: Option<DeflateError>,
+}
+
+impl <'a> Inflater<'a> {
+    pub fn new<R: Read + 'a>(data: R, format: Format) -> Inflater<'a> {
+        Inflater::with_options(data, format, InflateOptions::default())
+    }
+
+    pub fn with_options<R: Read + 'a>(data: R, format: Format,
+                                      options: InflateOptions) -> Inflater<'a> {
+        let mut inflate = Inflate::new();
+        inflate.keep_partial = options.recover;
+
+        Inflater {
+            data: BitReader::new(Box::new(data)),
+            frame: Frame::new(format, options),
+            stage: Stage::Header,
+            inflate,
+            error: None,
+        }
+    }
+
+    // Picks up a gzip file at a block boundary, see `GzipIndex`. `data`
+    // starts at the byte with the first bit of the block, of which the
+    // first `bit_offset` bits belong to the previous block. The checksums
+    // can't be verified without the start of the member.
+    pub(crate) fn resume<R: Read + 'a>(data: R, bit_offset: usize,
+                                       window: &[u8])
+            -> Result<Inflater<'a>, DeflateError> {
+        let options = InflateOptions {
+            verify_checksums: false,
+            multi_member: true,
+            ..InflateOptions::default()
+        };
+
+        let mut inflater = Inflater::with_options(data, Format::Gzip, options);
+        inflater.data.read_bits(bit_offset)?;
+        inflater.inflate.set_dictionary(window);
+        inflater.stage = Stage::Body;
+
+        Ok(inflater)
+    }
+
+    // The header of the gzip member being read, this is available after the
+    // first call to `read`.
+    pub fn gzip_header(&self) -> Option<&GzipHeader> {
+        self.frame.header.as_ref()
+    }
+
+    // Decodes and drops the next `length` bytes of output
+    pub(crate) fn skip(&mut self, mut length: u64) -> Result<(), DeflateError> {
+        let mut buf = vec![0; MAX_LOOKBACK];
+        while length > 0 {
+            let wanted = cmp::min(length, buf.len() as u64) as usize;
+            let read = self.read_impl(&mut buf[..wanted])?;
+            if read == 0 {
+                break;
+            }
+            length -= read as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl <'a> Inflater<'a> {
+    fn read_impl(&mut self, buf: &mut [u8]) -> Result<usize, DeflateError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.stage {
+                Stage::Header => {
+                    let ignores_error = self.frame.ignores_header_error();
+                    self.stage = match self.frame.read_header(&mut self.data) {
+                        Ok(dictionary) => {
+                            self.inflate.set_dictionary(dictionary);
+                            Stage::Body
+                        },
+                        Err(_) if ignores_error =>
+                            Stage::TrailingData,
+                        Err(e) => return Err(e),
+                    };
+                },
+                Stage::Body => {
+                    if self.error.is_none() {
+                        match self.inflate.fill(&mut self.data, buf.len()) {
+                            Ok(()) => {},
+                            Err(e) if self.frame.options.recover =>
+                                self.error = Some(e),
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    let length = self.inflate.read_pending(buf);
+                    self.frame.limits.add(self.data.position(), length)?;
+                    self.frame.update(&buf[..length]);
+
+                    if self.inflate.is_done() {
+                        self.stage = Stage::Trailer;
+                    }
+
+                    if length > 0 {
+                        return Ok(length);
+                    }
+
+                    if let Some(e) = self.error.take() {
+                        self.stage = Stage::Done;
+                        return Err(e);
+                    }
+                },
+                Stage::Trailer => {
+                    self.frame.read_trailer(&mut self.data)?;
+
+                    self.stage = Stage::Done;
+                    if self.frame.multi_member() && !self.data.at_end()? {
+                        self.inflate = Inflate::new();
+                        self.inflate.keep_partial = self.frame.options.recover;
+                        self.stage = Stage::Header;
+                    }
+                },
+                Stage::Done | Stage::TrailingData => return Ok(0),
+            }
+        }
+    }
+}
+
+impl <'a> Read for Inflater<'a> {
+    // Malformed data is reported as `InvalidData`, with the `DeflateError`
+    // as the inner error.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_impl(buf).map_err(io::Error::from)
+    }
+}
+
+// Returned by `PushInflater::push`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateStatus {
+    // All the input has been used, more is needed to make progress
+    NeedsInput,
+    // The end of the stream has been reached. For multi-member gzip this is
+    // also returned between members, pushing more input decodes the next one.
+    // Otherwise, input pushed from here on is dropped.
+    Done,
+}
+
+// A decoder for data that arrives in pieces, the input can be split at any
+// point, including in the middle of a block or of a header.
+pub struct PushInflater {
+    frame: Frame,
+    stage: Stage,
+    inflate: Inflate,
+    // Input that hasn't been decoded yet, the first `bit_offset` bits of it
+    // have already been consumed.
+    input: Vec<u8>,
+    bit_offset: usize,
+    // Position in `input` after the last complete step
+    mark: u64,
+    // Bytes of input dropped from the front of `input` so far
+    consumed: u64,
+}
+
+impl PushInflater {
+    pub fn new(format: Format) -> PushInflater {
+        PushInflater::with_options(format, InflateOptions::default())
+    }
+
+    pub fn with_options(format: Format, options: InflateOptions)
+            -> PushInflater {
+        PushInflater {
+            frame: Frame::new(format, options),
+            stage: Stage::Header,
+            inflate: Inflate::new(),
+            input: vec![],
+            bit_offset: 0,
+            mark: 0,
+            consumed: 0,
+        }
+    }
+
+    // The header of the gzip member being decoded, if it has been read
+    pub fn gzip_header(&self) -> Option<&GzipHeader> {
+        self.frame.header.as_ref()
+    }
+
+    // Decodes as much as possible of `input`, together with what was left
+    // over from the previous calls, and writes the output to `out`. Input
+    // that ends in the middle of a step is kept until the next call.
+    pub fn push(&mut self, input: &[u8], out: &mut dyn Write)
+            -> Result<InflateStatus, DeflateError> {
+        let mut buffered = mem::take(&mut self.input);
+        buffered.extend_from_slice(input);
+
+        let status = {
+            let mut data = BitReader::from_slice(&buffered);
+            data.read_bits(self.bit_offset)?;
+            self.mark = data.bit_position();
+
+            let available = buffered.len() as u64 * 8;
+            match self.decode(&mut data, available, out) {
+                Err(ref e) if e.is_unexpected_eof() =>
+                    Ok(InflateStatus::NeedsInput),
+                status => status,
+            }
+        };
+
+        // Whatever happens, hand out the output decoded so far
+        self.flush(out, self.mark)?;
+
+        // Roll back to the end of the last complete step
+        buffered.drain(..(self.mark / 8) as usize);
+        self.consumed += self.mark / 8;
+        self.bit_offset = (self.mark % 8) as usize;
+        self.input = buffered;
+
+        status
+    }
+
+    // Tells the decoder that there is no more input. A stream that isn't
+    // done yet is truncated, in recovery mode the output in what is left of
+    // the input is written before that's reported. A trailer that isn't
+    // required can be missing or cut short.
+    pub fn finish(&mut self, out: &mut dyn Write)
+            -> Result<InflateStatus, DeflateError> {
+        if self.push(&[], out)? == InflateStatus::Done {
+            return Ok(InflateStatus::Done);
+        }
+
+        if self.stage == Stage::Trailer && !self.frame.requires_trailer() {
+            let input = mem::take(&mut self.input);
+            let mut data = BitReader::from_slice(&input);
+            data.read_bits(self.bit_offset)?;
+
+            self.frame.read_trailer(&mut data)?;
+            self.stage = Stage::Done;
+            return Ok(InflateStatus::Done);
+        }
+
+        if self.stage == Stage::Body && self.frame.options.recover {
+            let input = mem::take(&mut self.input);
+            let mut data = BitReader::from_slice(&input);
+            data.read_bits(self.bit_offset)?;
+
+            // Nothing is going to be retried from here on
+            self.inflate.keep_partial = true;
+            let mut result = Ok(());
+            while result.is_ok() && !self.inflate.is_finished() {
+                result = self.inflate.step(&mut data, MAX_LOOKBACK);
+                if self.inflate.pending_len() >= MAX_LOOKBACK {
+                    self.flush(out, data.bit_position())?;
+                }
+            }
+            self.flush(out, data.bit_position())?;
+            self.stage = Stage::Done;
+            result?;
+        }
+
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                           "Stream is truncated.").into())
+    }
+
+    fn decode(&mut self, data: &mut BitReader, available: u64,
+              out: &mut dyn Write) -> Result<InflateStatus, DeflateError> {
+        loop {
+            self.mark = data.bit_position();
+
+            match self.stage {
+                Stage::Header => {
+                    let ignores_error = self.frame.ignores_header_error();
+                    self.stage = match self.frame.read_header(data) {
+                        Ok(dictionary) => {
+                            self.inflate.set_dictionary(dictionary);
+                            Stage::Body
+                        },
+                        Err(ref e) if ignores_error
+                            && !e.is_unexpected_eof() => Stage::TrailingData,
+                        Err(e) => return Err(e),
+                    };
+                },
+                Stage::Body => {
+                    // A symbol takes at most 48 bits, so this much output
+                    // can be decoded without running out of input halfway
+                    // through a step.
+                    let limit = ((available - self.mark) / 48) as usize;
+                    self.inflate.step(data, cmp::min(limit, MAX_LOOKBACK))?;
+
+                    if self.inflate.pending_len() >= MAX_LOOKBACK {
+                        self.flush(out, data.bit_position())?;
+                    }
+
+                    if self.inflate.is_finished() {
+                        self.flush(out, data.bit_position())?;
+                        self.stage = Stage::Trailer;
+                    }
+                },
+                Stage::Trailer => {
+                    // When verification is off the trailer is optional, so
+                    // wait until all of it is here before reading it.
+                    let mut needed = self.frame.trailer_bits();
+                    if needed > 0 {
+                        needed += (8 - self.mark % 8) % 8;
+                    }
+                    if available - self.mark < needed {
+                        return Ok(InflateStatus::NeedsInput);
+                    }
+
+                    self.frame.read_trailer(data)?;
+                    self.stage = Stage::Done;
+                },
+                Stage::Done => {
+                    if self.frame.multi_member() && available > self.mark {
+                        self.inflate = Inflate::new();
+                        self.stage = Stage::Header;
+                        continue;
+                    }
+
+                    // Anything pushed after the end isn't part of the
+                    // stream, drop it rather than keep it around
+                    self.mark = available;
+                    return Ok(InflateStatus::Done);
+                },
+                Stage::TrailingData => {
+                    // Drop the padding, and anything pushed after it
+                    self.mark = available;
+                    return Ok(InflateStatus::Done);
+                },
+            }
+        }
+    }
+
+    // The pending output was decoded from the input up to `position`, in
+    // bits from the start of the buffered input.
+    fn flush(&mut self, out: &mut dyn Write, position: u64)
+            -> Result<(), DeflateError> {
+        let input = self.consumed + position.div_ceil(8);
+        self.frame.limits.add(input, self.inflate.pending_len())?;
+
+        let (first, second) = self.inflate.pending();
+        for pending in &[first, second] {
+            out.write_all(pending)?;
+            self.frame.update(pending);
+        }
+
+        let length = first.len() + second.len();
+        self.inflate.consume(length);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::compress::{
+        rfc1950_compress,
+        rfc1951_compress,
+        CompressionLevel,
+    };
+    use crate::error::Limit;
+    use crate::gzip::rfc1952_compress;
+    use crate::test_data::lines;
+
+    // Reads with a small buffer to exercise the partial reads
+    fn read_small(mut inflater: Inflater) -> io::Result<Vec<u8>> {
+        let mut result = vec![];
+        let mut buf = [0; 7];
+        loop {
+            let length = inflater.read(&mut buf)?;
+            if length == 0 {
+                return Ok(result);
+            }
+            result.extend_from_slice(&buf[..length]);
+        }
+    }
+
+    #[test]
+    fn test_raw() {
+        let data = lines(100_000);
+        let mut compressed = vec![];
+        rfc1951_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+
+        let inflater = Inflater::new(&compressed[..], Format::Raw);
+        assert_eq!(read_small(inflater).unwrap(), data);
+    }
+
+    #[test]
+    fn test_zlib() {
+        let data = lines(100_000);
+        let mut compressed = vec![];
+        rfc1950_compress(&data, &mut compressed, CompressionLevel::Fast)
+            .unwrap();
+
+        let mut result = vec![];
+        Inflater::new(&compressed[..], Format::Zlib)
+            .read_to_end(&mut result).unwrap();
+        assert_eq!(result, data);
+
+        let inflater = Inflater::new(&compressed[..], Format::Zlib);
+        assert_eq!(read_small(inflater).unwrap(), data);
+    }
+
+    #[test]
+    fn test_zlib_checksum_mismatch() {
+        let mut compressed = vec![];
+        rfc1950_compress(b"TestingTesting", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0x01;
+
+        let mut result = vec![];
+        assert!(Inflater::new(&compressed[..], Format::Zlib)
+            .read_to_end(&mut result).is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let data = lines(100_000);
+        let header = GzipHeader {
+            name: Some("sample.txt".to_string()),
+            ..GzipHeader::default()
+        };
+
+        let mut compressed = vec![];
+        rfc1952_compress(&data, &mut compressed, CompressionLevel::Stored,
+                         &header).unwrap();
+        rfc1952_compress(b"more", &mut compressed, CompressionLevel::Best,
+                         &header).unwrap();
+
+        let mut inflater = Inflater::new(&compressed[..], Format::Gzip);
+        let mut result = vec![];
+        inflater.read_to_end(&mut result).unwrap();
+        assert_eq!(result, data);
+        assert_eq!(inflater.gzip_header().unwrap().name, header.name);
+
+        let options = InflateOptions {
+            multi_member: true,
+            ..InflateOptions::default()
+        };
+        let inflater = Inflater::with_options(&compressed[..], Format::Gzip,
+                                              options);
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"more");
+        assert_eq!(read_small(inflater).unwrap(), expected);
+    }
+
+    // Pushes `data` in chunks of `size` bytes
+    fn push_chunks(mut inflater: PushInflater, data: &[u8], size: usize)
+            -> Result<Vec<u8>, DeflateError> {
+        let mut result = vec![];
+        let mut status = InflateStatus::NeedsInput;
+        for chunk in data.chunks(size) {
+            status = inflater.push(chunk, &mut result)?;
+        }
+
+        assert_eq!(status, InflateStatus::Done);
+        Ok(result)
+    }
+
+    #[test]
+    fn test_push() {
+        let data = lines(2_000);
+
+        // Stored, fixed and dynamic blocks
+        for &level in &[CompressionLevel::Stored, CompressionLevel::Fast,
+                        CompressionLevel::Default] {
+            let mut compressed = vec![];
+            rfc1951_compress(&data, &mut compressed, level).unwrap();
+
+            for &size in &[1, 7, 4096, compressed.len()] {
+                let inflater = PushInflater::new(Format::Raw);
+                assert_eq!(push_chunks(inflater, &compressed, size).unwrap(),
+                           data);
+            }
+        }
+
+        let mut compressed = vec![];
+        rfc1951_compress(b"TestingTesting", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let inflater = PushInflater::new(Format::Raw);
+        assert_eq!(push_chunks(inflater, &compressed, 1).unwrap(),
+                   b"TestingTesting");
+    }
+
+    #[test]
+    fn test_push_zlib() {
+        let data = lines(2_000);
+        let mut compressed = vec![];
+        rfc1950_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+
+        for &size in &[1, 3, 1000] {
+            let inflater = PushInflater::new(Format::Zlib);
+            assert_eq!(push_chunks(inflater, &compressed, size).unwrap(),
+                       data);
+        }
+    }
+
+    #[test]
+    fn test_push_gzip() {
+        let header = GzipHeader {
+            name: Some("sample.txt".to_string()),
+            comment: Some("A comment".to_string()),
+            ..GzipHeader::default()
+        };
+
+        let mut compressed = vec![];
+        rfc1952_compress(b"Testing", &mut compressed,
+                         CompressionLevel::Default, &header).unwrap();
+        rfc1952_compress(b"Testing", &mut compressed,
+                         CompressionLevel::Default, &header).unwrap();
+
+        let options = InflateOptions {
+            multi_member: true,
+            ..InflateOptions::default()
+        };
+        let inflater = PushInflater::with_options(Format::Gzip, options);
+        assert_eq!(push_chunks(inflater, &compressed, 1).unwrap(),
+                   b"TestingTesting");
+    }
+
+    #[test]
+    fn test_gzip_trailing_data() {
+        let mut compressed = vec![];
+        rfc1952_compress(b"Testing", &mut compressed,
+                         CompressionLevel::Default, &GzipHeader::default())
+            .unwrap();
+        compressed.extend_from_slice(b"\0\0\0\0garbage");
+
+        let options = InflateOptions {
+            multi_member: true,
+            ..InflateOptions::default()
+        };
+        let inflater = Inflater::with_options(&compressed[..], Format::Gzip,
+                                              options.clone());
+        assert!(read_small(inflater).is_err());
+
+        let options = InflateOptions {
+            ignore_trailing_data: true,
+            ..options
+        };
+        let inflater = Inflater::with_options(&compressed[..], Format::Gzip,
+                                              options.clone());
+        assert_eq!(read_small(inflater).unwrap(), b"Testing");
+
+        let inflater = PushInflater::with_options(Format::Gzip, options);
+        assert_eq!(push_chunks(inflater, &compressed, 3).unwrap(), b"Testing");
+    }
+
+    #[test]
+    fn test_push_needs_input() {
+        let mut compressed = vec![];
+        rfc1950_compress(&lines(2_000), &mut compressed,
+                         CompressionLevel::Default).unwrap();
+
+        let mut inflater = PushInflater::new(Format::Zlib);
+        let mut result = vec![];
+        let half = compressed.len() / 2;
+
+        assert_eq!(inflater.push(&compressed[..half], &mut result).unwrap(),
+                   InflateStatus::NeedsInput);
+        // Whatever could be decoded is returned straight away
+        assert!(!result.is_empty());
+
+        // Without the trailer the stream is not done yet
+        let end = compressed.len() - 2;
+        assert_eq!(inflater.push(&compressed[half..end], &mut result).unwrap(),
+                   InflateStatus::NeedsInput);
+        assert_eq!(result, lines(2_000));
+
+        assert_eq!(inflater.push(&compressed[end..], &mut result).unwrap(),
+                   InflateStatus::Done);
+    }
+
+    #[test]
+    fn test_push_after_done() {
+        let mut compressed = vec![];
+        rfc1950_compress(b"TestingTesting", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+
+        let mut inflater = PushInflater::new(Format::Zlib);
+        let mut result = vec![];
+        assert_eq!(inflater.push(&compressed, &mut result).unwrap(),
+                   InflateStatus::Done);
+
+        for _ in 0..3 {
+            assert_eq!(inflater.push(b"garbage", &mut result).unwrap(),
+                       InflateStatus::Done);
+            assert!(inflater.input.is_empty());
+        }
+        assert_eq!(inflater.finish(&mut result).unwrap(), InflateStatus::Done);
+        assert_eq!(result, b"TestingTesting");
+    }
+
+    #[test]
+    fn test_push_checksum_mismatch() {
+        let mut compressed = vec![];
+        rfc1950_compress(b"TestingTesting", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0x01;
+
+        let inflater = PushInflater::new(Format::Zlib);
+        assert!(push_chunks(inflater, &compressed, 3).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = vec![0; 100_000];
+        let mut compressed = vec![];
+        rfc1950_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+
+        let options = InflateOptions {
+            max_ratio: Some(10),
+            ..InflateOptions::default()
+        };
+
+        let inflater = Inflater::with_options(&compressed[..], Format::Zlib,
+                                              options.clone());
+        let error = read_small(inflater).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let inflater = PushInflater::with_options(Format::Zlib, options);
+        match push_chunks(inflater, &compressed, 5) {
+            Err(DeflateError::LimitExceeded(Limit::Ratio(10))) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_recover() {
+        let data = lines(10_000);
+        let mut compressed = vec![];
+        rfc1951_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        let options = InflateOptions {
+            recover: true,
+            ..InflateOptions::default()
+        };
+        let mut inflater = Inflater::with_options(&compressed[..], Format::Raw,
+                                                  options);
+        let mut result = vec![];
+        let error = inflater.read_to_end(&mut result).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!result.is_empty());
+        assert!(data.starts_with(&result));
+    }
+
+    #[test]
+    fn test_recover_second_member() {
+        let options = InflateOptions {
+            multi_member: true,
+            recover: true,
+            ..InflateOptions::default()
+        };
+        let recovered = |compressed: &[u8]| {
+            let mut inflater = Inflater::with_options(compressed, Format::Gzip,
+                                                      options.clone());
+            let mut result = vec![];
+            let error = inflater.read_to_end(&mut result).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+            result
+        };
+
+        for &level in &[CompressionLevel::Stored, CompressionLevel::Default] {
+            let mut second = vec![];
+            rfc1952_compress(&lines(1_000), &mut second, level,
+                             &GzipHeader::default()).unwrap();
+            second.truncate(second.len() / 2);
+
+            let mut compressed = vec![];
+            rfc1952_compress(&lines(100), &mut compressed,
+                             CompressionLevel::Default,
+                             &GzipHeader::default()).unwrap();
+            compressed.extend_from_slice(&second);
+
+            // The second member is recovered as well as on its own
+            let mut expected = lines(100);
+            expected.extend(recovered(&second));
+            assert!(expected.len() > lines(100).len());
+            assert_eq!(recovered(&compressed), expected);
+        }
+    }
+
+    #[test]
+    fn test_push_recover() {
+        let data = lines(10_000);
+        let mut compressed = vec![];
+        rfc1951_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        // Without recovery the output is only what could be decoded for sure
+        let mut inflater = PushInflater::new(Format::Raw);
+        let mut result = vec![];
+        assert_eq!(inflater.push(&compressed, &mut result).unwrap(),
+                   InflateStatus::NeedsInput);
+        assert!(inflater.finish(&mut result).unwrap_err().is_unexpected_eof());
+        assert!(data.starts_with(&result));
+
+        let options = InflateOptions {
+            recover: true,
+            ..InflateOptions::default()
+        };
+        let mut expected = vec![];
+        Inflater::with_options(&compressed[..], Format::Raw, options.clone())
+            .read_to_end(&mut expected).unwrap_err();
+
+        let mut inflater = PushInflater::with_options(Format::Raw, options);
+        let mut result = vec![];
+        for chunk in compressed.chunks(7) {
+            inflater.push(chunk, &mut result).unwrap();
+        }
+        assert!(inflater.finish(&mut result).unwrap_err().is_unexpected_eof());
+        assert_eq!(result, expected);
+
+        // A complete stream finishes cleanly
+        let mut compressed = vec![];
+        rfc1951_compress(&data, &mut compressed, CompressionLevel::Default)
+            .unwrap();
+        let mut inflater = PushInflater::new(Format::Raw);
+        let mut result = vec![];
+        inflater.push(&compressed, &mut result).unwrap();
+        assert_eq!(inflater.finish(&mut result).unwrap(),
+                   InflateStatus::Done);
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn test_push_finish_without_trailer() {
+        let mut zlib = vec![];
+        rfc1950_compress(b"TestingTesting", &mut zlib,
+                         CompressionLevel::Default).unwrap();
+        let mut gzip = vec![];
+        rfc1952_compress(b"TestingTesting", &mut gzip,
+                         CompressionLevel::Default, &GzipHeader::default())
+            .unwrap();
+
+        let unverified = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        let trailing_data = InflateOptions {
+            ignore_trailing_data: true,
+            ..InflateOptions::default()
+        };
+        let cases = [
+            (Format::Zlib, &zlib, 4, &unverified, true),
+            (Format::Zlib, &zlib, 2, &trailing_data, true),
+            (Format::Zlib, &zlib, 2, &InflateOptions::default(), false),
+            (Format::Gzip, &gzip, 3, &unverified, true),
+            (Format::Gzip, &gzip, 8, &trailing_data, false),
+        ];
+
+        // The trailer is missing or cut short
+        for (format, compressed, missing, options, accepted) in cases {
+            let end = compressed.len() - missing;
+            let mut inflater = PushInflater::with_options(format,
+                                                          options.clone());
+            let mut result = vec![];
+            assert_eq!(inflater.push(&compressed[..end], &mut result).unwrap(),
+                       InflateStatus::NeedsInput);
+            assert_eq!(result, b"TestingTesting");
+
+            match inflater.finish(&mut result) {
+                Ok(status) if accepted =>
+                    assert_eq!(status, InflateStatus::Done),
+                Err(e) if !accepted => assert!(e.is_unexpected_eof()),
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+    }
+}
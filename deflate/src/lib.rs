@@ -1,17 +1,99 @@
-#![feature(reverse_bits)]
-
 mod deflate;
 mod gzip;
 mod bit_reader;
+mod bit_writer;
+mod checksum;
+mod compress;
+mod error;
+mod index;
+mod inflater;
+mod lzw;
+mod parallel;
+mod zip;
+
+#[cfg(test)]
+mod test_data;
 
 pub use crate::deflate::{
     rfc1950,
+    rfc1950_with_options,
     rfc1951,
+    rfc1951_with_options,
+    Inflated,
+    InflateOptions,
 };
 
 pub use crate::bit_reader::{
     ReadBits,
     BitReader,
+    MsbBitReader,
+};
+
+pub use crate::bit_writer::{
+    WriteBits,
+    BitWriter,
+    MsbBitWriter,
+};
+
+pub use crate::checksum::{
+    Adler32,
+    Checksum,
+    ChecksumMismatch,
+    Crc32,
 };
 
-pub use crate::gzip::rfc1952;
+pub use crate::error::{
+    DeflateError,
+    Limit,
+};
+
+pub use crate::compress::{
+    rfc1950_compress,
+    rfc1950_compress_with_dictionary,
+    rfc1951_compress,
+    CompressionLevel,
+    CompressWriter,
+};
+
+pub use crate::gzip::{
+    rfc1952,
+    rfc1952_compress,
+    rfc1952_header,
+    rfc1952_members,
+    rfc1952_with_options,
+    ExtraField,
+    GzipHeader,
+    GzipMember,
+    Os,
+};
+
+pub use crate::index::{
+    GzipCheckpoint,
+    GzipIndex,
+};
+
+pub use crate::inflater::{
+    Format,
+    InflateStatus,
+    Inflater,
+    PushInflater,
+};
+
+pub use crate::lzw::{
+    lzw_decode,
+    lzw_encode,
+    LzwOptions,
+};
+
+pub use crate::parallel::{
+    rfc1952_compress_parallel,
+    ParallelGzipWriter,
+    ParallelOptions,
+};
+
+pub use crate::zip::{
+    DosDateTime,
+    ZipArchive,
+    ZipEntry,
+    ZipMethod,
+};
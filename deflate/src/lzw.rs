@@ -0,0 +1,432 @@
+// Variable-width LZW as used by the LZWDecode filter in PDF (7.4.4), TIFF
+// and GIF. The three differ only in small details, see `LzwOptions`.
+
+use std::io;
+use std::io::{
+    ErrorKind,
+    Write,
+};
+
+use std::collections::HashMap;
+
+use crate::bit_reader::{
+    BitReader,
+    MsbBitReader,
+    ReadBits,
+};
+use crate::bit_writer::{
+    BitWriter,
+    MsbBitWriter,
+    WriteBits,
+};
+use crate::error::{
+    DeflateError,
+    Limit,
+};
+
+// Codes never get longer than this, so the table has at most 4096 entries
+const MAX_CODE_WIDTH: usize = 12;
+const TABLE_SIZE: usize = 1 << MAX_CODE_WIDTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LzwOptions {
+    // Number of bits in a literal, the clear code is `1 << code_size` and
+    // the end of data code the one after that. PDF and TIFF always use 8,
+    // GIF stores it in the image data and it goes from 2 to 8.
+    pub code_size: u8,
+    // PDF's EarlyChange: codes get one bit longer one code earlier than
+    // needed. This is the PDF default and what TIFF does, GIF doesn't.
+    pub early_change: bool,
+    // GIF packs codes starting from the least significant bit of each byte,
+    // PDF and TIFF from the most significant one.
+    pub lsb_first: bool,
+    // Decoding stops with `DeflateError::LimitExceeded` once the output is
+    // bigger than this.
+    pub max_output: Option<u64>,
+}
+
+impl Default for LzwOptions {
+    fn default() -> LzwOptions {
+        LzwOptions {
+            code_size: 8,
+            early_change: true,
+            lsb_first: false,
+            max_output: None,
+        }
+    }
+}
+
+impl LzwOptions {
+    pub fn gif(code_size: u8) -> LzwOptions {
+        LzwOptions {
+            code_size,
+            early_change: false,
+            lsb_first: true,
+            max_output: None,
+        }
+    }
+
+    fn clear_code(&self) -> u16 {
+        1 << self.code_size
+    }
+
+    fn check(&self) -> Result<(), DeflateError> {
+        if self.code_size < 2 || self.code_size > 8 {
+            return Err(DeflateError::InvalidLzwCodeSize(self.code_size));
+        }
+        Ok(())
+    }
+}
+
+// Decodes `data` to `out` and returns the number of bytes written. Data that
+// ends without an end of data code is accepted, as plenty of PDF producers
+// leave it out.
+pub fn lzw_decode(data: &[u8], out: &mut dyn Write,
+                  options: &LzwOptions) -> Result<u64, DeflateError> {
+    options.check()?;
+
+    if options.lsb_first {
+        decode(&mut BitReader::from_slice(data), out, options)
+    } else {
+        decode(&mut MsbBitReader::from_slice(data), out, options)
+    }
+}
+
+// Every string in the table is a previous string plus one byte, so we only
+// need to store that byte and where the previous string is.
+struct Table {
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    // The first byte and the length of the whole string
+    first: Vec<u8>,
+    length: Vec<u16>,
+    next: u16,
+}
+
+impl Table {
+    fn new(clear: u16) -> Table {
+        let mut table = Table {
+            prefix: vec![0; TABLE_SIZE],
+            suffix: vec![0; TABLE_SIZE],
+            first: vec![0; TABLE_SIZE],
+            length: vec![0; TABLE_SIZE],
+            next: clear + 2,
+        };
+
+        for code in 0..clear {
+            table.suffix[code as usize] = code as u8;
+            table.first[code as usize] = code as u8;
+            table.length[code as usize] = 1;
+        }
+
+        table
+    }
+
+    fn add(&mut self, prefix: u16, byte: u8) {
+        // When the table is full GIF keeps going with the codes it has,
+        // PDF and TIFF encoders send a clear code before this happens.
+        if self.next as usize == TABLE_SIZE {
+            return;
+        }
+
+        let next = self.next as usize;
+        self.prefix[next] = prefix;
+        self.suffix[next] = byte;
+        self.first[next] = self.first[prefix as usize];
+        self.length[next] = self.length[prefix as usize] + 1;
+        self.next += 1;
+    }
+
+    // Writes the string for `code` into `buffer`, back to front
+    fn string(&self, mut code: u16, buffer: &mut Vec<u8>) {
+        let length = self.length[code as usize] as usize;
+        buffer.resize(length, 0);
+
+        for i in (0..length).rev() {
+            buffer[i] = self.suffix[code as usize];
+            code = self.prefix[code as usize];
+        }
+    }
+}
+
+fn decode<R: ReadBits>(reader: &mut R, out: &mut dyn Write,
+                       options: &LzwOptions) -> Result<u64, DeflateError> {
+    let clear = options.clear_code();
+    let end = clear + 1;
+    let initial_width = options.code_size as usize + 1;
+    let early_change = options.early_change as usize;
+
+    let mut table = Table::new(clear);
+    let mut width = initial_width;
+    let mut previous: Option<u16> = None;
+    let mut buffer = Vec::with_capacity(TABLE_SIZE);
+    let mut written = 0u64;
+
+    loop {
+        let code = match reader.read_number(width) {
+            Ok(code) => code as u16,
+            // Running out of data is the same as an end of data code
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if code == clear {
+            table.next = clear + 2;
+            width = initial_width;
+            previous = None;
+            continue;
+        }
+
+        if code == end {
+            break;
+        }
+
+        match previous {
+            None if code < clear => {
+                table.string(code, &mut buffer);
+            },
+            Some(previous) if code < table.next => {
+                table.string(code, &mut buffer);
+                table.add(previous, table.first[code as usize]);
+            },
+            // The string that the encoder just added, which is the previous
+            // string plus its own first byte.
+            Some(previous) if code == table.next => {
+                table.add(previous, table.first[previous as usize]);
+                table.string(code, &mut buffer);
+            },
+            _ => return Err(DeflateError::InvalidLzwCode(code)),
+        }
+
+        written += buffer.len() as u64;
+        if let Some(max_output) = options.max_output {
+            if written > max_output {
+                return Err(DeflateError::LimitExceeded(
+                    Limit::Output(max_output)));
+            }
+        }
+        out.write_all(&buffer)?;
+
+        previous = Some(code);
+        if table.next as usize + early_change >= 1 << width
+                && width < MAX_CODE_WIDTH {
+            width += 1;
+        }
+    }
+
+    Ok(written)
+}
+
+// Compresses `data` into `out`, starting with a clear code and finishing
+// with an end of data code. Returns the number of compressed bytes written.
+pub fn lzw_encode(data: &[u8], out: &mut dyn Write,
+                  options: &LzwOptions) -> io::Result<usize> {
+    options.check()?;
+
+    if options.lsb_first {
+        let mut writer = BitWriter::new(out);
+        encode(&mut writer, data, options)?;
+        writer.flush()?;
+        Ok(writer.written())
+    } else {
+        let mut writer = MsbBitWriter::new(out);
+        encode(&mut writer, data, options)?;
+        writer.flush()?;
+        Ok(writer.written())
+    }
+}
+
+fn encode<W: WriteBits>(writer: &mut W, data: &[u8],
+                        options: &LzwOptions) -> io::Result<()> {
+    let clear = options.clear_code();
+    let end = clear + 1;
+    let initial_width = options.code_size as usize + 1;
+    let early_change = options.early_change as usize;
+    let max_literal = clear - 1;
+
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = clear + 2;
+    let mut width = initial_width;
+    let mut current: Option<u16> = None;
+
+    writer.write_number(clear as u64, width)?;
+
+    for &byte in data {
+        if byte as u16 > max_literal {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                format!("Byte {} doesn't fit in {} bits.", byte,
+                        options.code_size)));
+        }
+
+        let prefix = match current {
+            None => {
+                current = Some(byte as u16);
+                continue;
+            },
+            Some(prefix) => prefix,
+        };
+
+        if let Some(&code) = table.get(&(prefix, byte)) {
+            current = Some(code);
+            continue;
+        }
+
+        writer.write_number(prefix as u64, width)?;
+        table.insert((prefix, byte), next);
+        next += 1;
+        current = Some(byte as u16);
+
+        // The decoder is one code behind, it adds the entry for `prefix`
+        // only after reading the next code.
+        if next as usize - 1 + early_change >= 1 << width
+                && width < MAX_CODE_WIDTH {
+            width += 1;
+        }
+
+        // Start over before the table is full, the decoder can't know when
+        // it's safe to stop adding entries otherwise.
+        if next as usize == TABLE_SIZE {
+            writer.write_number(clear as u64, width)?;
+            table.clear();
+            next = clear + 2;
+            width = initial_width;
+        }
+    }
+
+    if let Some(code) = current {
+        writer.write_number(code as u64, width)?;
+
+        // The decoder adds an entry after the last code too, which can make
+        // the end of data code one bit longer.
+        next += 1;
+        if next as usize - 1 + early_change >= 1 << width
+                && width < MAX_CODE_WIDTH {
+            width += 1;
+        }
+    }
+
+    writer.write_number(end as u64, width)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_data::letters;
+
+    fn decode(data: &[u8], options: &LzwOptions) -> Result<Vec<u8>, DeflateError> {
+        let mut out = vec![];
+        lzw_decode(data, &mut out, options)?;
+        Ok(out)
+    }
+
+    fn round_trip(data: &[u8], options: &LzwOptions) {
+        let mut encoded = vec![];
+        let written = lzw_encode(data, &mut encoded, options).unwrap();
+        assert_eq!(written, encoded.len());
+
+        assert_eq!(decode(&encoded, options).unwrap(), data);
+    }
+
+    #[test]
+    fn test_pdf_example() {
+        // 7.4.4.2 - Example of LZW encoding
+        let data = [0x80, 0x0B, 0x60, 0x50, 0x22, 0x0C, 0x0C, 0x85, 0x01];
+        let expected = [45, 45, 45, 45, 45, 65, 45, 45, 45, 66];
+
+        assert_eq!(decode(&data, &LzwOptions::default()).unwrap(), expected);
+
+        let mut encoded = vec![];
+        lzw_encode(&expected, &mut encoded, &LzwOptions::default()).unwrap();
+        assert_eq!(encoded, data);
+    }
+
+    #[test]
+    fn test_missing_end_of_data() {
+        let data = [0x80, 0x0B, 0x60, 0x50, 0x22, 0x0C, 0x0C, 0x84];
+        let expected = [45, 45, 45, 45, 45, 65, 45, 45, 45, 66];
+
+        assert_eq!(decode(&data, &LzwOptions::default()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_invalid_code() {
+        // Clear code followed by 260, which isn't in the table yet
+        let data = [0x80, 0x41, 0x00];
+
+        match decode(&data, &LzwOptions::default()) {
+            Err(DeflateError::InvalidLzwCode(260)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_gif() {
+        // The 10x10 sample image from Wikipedia's GIF article, 2-bit codes
+        // 4 1 6 6 2 9 9 7 8 10 2 12 1 14 15 6 0 21 0 10 7 22 23 18 26 7 10
+        // 29 13 24 12 18 16 36 12 5.
+        let data = [0x8C, 0x2D, 0x99, 0x87, 0x2A, 0x1C, 0xDC, 0x33, 0xA0,
+                    0x02, 0x75, 0xEC, 0x95, 0xFA, 0xA8, 0xDE, 0x60, 0x8C,
+                    0x04, 0x91, 0x4C, 0x01];
+
+        let decoded = decode(&data, &LzwOptions::gif(2)).unwrap();
+        assert_eq!(decoded.len(), 100);
+        assert_eq!(&decoded[..12], &[1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 1, 1]);
+
+        round_trip(&decoded, &LzwOptions::gif(2));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = letters(100_000);
+
+        round_trip(&[], &LzwOptions::default());
+        round_trip(b"a", &LzwOptions::default());
+        round_trip(&data, &LzwOptions::default());
+        round_trip(&vec![0; 100_000], &LzwOptions::default());
+        round_trip(&data, &LzwOptions {
+            early_change: false,
+            ..LzwOptions::default()
+        });
+        round_trip(&data, &LzwOptions::gif(8));
+        round_trip(&data.iter().map(|b| b & 0x7).collect::<Vec<_>>(),
+                   &LzwOptions::gif(3));
+    }
+
+    #[test]
+    fn test_early_change_mismatch() {
+        // Without EarlyChange the codes switch to 10 bits one code later,
+        // so decoding with the wrong setting falls apart.
+        let data = letters(10_000);
+        let mut encoded = vec![];
+        lzw_encode(&data, &mut encoded, &LzwOptions::default()).unwrap();
+
+        let options = LzwOptions { early_change: false, ..LzwOptions::default() };
+        assert_ne!(decode(&encoded, &options).ok(), Some(data));
+    }
+
+    #[test]
+    fn test_max_output() {
+        let mut encoded = vec![];
+        lzw_encode(&vec![0; 10_000], &mut encoded,
+                   &LzwOptions::default()).unwrap();
+
+        let options = LzwOptions {
+            max_output: Some(1000),
+            ..LzwOptions::default()
+        };
+        match decode(&encoded, &options) {
+            Err(DeflateError::LimitExceeded(Limit::Output(1000))) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(decode(&[], &LzwOptions::gif(9)).is_err());
+
+        let mut out = vec![];
+        let error = lzw_encode(&[4], &mut out, &LzwOptions::gif(2)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
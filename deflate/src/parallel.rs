@@ -0,0 +1,354 @@
+// Multi-threaded gzip compression in the style of pigz. The input is split
+// into blocks that are compressed on their own threads, each using the 32K
+// of input before it as a dictionary so that little compression is lost.
+// Every block but the last ends on a byte boundary, so the pieces can simply
+// be put one after the other to make a single gzip member.
+
+use std::io;
+use std::io::{
+    ErrorKind,
+    Write,
+};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::bit_writer::BitWriter;
+use crate::checksum::{
+    Checksum,
+    Crc32,
+};
+use crate::compress::{
+    compress_from,
+    CompressionLevel,
+};
+use crate::deflate::MAX_LOOKBACK;
+use crate::gzip::{
+    header_bytes,
+    GzipHeader,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelOptions {
+    // Number of blocks compressed at the same time
+    pub threads: usize,
+    // Size of the input given to each thread, pigz uses 128K. Smaller
+    // blocks spread better over the threads but compress a little worse.
+    pub block_size: usize,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> ParallelOptions {
+        ParallelOptions {
+            threads: thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            block_size: 128 * 1024,
+        }
+    }
+}
+
+// What a thread hands back for its block
+struct Compressed {
+    data: Vec<u8>,
+    crc32: Crc32,
+    size: usize,
+}
+
+// Compresses everything written to it into a single gzip member. The data
+// is written to `out` in order as blocks are done, `finish` needs to be
+// called at the end to write the last block and the trailer.
+pub struct ParallelGzipWriter<W: Write> {
+    out: W,
+    level: CompressionLevel,
+    options: ParallelOptions,
+    header: Option<Vec<u8>>,
+    // Input that hasn't been handed to a thread yet
+    block: Vec<u8>,
+    // The last 32K of input before `block`
+    window: Vec<u8>,
+    // Blocks being compressed, oldest first
+    pending: VecDeque<JoinHandle<io::Result<Compressed>>>,
+    crc32: Crc32,
+    size: u64,
+    written: usize,
+}
+
+impl <W: Write> ParallelGzipWriter<W> {
+    pub fn new(out: W, level: CompressionLevel, header: &GzipHeader)
+            -> io::Result<ParallelGzipWriter<W>> {
+        ParallelGzipWriter::with_options(out, level, header,
+                                         ParallelOptions::default())
+    }
+
+    pub fn with_options(out: W, level: CompressionLevel, header: &GzipHeader,
+                        options: ParallelOptions)
+            -> io::Result<ParallelGzipWriter<W>> {
+        if options.threads == 0 || options.block_size == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                "Threads and block size need to be at least 1."));
+        }
+
+        Ok(ParallelGzipWriter {
+            out,
+            level,
+            options,
+            header: Some(header_bytes(header, level)?),
+            block: Vec::with_capacity(options.block_size),
+            window: vec![],
+            pending: VecDeque::new(),
+            crc32: Crc32::new(),
+            size: 0,
+            written: 0,
+        })
+    }
+
+    // Number of compressed bytes written to `out` so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    // Compresses what's left, waits for all the threads and writes the
+    // trailer. Returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_impl()?;
+        Ok(self.out)
+    }
+
+    fn finish_impl(&mut self) -> io::Result<()> {
+        // The last block is the only final one, even when it's empty
+        self.start_block(true)?;
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+
+        // RFC1952 ~ 2.3.1 - ISIZE is the input size modulo 2^32
+        let mut trailer = self.crc32.value().to_le_bytes().to_vec();
+        trailer.extend_from_slice(&(self.size as u32).to_le_bytes());
+        self.out.write_all(&trailer)?;
+        self.written += trailer.len();
+
+        self.out.flush()
+    }
+
+    // Hands `block` over to a new thread
+    fn start_block(&mut self, is_final: bool) -> io::Result<()> {
+        if self.pending.len() >= self.options.threads {
+            self.write_oldest()?;
+        }
+
+        let level = self.level;
+        let dictionary_size = self.window.len();
+        let mut data = self.window.clone();
+        data.extend_from_slice(&self.block);
+
+        // The window for the next block
+        let start = data.len().saturating_sub(MAX_LOOKBACK);
+        self.window = data[start..].to_vec();
+        self.block.clear();
+
+        self.pending.push_back(thread::spawn(move || {
+            let mut compressed = vec![];
+            {
+                let mut writer = BitWriter::new(&mut compressed);
+                compress_from(&mut writer, &data, dictionary_size, level,
+                              is_final)?;
+                writer.flush()?;
+            }
+
+            let mut crc32 = Crc32::new();
+            crc32.update(&data[dictionary_size..]);
+
+            Ok(Compressed {
+                data: compressed,
+                crc32,
+                size: data.len() - dictionary_size,
+            })
+        }));
+
+        Ok(())
+    }
+
+    // Waits for the oldest block and writes it out
+    fn write_oldest(&mut self) -> io::Result<()> {
+        let handle = match self.pending.pop_front() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+
+        let compressed = handle.join()
+            .map_err(|_| io::Error::other("Compression thread panicked."))??;
+
+        if let Some(header) = self.header.take() {
+            self.out.write_all(&header)?;
+            self.written += header.len();
+        }
+
+        self.out.write_all(&compressed.data)?;
+        self.written += compressed.data.len();
+
+        self.crc32.combine(&compressed.crc32, compressed.size as u64);
+        self.size += compressed.size as u64;
+
+        Ok(())
+    }
+}
+
+impl <W: Write> Write for ParallelGzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // A full block is only sent off once we know it's not the last one
+        if self.block.len() == self.options.block_size {
+            self.start_block(false)?;
+        }
+
+        let length = cmp::min(buf.len(),
+                              self.options.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..length]);
+
+        Ok(length)
+    }
+
+    // Writes out the blocks that are done without waiting for the others.
+    // Unlike zlib this doesn't compress what's buffered, that would only
+    // make the blocks smaller.
+    fn flush(&mut self) -> io::Result<()> {
+        while self.pending.front().is_some_and(JoinHandle::is_finished) {
+            self.write_oldest()?;
+        }
+
+        self.out.flush()
+    }
+}
+
+// 2.3 - Compresses `data` into a single gzip member using several threads
+// and writes it to `out`. Returns the number of compressed bytes written.
+pub fn rfc1952_compress_parallel(data: &[u8], out: &mut dyn Write,
+                                 level: CompressionLevel, header: &GzipHeader,
+                                 options: ParallelOptions)
+        -> io::Result<usize> {
+    let mut writer = ParallelGzipWriter::with_options(out, level, header,
+                                                      options)?;
+    writer.write_all(data)?;
+    writer.finish_impl()?;
+    Ok(writer.written())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::bit_reader::BitReader;
+    use crate::deflate::InflateOptions;
+    use crate::gzip::{
+        rfc1952_compress,
+        rfc1952_header,
+        rfc1952_with_options,
+    };
+    use crate::test_data::sample;
+
+    fn options(threads: usize, block_size: usize) -> ParallelOptions {
+        ParallelOptions { threads, block_size }
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let inflated = rfc1952_with_options(&mut BitReader::from_slice(data),
+                                            &mut out,
+                                            &InflateOptions::default())
+            .unwrap();
+        assert_eq!(inflated.consumed, data.len() as u64);
+        out
+    }
+
+    fn compress(data: &[u8], level: CompressionLevel,
+                options: ParallelOptions) -> Vec<u8> {
+        let mut out = vec![];
+        let written = rfc1952_compress_parallel(
+            data, &mut out, level, &GzipHeader::default(), options).unwrap();
+        assert_eq!(written, out.len());
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = sample(500_000);
+
+        for &level in &[CompressionLevel::Stored, CompressionLevel::Fast,
+                        CompressionLevel::Default] {
+            let compressed = compress(&data, level, options(4, 64 * 1024));
+            assert_eq!(gunzip(&compressed), data);
+        }
+
+        // Blocks smaller than the window, and a single thread
+        let compressed = compress(&data, CompressionLevel::Fast,
+                                  options(1, 10_000));
+        assert_eq!(gunzip(&compressed), data);
+    }
+
+    #[test]
+    fn test_block_boundaries() {
+        // Empty input, and input that fills the last block exactly
+        assert_eq!(gunzip(&compress(b"", CompressionLevel::Default,
+                                    options(2, 1000))), b"");
+
+        let data = sample(4000);
+        assert_eq!(gunzip(&compress(&data, CompressionLevel::Default,
+                                    options(2, 1000))), data);
+    }
+
+    #[test]
+    fn test_dictionary() {
+        // With the previous block as a dictionary, repeated data compresses
+        // about as well as with a single thread.
+        let data = sample(50_000).repeat(4);
+        let parallel = compress(&data, CompressionLevel::Default,
+                                options(4, 50_000));
+
+        let mut single = vec![];
+        rfc1952_compress(&data, &mut single, CompressionLevel::Default,
+                         &GzipHeader::default()).unwrap();
+
+        assert!(parallel.len() < single.len() + single.len() / 10);
+    }
+
+    #[test]
+    fn test_writer() {
+        let data = sample(300_000);
+        let header = GzipHeader {
+            name: Some("data.txt".to_string()),
+            ..GzipHeader::default()
+        };
+
+        let mut writer = ParallelGzipWriter::with_options(
+            vec![], CompressionLevel::Fast, &header, options(3, 32 * 1024))
+            .unwrap();
+        for chunk in data.chunks(7777) {
+            writer.write_all(chunk).unwrap();
+            writer.flush().unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+
+        let mut reader = BitReader::from_slice(&compressed);
+        let mut out = vec![];
+        rfc1952_with_options(&mut reader, &mut out,
+                             &InflateOptions::default()).unwrap();
+        assert_eq!(out, data);
+        let actual = rfc1952_header(&mut BitReader::from_slice(&compressed))
+            .unwrap();
+        assert_eq!(actual.name, header.name);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let result = ParallelGzipWriter::with_options(
+            vec![], CompressionLevel::Fast, &GzipHeader::default(),
+            options(0, 1000));
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+}
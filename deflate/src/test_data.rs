@@ -0,0 +1,41 @@
+// Deterministic inputs for the tests, shared so that every module
+// exercises the codecs with the same kinds of data.
+
+// Bytes from `alphabet` picked by a linear congruential generator
+fn lcg(len: usize, alphabet: &[u8]) -> Vec<u8> {
+    let mut state = 12345u32;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        alphabet[(state >> 16) as usize % alphabet.len()]
+    }).collect()
+}
+
+// Short words and lines, compresses to about a third
+pub fn sample(len: usize) -> Vec<u8> {
+    lcg(len, b"abcdefgh \n")
+}
+
+// Not too repetitive so that an LZW table fills up slowly and codes go
+// through every width.
+pub fn letters(len: usize) -> Vec<u8> {
+    lcg(len, b"abcdefghijklmnop")
+}
+
+// Numbered lines of text, these repeat every 777 lines
+pub fn lines(count: u32) -> Vec<u8> {
+    (0..count)
+        .flat_map(|i| format!("line {} of the sample\n", i % 777)
+            .into_bytes())
+        .collect()
+}
+
+// Simple xorshift generator, the output barely compresses
+pub fn pseudo_random(length: usize) -> Vec<u8> {
+    let mut state = 0x2545F4914F6CDD1Du64;
+    (0..length).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
@@ -0,0 +1,591 @@
+// This module reads ZIP archives as described by PKWARE's APPNOTE.TXT
+// A copy of it is available at
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+
+use std::io;
+use std::io::{
+    ErrorKind,
+    Write,
+};
+
+use std::convert::TryFrom;
+
+use crate::bit_reader::BitReader;
+use crate::checksum::{
+    ChecksumMismatch,
+    ChecksumWriter,
+    Crc32,
+};
+use crate::deflate::{
+    rfc1951_with_options,
+    InflateOptions,
+    Limits,
+};
+use crate::error::DeflateError;
+use crate::gzip::{
+    extra_fields,
+    ExtraField,
+};
+
+// 4.3.7, 4.3.12, 4.3.14, 4.3.15 and 4.3.16
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034B50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014B50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064B50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064B50;
+const END_SIGNATURE: u32 = 0x06054B50;
+
+const END_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+// 4.5.3 - Header ID of the Zip64 extended information extra field
+const ZIP64_EXTRA_ID: [u8; 2] = [0x01, 0x00];
+
+// 4.4.4 - General purpose bit flags
+const FLAG_ENCRYPTED: u16 = 0b0000_0000_0000_0001;
+const FLAG_UTF8: u16 = 0b0000_1000_0000_0000;
+
+// 4.4.5 - Only the methods we can extract get a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ZipMethod {
+    Stored,
+    Deflated,
+    Other(u16),
+}
+
+impl ZipMethod {
+    fn from(data: u16) -> ZipMethod {
+        match data {
+            0 => ZipMethod::Stored,
+            8 => ZipMethod::Deflated,
+            method => ZipMethod::Other(method),
+        }
+    }
+}
+
+// 4.4.6 - MS-DOS date and time, local time with a two second resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DosDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DosDateTime {
+    fn from(time: u16, date: u16) -> DosDateTime {
+        DosDateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+}
+
+// 4.3.12 - An entry of the central directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    // Path inside the archive, always with forward slashes. Directories end
+    // with a slash.
+    pub name: String,
+    pub comment: String,
+    pub method: ZipMethod,
+    pub flags: u16,
+    pub modified: DosDateTime,
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub size: u64,
+    // Offset of the local file header from the start of the archive
+    pub offset: u64,
+    // Host dependent, e.g. on Unix the file mode is in the upper 16 bits
+    pub external_attributes: u32,
+    pub extra: Vec<ExtraField>,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+}
+
+// A ZIP archive held in memory. The entries are listed from the central
+// directory when the archive is opened and extracted on request.
+#[derive(Debug, Clone)]
+pub struct ZipArchive<'a> {
+    data: &'a [u8],
+    entries: Vec<ZipEntry>,
+    comment: String,
+}
+
+fn truncated() -> DeflateError {
+    io::Error::new(ErrorKind::UnexpectedEof, "Truncated zip archive.").into()
+}
+
+// Reads the little endian fields of a record
+struct Record<'a> {
+    data: &'a [u8],
+}
+
+impl <'a> Record<'a> {
+    // Starts reading at `offset`, checking that the record starts with
+    // `signature`.
+    fn at(data: &'a [u8], offset: u64, signature: u32)
+            -> Result<Record<'a>, DeflateError> {
+        let data = usize::try_from(offset).ok()
+            .and_then(|offset| data.get(offset..))
+            .ok_or_else(truncated)?;
+
+        let mut record = Record { data };
+        let actual = record.read_u32()?;
+        if actual != signature {
+            return Err(DeflateError::InvalidZipSignature(actual));
+        }
+
+        Ok(record)
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], DeflateError> {
+        if self.data.len() < length {
+            return Err(truncated());
+        }
+
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DeflateError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DeflateError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DeflateError> {
+        let bytes = self.read_bytes(8)?;
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+// Appendix D - The upper half of IBM Code Page 437, the lower half is ASCII
+const CP437: &str = "\
+    ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+// Names are UTF-8 when bit 11 is set and IBM Code Page 437 otherwise, but
+// plenty of tools write UTF-8 without setting the bit. Only names that
+// aren't valid UTF-8 are read as Code Page 437.
+fn string(bytes: &[u8]) -> String {
+    String::from_utf8(bytes.to_vec())
+        .unwrap_or_else(|_| bytes.iter()
+            .map(|&b| match b {
+                0x00..=0x7F => b as char,
+                _ => CP437.chars().nth(b as usize - 0x80).unwrap(),
+            })
+            .collect())
+}
+
+impl <'a> ZipArchive<'a> {
+    pub fn new(data: &'a [u8]) -> Result<ZipArchive<'a>, DeflateError> {
+        let end = find_end(data)?;
+
+        let mut record = Record::at(data, end as u64, END_SIGNATURE)?;
+        let _disk = record.read_u16()?;
+        let _directory_disk = record.read_u16()?;
+        let _disk_entries = record.read_u16()?;
+        let mut count = record.read_u16()? as u64;
+        let mut directory_size = record.read_u32()? as u64;
+        let mut directory_offset = record.read_u32()? as u64;
+        let comment_length = record.read_u16()? as usize;
+        let comment = string(record.read_bytes(comment_length)?);
+
+        // 4.4.1.4 - Fields that don't fit are set to all ones and the actual
+        // value is in the Zip64 end of central directory record.
+        if count == 0xFFFF || directory_size == 0xFFFFFFFF
+                || directory_offset == 0xFFFFFFFF {
+            if let Some(zip64_end) = find_zip64_end(data, end)? {
+                let mut record = Record::at(data, zip64_end,
+                                            ZIP64_END_SIGNATURE)?;
+                let _record_size = record.read_u64()?;
+                let _version_made_by = record.read_u16()?;
+                let _version_needed = record.read_u16()?;
+                let _disk = record.read_u32()?;
+                let _directory_disk = record.read_u32()?;
+                let _disk_entries = record.read_u64()?;
+                count = record.read_u64()?;
+                directory_size = record.read_u64()?;
+                directory_offset = record.read_u64()?;
+            }
+        }
+
+        let mut entries = vec![];
+        let mut offset = directory_offset;
+        for _ in 0..count {
+            let (entry, size) = central_header(data, offset)?;
+            entries.push(entry);
+            offset += size;
+        }
+
+        if offset - directory_offset > directory_size {
+            return Err(truncated());
+        }
+
+        Ok(ZipArchive { data, entries, comment })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    // Extracts `entry` to `out` checking its CRC-32, returns the number of
+    // bytes written.
+    pub fn extract(&self, entry: &ZipEntry, out: &mut dyn Write)
+            -> Result<u64, DeflateError> {
+        self.extract_with_options(entry, out, &InflateOptions::default())
+    }
+
+    // Like `extract`, but with the limits and checksum verification of
+    // `options`. The gzip and trailing data options don't apply here.
+    pub fn extract_with_options(&self, entry: &ZipEntry, out: &mut dyn Write,
+                                options: &InflateOptions)
+            -> Result<u64, DeflateError> {
+        if entry.is_encrypted() {
+            return Err(DeflateError::EncryptedZipEntry);
+        }
+
+        let data = self.entry_data(entry)?;
+        let mut out = ChecksumWriter::new(out, Crc32::new());
+
+        let size = match entry.method {
+            ZipMethod::Stored => {
+                let mut limits = Limits::new(options, 0);
+                limits.add(data.len() as u64, data.len())?;
+                out.write_all(data)?;
+                data.len() as u64
+            },
+            ZipMethod::Deflated => {
+                let mut reader = BitReader::from_slice(data);
+                let inflated = rfc1951_with_options(&mut reader, &mut out,
+                                                    options)?;
+                if let Some(e) = inflated.error {
+                    return Err(e);
+                }
+                inflated.size as u64
+            },
+            ZipMethod::Other(method) =>
+                return Err(DeflateError::UnsupportedZipMethod(method)),
+        };
+
+        if options.verify_checksums {
+            ChecksumMismatch::Crc32 {
+                expected: entry.crc32,
+                actual: out.value(),
+            }.check()?;
+        }
+
+        Ok(size)
+    }
+
+    // 4.3.7 - The compressed data follows the local file header. Sizes in
+    // the local header are zero when there is a data descriptor, so we use
+    // the ones from the central directory.
+    fn entry_data(&self, entry: &ZipEntry) -> Result<&'a [u8], DeflateError> {
+        let mut record = Record::at(self.data, entry.offset,
+                                    LOCAL_HEADER_SIGNATURE)?;
+        let _fixed = record.read_bytes(22)?;
+        let name_length = record.read_u16()? as usize;
+        let extra_length = record.read_u16()? as usize;
+        record.read_bytes(name_length + extra_length)?;
+
+        let compressed_size = usize::try_from(entry.compressed_size)
+            .map_err(|_| truncated())?;
+        record.read_bytes(compressed_size)
+    }
+}
+
+// 4.3.16 - The end of central directory record is at the end of the archive,
+// followed only by a comment of up to 64K.
+fn find_end(data: &[u8]) -> Result<usize, DeflateError> {
+    if data.len() < END_SIZE {
+        return Err(DeflateError::MissingEndOfCentralDirectory);
+    }
+
+    let last = data.len() - END_SIZE;
+    let first = last.saturating_sub(MAX_COMMENT_SIZE);
+
+    (first..=last).rev()
+        .find(|&i| {
+            data[i..i + 4] == END_SIGNATURE.to_le_bytes()
+                // The comment must reach the end of the archive
+                && i + END_SIZE + u16::from_le_bytes(
+                    [data[i + 20], data[i + 21]]) as usize <= data.len()
+        })
+        .ok_or(DeflateError::MissingEndOfCentralDirectory)
+}
+
+// 4.3.15 - The Zip64 end of central directory locator sits right before the
+// end of central directory record, when there is one.
+fn find_zip64_end(data: &[u8], end: usize)
+        -> Result<Option<u64>, DeflateError> {
+    if end < ZIP64_LOCATOR_SIZE {
+        return Ok(None);
+    }
+
+    let mut record = match Record::at(data, (end - ZIP64_LOCATOR_SIZE) as u64,
+                                      ZIP64_LOCATOR_SIGNATURE) {
+        Ok(record) => record,
+        Err(DeflateError::InvalidZipSignature(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let _disk = record.read_u32()?;
+    Ok(Some(record.read_u64()?))
+}
+
+// 4.3.12 - Reads the central directory header at `offset`, returns the entry
+// and the size of the header.
+fn central_header(data: &[u8], offset: u64)
+        -> Result<(ZipEntry, u64), DeflateError> {
+    let mut record = Record::at(data, offset, CENTRAL_HEADER_SIGNATURE)?;
+    let _version_made_by = record.read_u16()?;
+    let _version_needed = record.read_u16()?;
+    let flags = record.read_u16()?;
+    let method = ZipMethod::from(record.read_u16()?);
+    let time = record.read_u16()?;
+    let date = record.read_u16()?;
+    let crc32 = record.read_u32()?;
+    let mut compressed_size = record.read_u32()? as u64;
+    let mut size = record.read_u32()? as u64;
+    let name_length = record.read_u16()? as usize;
+    let extra_length = record.read_u16()? as usize;
+    let comment_length = record.read_u16()? as usize;
+    let _disk = record.read_u16()?;
+    let _internal_attributes = record.read_u16()?;
+    let external_attributes = record.read_u32()?;
+    let mut local_offset = record.read_u32()? as u64;
+
+    let name_bytes = record.read_bytes(name_length)?;
+    let extra = extra_fields(record.read_bytes(extra_length)?)?;
+    let comment_bytes = record.read_bytes(comment_length)?;
+
+    let (name, comment) = if flags & FLAG_UTF8 != 0 {
+        (String::from_utf8_lossy(name_bytes).into_owned(),
+         String::from_utf8_lossy(comment_bytes).into_owned())
+    } else {
+        (string(name_bytes), string(comment_bytes))
+    };
+
+    // 4.5.3 - Only the fields that are all ones in the header are in the
+    // Zip64 extra field, in this order.
+    if let Some(zip64) = extra.iter().find(|field| field.id == ZIP64_EXTRA_ID) {
+        let mut fields = Record { data: &zip64.data };
+        if size == 0xFFFFFFFF {
+            size = fields.read_u64()?;
+        }
+        if compressed_size == 0xFFFFFFFF {
+            compressed_size = fields.read_u64()?;
+        }
+        if local_offset == 0xFFFFFFFF {
+            local_offset = fields.read_u64()?;
+        }
+    }
+
+    let entry = ZipEntry {
+        name,
+        comment,
+        method,
+        flags,
+        modified: DosDateTime::from(time, date),
+        crc32,
+        compressed_size,
+        size,
+        offset: local_offset,
+        external_attributes,
+        extra,
+    };
+
+    let header_size = 46 + name_length + extra_length + comment_length;
+    Ok((entry, header_size as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::deflate::rfc1951;
+
+    const ARCHIVE: &[u8] = include_bytes!("../tests/data.zip");
+    const ZIP64_ARCHIVE: &[u8] = include_bytes!("../tests/data64.zip");
+    const EXPECTED: &[u8] = include_bytes!("../tests/expected.txt");
+
+    fn extract(archive: &ZipArchive, name: &str)
+            -> Result<Vec<u8>, DeflateError> {
+        let mut out = vec![];
+        archive.extract(archive.by_name(name).unwrap(), &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_entries() {
+        let archive = ZipArchive::new(ARCHIVE).unwrap();
+        assert_eq!(archive.comment(), "oxi-pdf test archive");
+
+        let names: Vec<_> = archive.entries().iter()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["docs/", "docs/expected.txt", "stored.txt"]);
+
+        assert!(archive.entries()[0].is_dir());
+
+        let entry = archive.by_name("docs/expected.txt").unwrap();
+        assert_eq!(entry.method, ZipMethod::Deflated);
+        assert_eq!(entry.size, EXPECTED.len() as u64 * 20);
+        assert!(!entry.is_dir());
+        assert_eq!(entry.modified, DosDateTime {
+            year: 2019,
+            month: 3,
+            day: 24,
+            hour: 12,
+            minute: 30,
+            second: 10,
+        });
+
+        let entry = archive.by_name("stored.txt").unwrap();
+        assert_eq!(entry.method, ZipMethod::Stored);
+        assert_eq!(entry.compressed_size, entry.size);
+    }
+
+    #[test]
+    fn test_extract() {
+        let archive = ZipArchive::new(ARCHIVE).unwrap();
+
+        assert_eq!(extract(&archive, "docs/expected.txt").unwrap(),
+                   EXPECTED.repeat(20));
+        assert_eq!(extract(&archive, "stored.txt").unwrap(), EXPECTED);
+        assert_eq!(extract(&archive, "docs/").unwrap(), b"");
+    }
+
+    #[test]
+    fn test_zip64() {
+        let archive = ZipArchive::new(ZIP64_ARCHIVE).unwrap();
+        assert_eq!(archive.entries().len(), 1);
+
+        let entry = &archive.entries()[0];
+        assert_eq!(entry.name, "a.txt");
+        assert_eq!(entry.size, 56);
+        assert_eq!(entry.compressed_size, 19);
+        assert_eq!(entry.offset, 0);
+
+        assert_eq!(extract(&archive, "a.txt").unwrap(),
+                   b"Hello, ZIP64! ".repeat(4));
+    }
+
+    #[test]
+    fn test_crc32_mismatch() {
+        let archive = ZipArchive::new(ARCHIVE).unwrap();
+        let entry = archive.by_name("stored.txt").unwrap();
+        // Flip a bit of the stored data
+        let mut data = ARCHIVE.to_vec();
+        let start = entry.offset as usize + 30 + entry.name.len();
+        data[start] ^= 0x01;
+
+        let archive = ZipArchive::new(&data).unwrap();
+        match extract(&archive, "stored.txt") {
+            Err(DeflateError::Checksum(ChecksumMismatch::Crc32 { .. })) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let options = InflateOptions {
+            verify_checksums: false,
+            ..InflateOptions::default()
+        };
+        let mut out = vec![];
+        archive.extract_with_options(archive.by_name("stored.txt").unwrap(),
+                                     &mut out, &options).unwrap();
+        assert_eq!(out.len(), EXPECTED.len());
+    }
+
+    #[test]
+    fn test_limits() {
+        let archive = ZipArchive::new(ARCHIVE).unwrap();
+        let options = InflateOptions {
+            max_output: Some(10),
+            ..InflateOptions::default()
+        };
+
+        for name in &["docs/expected.txt", "stored.txt"] {
+            let mut out = vec![];
+            let result = archive.extract_with_options(
+                archive.by_name(name).unwrap(), &mut out, &options);
+            match result {
+                Err(DeflateError::LimitExceeded(_)) => {},
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_a_zip() {
+        match ZipArchive::new(b"Not a zip archive, just some text.") {
+            Err(DeflateError::MissingEndOfCentralDirectory) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Without the last byte the central directory is cut short
+        let data = &ZIP64_ARCHIVE[..ZIP64_ARCHIVE.len() - 1];
+        assert!(ZipArchive::new(data).is_err());
+    }
+
+    #[test]
+    fn test_local_header() {
+        let archive = ZipArchive::new(ARCHIVE).unwrap();
+        let entry = archive.by_name("stored.txt").unwrap();
+        let mut data = ARCHIVE.to_vec();
+        data[entry.offset as usize] = 0;
+
+        let archive = ZipArchive::new(&data).unwrap();
+        match extract(&archive, "stored.txt") {
+            Err(DeflateError::InvalidZipSignature(_)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // The deflated data is plain RFC1951
+        let entry = archive.by_name("docs/expected.txt").unwrap();
+        let data = archive.entry_data(entry).unwrap();
+        let mut out = vec![];
+        rfc1951(&mut BitReader::from_slice(data), &mut out).unwrap();
+        assert_eq!(out, EXPECTED.repeat(20));
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string(b"plain.txt"), "plain.txt");
+        assert_eq!(string("f\u{FC}r.txt".as_bytes()), "f\u{FC}r.txt");
+        // Not UTF-8, so Code Page 437
+        assert_eq!(string(b"f\x81r \xE1\xFF.txt"), "f\u{FC}r \u{DF}\u{A0}.txt");
+        assert_eq!(CP437.chars().count(), 128);
+    }
+}
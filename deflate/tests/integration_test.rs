@@ -1,14 +1,32 @@
 extern crate deflate;
 
+use std::env;
+use std::fs;
 use std::fs::File;
 use std::io::{
     Read,
     Cursor,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::{
+    Command,
+    Output,
+    Stdio,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    UNIX_EPOCH,
 };
 
 use deflate::{
     BitReader,
     rfc1952,
+    ZipArchive,
 };
 
 #[test]
@@ -25,3 +43,256 @@ fn test_rfc1952() {
 
     assert_eq!(decompressed.into_inner(), expected);
 }
+
+#[test]
+fn test_zip() {
+    let mut data = vec![];
+    File::open("tests/data.zip").unwrap().read_to_end(&mut data).unwrap();
+    let archive = ZipArchive::new(&data).unwrap();
+
+    let mut expected = vec![];
+    File::open("tests/expected.txt").unwrap()
+        .read_to_end(&mut expected).unwrap();
+
+    let mut extracted = vec![];
+    for entry in archive.entries().iter().filter(|entry| !entry.is_dir()) {
+        archive.extract(entry, &mut extracted).unwrap();
+    }
+
+    assert_eq!(extracted, expected.repeat(21));
+}
+
+// A fresh directory for each test of deflate-cmd
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("deflate-cmd-{}-{}",
+                                           std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("data.txt"), expected()).unwrap();
+    dir
+}
+
+fn deflate_cmd(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_deflate-cmd"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Runs deflate-cmd with `input` on stdin
+fn deflate_cmd_stdin(dir: &Path, args: &[&str], input: Vec<u8>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_deflate-cmd"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // From another thread so that a full stdout pipe can't block us
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+
+    output
+}
+
+// Big enough to compress well
+fn expected() -> Vec<u8> {
+    fs::read("tests/expected.txt").unwrap().repeat(1000)
+}
+
+#[test]
+fn test_cmd_round_trip() {
+    let dir = scratch("round-trip");
+
+    assert!(deflate_cmd(&dir, &["data.txt"]).status.success());
+    assert!(!dir.join("data.txt").exists());
+    let compressed = fs::read(dir.join("data.txt.gz")).unwrap();
+    assert!(compressed.len() < expected().len());
+
+    assert!(deflate_cmd(&dir, &["-d", "data.txt.gz"]).status.success());
+    assert!(!dir.join("data.txt.gz").exists());
+    assert_eq!(fs::read(dir.join("data.txt")).unwrap(), expected());
+
+    // To stdout, the input stays
+    assert!(deflate_cmd(&dir, &["-k", "data.txt"]).status.success());
+    let output = deflate_cmd(&dir, &["-dc", "data.txt.gz"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected());
+    assert!(dir.join("data.txt.gz").exists());
+
+    // The output is there already
+    let output = deflate_cmd(&dir, &["-d", "data.txt.gz"]);
+    assert!(!output.status.success());
+    assert!(dir.join("data.txt.gz").exists());
+}
+
+#[test]
+fn test_cmd_output() {
+    let dir = scratch("output");
+
+    let output = deflate_cmd(&dir, &["-o", "new.gz", "data.txt"]);
+    assert!(output.status.success());
+    // Like with -c the input stays
+    assert!(dir.join("data.txt").exists());
+
+    assert!(deflate_cmd(&dir, &["-d", "-o", "new.txt", "new.gz"])
+            .status.success());
+    assert_eq!(fs::read(dir.join("new.txt")).unwrap(), expected());
+}
+
+#[test]
+fn test_cmd_keep() {
+    let dir = scratch("keep");
+
+    assert!(deflate_cmd(&dir, &["-k", "data.txt"]).status.success());
+    assert!(dir.join("data.txt").exists());
+    assert!(dir.join("data.txt.gz").exists());
+
+    fs::remove_file(dir.join("data.txt")).unwrap();
+    assert!(deflate_cmd(&dir, &["-dk", "data.txt.gz"]).status.success());
+    assert!(dir.join("data.txt.gz").exists());
+    assert_eq!(fs::read(dir.join("data.txt")).unwrap(), expected());
+}
+
+#[test]
+fn test_cmd_test() {
+    let dir = scratch("test");
+    assert!(deflate_cmd(&dir, &["data.txt"]).status.success());
+    assert!(deflate_cmd(&dir, &["-t", "data.txt.gz"]).status.success());
+
+    // RFC1952 ~ 2.3.1 - The CRC-32 is the 8 bytes before the end
+    let path = dir.join("data.txt.gz");
+    let mut compressed = fs::read(&path).unwrap();
+    let crc32 = compressed.len() - 8;
+    compressed[crc32] ^= 0xFF;
+    fs::write(&path, compressed).unwrap();
+
+    let output = deflate_cmd(&dir, &["-t", "data.txt.gz"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("data.txt.gz"));
+}
+
+#[test]
+fn test_cmd_name() {
+    let dir = scratch("name");
+    let modified = UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+    File::options().write(true).open(dir.join("data.txt")).unwrap()
+        .set_modified(modified).unwrap();
+
+    assert!(deflate_cmd(&dir, &["data.txt"]).status.success());
+    fs::rename(dir.join("data.txt.gz"), dir.join("renamed.gz")).unwrap();
+
+    // Without -N only the suffix is removed
+    assert!(deflate_cmd(&dir, &["-dk", "renamed.gz"]).status.success());
+    assert_eq!(fs::read(dir.join("renamed")).unwrap(), expected());
+
+    assert!(deflate_cmd(&dir, &["-dN", "renamed.gz"]).status.success());
+    let path = dir.join("data.txt");
+    assert_eq!(fs::read(&path).unwrap(), expected());
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+}
+
+#[test]
+fn test_cmd_formats() {
+    let dir = scratch("formats");
+
+    for &(format, suffix) in &[("zlib", ".zz"), ("raw", ".deflate")] {
+        let compressed = format!("data.txt{}", suffix);
+        assert!(deflate_cmd(&dir, &["-k", "-F", format, "data.txt"])
+                .status.success());
+        assert!(dir.join(&compressed).exists());
+
+        let output = deflate_cmd(&dir, &["-dc", "-F", format, &compressed]);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, expected());
+
+        // Not gzip data
+        let output = deflate_cmd(&dir, &["-dc", &compressed]);
+        assert!(!output.status.success());
+    }
+
+    // 2.2 - CMF of a zlib stream with a 32K window
+    assert_eq!(fs::read(dir.join("data.txt.zz")).unwrap()[0], 0x78);
+}
+
+#[test]
+fn test_cmd_stdin() {
+    let dir = scratch("stdin");
+    // Several blocks of the compressor
+    let data = expected().repeat(10);
+
+    for &format in &["gzip", "zlib", "raw"] {
+        for &threads in &["1", "4"] {
+            let output = deflate_cmd_stdin(&dir, &["-F", format, "-p", threads],
+                                           data.clone());
+            assert!(output.status.success());
+            assert!(output.stdout.len() < data.len());
+
+            let output = deflate_cmd_stdin(&dir, &["-d", "-F", format, "-"],
+                                           output.stdout);
+            assert!(output.status.success());
+            assert_eq!(output.stdout, data);
+        }
+    }
+
+    // RFC1952 ~ 2.3.1 - No name or time stamp to save from stdin
+    let output = deflate_cmd_stdin(&dir, &[], expected());
+    assert_eq!(output.stdout[3], 0);
+    assert_eq!(&output.stdout[4..8], &[0; 4]);
+
+    // Nothing is left behind in the directory
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["data.txt"]);
+
+    let output = deflate_cmd_stdin(&dir, &["-d"], b"Not gzip data".to_vec());
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_cmd_list() {
+    let dir = scratch("list");
+    assert!(deflate_cmd(&dir, &["-k", "data.txt"]).status.success());
+    assert!(deflate_cmd(&dir, &["-k", "-F", "zlib", "data.txt"])
+            .status.success());
+
+    for &(args, compressed) in &[(&["-l", "data.txt.gz"][..], "data.txt.gz"),
+                                 (&["-l", "-F", "zlib", "data.txt.zz"][..],
+                                  "data.txt.zz")] {
+        let output = deflate_cmd(&dir, args);
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("uncompressed"));
+
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        let size = fs::metadata(dir.join(compressed)).unwrap().len();
+        assert_eq!(fields[0], size.to_string());
+        assert_eq!(fields[1], expected().len().to_string());
+        assert_eq!(fields.last(), Some(&"data.txt"));
+    }
+
+    // The sizes of all the members
+    let member = fs::read(dir.join("data.txt.gz")).unwrap();
+    fs::write(dir.join("twice.gz"), member.repeat(2)).unwrap();
+    let output = deflate_cmd(&dir, &["-l", "twice.gz"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let fields: Vec<&str> = stdout.lines().nth(1).unwrap()
+        .split_whitespace().collect();
+    assert_eq!(fields[0], (2 * member.len()).to_string());
+    assert_eq!(fields[1], (2 * expected().len()).to_string());
+
+    let output = deflate_cmd(&dir, &["-l", "data.txt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("data.txt"));
+}
@@ -0,0 +1,507 @@
+// The public face of the library: a parsed file and the page tree in it.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::slice;
+
+use crate::error::PdfError;
+use crate::parser::{
+    parse_page,
+    parse_pdf,
+    Key,
+    Operator,
+    Pdf,
+    PdfDictionary,
+    PdfObject,
+    Revision,
+    Version,
+};
+
+// 7.7.3.4 - Attributes a page takes from its ancestors when it doesn't have
+// them itself
+const INHERITABLE: [&str; 4] = ["Resources", "MediaBox", "CropBox", "Rotate"];
+
+// Real page trees are a handful of levels deep, this stops reference loops
+const MAX_PAGE_TREE_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub struct Document {
+    pdf: Pdf,
+}
+
+impl Document {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Document, PdfError> {
+        Document::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Document, PdfError> {
+        Document::new(parse_pdf(data)?)
+    }
+
+    fn new(pdf: Pdf) -> Result<Document, PdfError> {
+        let document = Document { pdf };
+
+        if document.trailer().dictionary("Root", &document.pdf).is_none() {
+            return Err(PdfError::MissingCatalog);
+        }
+
+        Ok(document)
+    }
+
+    // The objects, for the helpers on `PdfObject` and `PdfDictionary` that
+    // follow references
+    pub fn pdf(&self) -> &Pdf {
+        &self.pdf
+    }
+
+    pub fn version(&self) -> &Version {
+        self.pdf.version()
+    }
+
+    // 7.5.6 - The original file and its incremental updates, oldest first
+    pub fn revisions(&self) -> &[Revision] {
+        self.pdf.revisions()
+    }
+
+    // The document as it was when revision `index` was written, 0 being the
+    // original file
+    pub fn at_revision(&self, index: usize) -> Result<Document, PdfError> {
+        let pdf = self.pdf.at_revision(index)
+            .ok_or(PdfError::NoSuchRevision(index))?;
+        Document::new(pdf)
+    }
+
+    // 7.5.5
+    pub fn trailer(&self) -> &PdfDictionary {
+        self.pdf.trailer()
+    }
+
+    // 7.7.2 - Checked to be there when the document is opened
+    pub fn catalog(&self) -> &PdfDictionary {
+        self.trailer().dictionary("Root", &self.pdf)
+            .expect("The catalog is checked in from_bytes")
+    }
+
+    // Returns `PdfObject::Null` for objects that are not in the file, like
+    // 7.3.10 says.
+    pub fn object(&self, key: &Key) -> &PdfObject {
+        self.pdf.resolve(key)
+    }
+
+    // Follows `object` if it's a reference
+    fn resolve<'a>(&'a self, object: &'a PdfObject) -> &'a PdfObject {
+        match object {
+            PdfObject::Reference(key) => self.pdf.resolve(key),
+            _ => object,
+        }
+    }
+
+    // 7.7.3.2 - The root of the page tree
+    fn page_tree(&self) -> Option<&PdfDictionary> {
+        self.catalog().dictionary("Pages", &self.pdf)
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.page_tree()
+            .and_then(|pages| pages.unsigned("Count"))
+            .unwrap_or(0) as usize
+    }
+
+    // Walks down the page tree using the /Count of each node, so only the
+    // nodes on the way to the page are looked at.
+    pub fn page(&self, mut index: usize) -> Option<Page<'_>> {
+        let mut node = self.page_tree()?;
+        let mut inherited = HashMap::new();
+
+        for _ in 0..MAX_PAGE_TREE_DEPTH {
+            inherit(node, &mut inherited);
+
+            let mut next = None;
+            for kid in node.array("Kids")? {
+                // A kid that is missing or not a dictionary has no pages
+                let kid = match kid.as_dictionary(&self.pdf) {
+                    Some(kid) => kid,
+                    None => continue,
+                };
+                let count = if is_page(kid) {
+                    1
+                } else {
+                    kid.unsigned("Count").unwrap_or(0) as usize
+                };
+
+                if index < count {
+                    next = Some(kid);
+                    break;
+                }
+                index -= count;
+            }
+
+            node = next?;
+            if is_page(node) {
+                inherit(node, &mut inherited);
+                return Some(Page {
+                    document: self,
+                    dictionary: node,
+                    inherited,
+                });
+            }
+        }
+
+        None
+    }
+
+    // All the pages in order, in a single walk over the tree
+    pub fn pages(&self) -> impl Iterator<Item = Page<'_>> {
+        let mut pages = Pages {
+            document: self,
+            stack: vec![],
+            visited: HashSet::new(),
+        };
+        if let Some(root) = self.page_tree() {
+            pages.enter(root, HashMap::new());
+        }
+        pages
+    }
+}
+
+// A depth first walk of the page tree. The stack has the kids left to walk
+// in each node on the way down, with the attributes they inherit.
+struct Pages<'a> {
+    document: &'a Document,
+    stack: Vec<(slice::Iter<'a, PdfObject>,
+                HashMap<&'static str, &'a PdfObject>)>,
+    // The intermediate nodes walked so far, so reference loops end
+    visited: HashSet<*const PdfDictionary>,
+}
+
+impl <'a> Pages<'a> {
+    fn enter(&mut self,
+             node: &'a PdfDictionary,
+             mut inherited: HashMap<&'static str, &'a PdfObject>) {
+        if self.stack.len() >= MAX_PAGE_TREE_DEPTH
+                || !self.visited.insert(node as *const PdfDictionary) {
+            return;
+        }
+
+        if let Some(kids) = node.array("Kids") {
+            inherit(node, &mut inherited);
+            self.stack.push((kids.iter(), inherited));
+        }
+    }
+}
+
+impl <'a> Iterator for Pages<'a> {
+    type Item = Page<'a>;
+
+    fn next(&mut self) -> Option<Page<'a>> {
+        loop {
+            let (kids, inherited) = self.stack.last_mut()?;
+            let kid = match kids.next() {
+                Some(kid) => kid,
+                None => {
+                    self.stack.pop();
+                    continue;
+                },
+            };
+
+            // Skipped like in `Document::page`
+            let kid = match kid.as_dictionary(&self.document.pdf) {
+                Some(kid) => kid,
+                None => continue,
+            };
+
+            let mut inherited = inherited.clone();
+            if is_page(kid) {
+                inherit(kid, &mut inherited);
+                return Some(Page {
+                    document: self.document,
+                    dictionary: kid,
+                    inherited,
+                });
+            }
+            self.enter(kid, inherited);
+        }
+    }
+}
+
+// 7.7.3.3 - /Type is required, but leaves are pages whatever they say
+fn is_page(node: &PdfDictionary) -> bool {
+    node.identifier("Type") == Some("Page") || node.get("Kids").is_none()
+}
+
+fn inherit<'a>(node: &'a PdfDictionary,
+               inherited: &mut HashMap<&'static str, &'a PdfObject>) {
+    for key in INHERITABLE.iter() {
+        if let Some(value) = node.get(key) {
+            inherited.insert(key, value);
+        }
+    }
+}
+
+// 7.7.3.3
+pub struct Page<'a> {
+    document: &'a Document,
+    dictionary: &'a PdfDictionary,
+    // The inheritable attributes from the closest node that has them
+    inherited: HashMap<&'static str, &'a PdfObject>,
+}
+
+impl <'a> Page<'a> {
+    pub fn dictionary(&self) -> &'a PdfDictionary {
+        self.dictionary
+    }
+
+    // Looks up `key` in the page, or in its ancestors for the inheritable
+    // attributes. References are resolved.
+    pub fn get(&self, key: &str) -> Option<&'a PdfObject> {
+        let value = self.dictionary.get(key)
+            .or_else(|| self.inherited.get(key).cloned())?;
+        Some(self.document.resolve(value))
+    }
+
+    // 7.9.5 - As [llx lly urx ury]
+    pub fn media_box(&self) -> Option<[f64; 4]> {
+        self.get("MediaBox").and_then(rectangle)
+    }
+
+    // 14.11.2 - Defaults to the media box
+    pub fn crop_box(&self) -> Option<[f64; 4]> {
+        self.get("CropBox").and_then(rectangle)
+            .or_else(|| self.media_box())
+    }
+
+    // Degrees clockwise, a multiple of 90
+    pub fn rotation(&self) -> i64 {
+        self.get("Rotate").and_then(PdfObject::as_integer).unwrap_or(0)
+    }
+
+    pub fn resources(&self) -> Option<&'a PdfDictionary> {
+        match self.get("Resources")? {
+            PdfObject::Dictionary(resources) => Some(resources),
+            _ => None,
+        }
+    }
+
+    // The decoded content streams. When there are several they are joined
+    // with a new line, they are meant to be read as one. Fails if one of
+    // them couldn't be decoded.
+    pub fn contents(&self) -> Result<Vec<u8>, PdfError> {
+        let streams = match self.get("Contents") {
+            Some(PdfObject::Array(streams)) => streams.iter()
+                .map(|stream| self.document.resolve(stream))
+                .collect(),
+            Some(stream) => vec![stream],
+            None => vec![],
+        };
+
+        let mut contents = vec![];
+        for stream in streams.into_iter().filter_map(PdfObject::as_stream) {
+            if let Some(filter) = stream.filters().first() {
+                return Err(PdfError::Parse(format!(
+                    "Content stream with filter {:?} that couldn't be applied.",
+                    filter)));
+            }
+
+            if !contents.is_empty() {
+                contents.push(b'\n');
+            }
+            contents.extend_from_slice(&stream.data);
+        }
+
+        Ok(contents)
+    }
+
+    // 7.8.2 - The operators in the content streams with their operands
+    pub fn operations(&self)
+            -> Result<Vec<(Vec<PdfObject>, Operator)>, PdfError> {
+        Ok(parse_page(&self.contents()?)?)
+    }
+}
+
+fn rectangle(object: &PdfObject) -> Option<[f64; 4]> {
+    let mut values = object.as_float_array()?;
+    Some([values.next()?, values.next()?, values.next()?, values.next()?])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A classic file with objects numbered from 1 in the given order
+    fn build(objects: &[&str], trailer: &str) -> Vec<u8> {
+        let mut data = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(data.len());
+            data.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object)
+                .as_bytes());
+        }
+
+        let xref = data.len();
+        data.extend(format!("xref\n0 {}\n0000000000 65535 f\r\n",
+                            objects.len() + 1).as_bytes());
+        for offset in offsets {
+            data.extend(format!("{:010} 00000 n\r\n", offset).as_bytes());
+        }
+        data.extend(format!("trailer\n{}\nstartxref\n{}\n%%EOF\n",
+                            trailer, xref).as_bytes());
+
+        data
+    }
+
+    fn page_tree() -> Vec<u8> {
+        build(&[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R 4 0 R] /Count 3 \
+                /MediaBox [0 0 612 792] /Resources << /ProcSet [/PDF] >> >>",
+            "<< /Type /Page /Parent 2 0 R >>",
+            "<< /Type /Pages /Parent 2 0 R /Kids [5 0 R 6 0 R] /Count 2 \
+                /Rotate 90 >>",
+            "<< /Type /Page /Parent 4 0 R /MediaBox 9 0 R >>",
+            "<< /Type /Page /Parent 4 0 R /Contents [7 0 R 8 0 R] >>",
+            "<< /Length 9 >>\nstream\n0 0 m 1 1\nendstream",
+            "<< /Length 3 >>\nstream\nl S\nendstream",
+            "[0 0 100 200]",
+        ], "<< /Size 10 /Root 1 0 R >>")
+    }
+
+    #[test]
+    fn test_document() {
+        let document = Document::from_bytes(page_tree()).unwrap();
+
+        assert_eq!(document.version(), &Version::V1_4);
+        assert_eq!(document.trailer().integer("Size"), Some(10));
+        assert_eq!(document.catalog().identifier("Type"), Some("Catalog"));
+        assert_eq!(document.object(&Key::new(9, 0)).as_float_array().unwrap()
+                   .collect::<Vec<_>>(), vec![0.0, 0.0, 100.0, 200.0]);
+        assert_eq!(document.object(&Key::new(42, 0)), &PdfObject::Null);
+    }
+
+    #[test]
+    fn test_pages() {
+        let document = Document::from_bytes(page_tree()).unwrap();
+        assert_eq!(document.page_count(), 3);
+        assert_eq!(document.pages().count(), 3);
+        assert!(document.page(3).is_none());
+
+        // Everything comes from the root of the tree
+        let first = document.page(0).unwrap();
+        assert_eq!(first.media_box(), Some([0.0, 0.0, 612.0, 792.0]));
+        assert_eq!(first.crop_box(), first.media_box());
+        assert_eq!(first.rotation(), 0);
+        assert!(first.resources().unwrap().get("ProcSet").is_some());
+        assert!(first.contents().unwrap().is_empty());
+
+        // A media box of its own, by reference, and the rotation of the
+        // intermediate node
+        let second = document.page(1).unwrap();
+        assert_eq!(second.media_box(), Some([0.0, 0.0, 100.0, 200.0]));
+        assert_eq!(second.rotation(), 90);
+
+        let third = document.page(2).unwrap();
+        assert_eq!(third.dictionary().get("Parent"),
+                   Some(&PdfObject::Reference(Key::new(4, 0))));
+        assert_eq!(third.contents().unwrap(), b"0 0 m 1 1\nl S");
+        let operations: Vec<Operator> = third.operations().unwrap()
+            .into_iter().map(|(_, operator)| operator).collect();
+        assert_eq!(operations, vec![Operator::Subpath, Operator::Line,
+                                    Operator::Stroke]);
+    }
+
+    #[test]
+    fn test_broken_kids() {
+        let document = Document::from_bytes(build(&[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R 42 0 R 4 0 R (kid) 5 0 R] /Count 3 >>",
+            "<< /Type /Page /Parent 2 0 R /Rotate 90 >>",
+            "<< /Type /Page /Parent 2 0 R /Rotate 180 >>",
+            "<< /Type /Page /Parent 2 0 R /Rotate 270 >>",
+        ], "<< /Size 6 /Root 1 0 R >>")).unwrap();
+
+        // The missing object and the string have no pages
+        let rotations: Vec<i64> = (0..3)
+            .map(|index| document.page(index).unwrap().rotation())
+            .collect();
+        assert_eq!(rotations, vec![90, 180, 270]);
+        assert_eq!(document.pages().map(|page| page.rotation())
+                   .collect::<Vec<_>>(), rotations);
+    }
+
+    #[test]
+    fn test_page_tree_loop() {
+        let document = Document::from_bytes(build(&[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R 4 0 R] /Count 2 >>",
+            "<< /Type /Pages /Parent 2 0 R /Kids [2 0 R 3 0 R 5 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R >>",
+            "<< /Type /Page /Parent 3 0 R >>",
+        ], "<< /Size 6 /Root 1 0 R >>")).unwrap();
+
+        // Each node is walked once
+        let pages: Vec<_> = document.pages()
+            .map(|page| page.dictionary().get("Parent").cloned())
+            .collect();
+        assert_eq!(pages, vec![Some(PdfObject::Reference(Key::new(3, 0))),
+                               Some(PdfObject::Reference(Key::new(2, 0)))]);
+    }
+
+    #[test]
+    fn test_unparseable_contents() {
+        let document = Document::from_bytes(build(&[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>",
+            "<< /Length 12 >>\nstream\n0 0 m S ) )\nendstream",
+        ], "<< /Size 5 /Root 1 0 R >>")).unwrap();
+
+        // The page is still there, only its operations fail
+        let page = document.page(0).unwrap();
+        assert!(matches!(page.operations(), Err(PdfError::Parse(_))));
+    }
+
+    #[test]
+    fn test_undecodable_contents() {
+        let document = Document::from_bytes(build(&[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>",
+            "<< /Length 7 /Filter /ASCIIHexDecode >>\nstream\n302053>\nendstream",
+        ], "<< /Size 5 /Root 1 0 R >>")).unwrap();
+
+        let page = document.page(0).unwrap();
+        assert!(matches!(page.contents(), Err(PdfError::Parse(_))));
+        assert!(matches!(page.operations(), Err(PdfError::Parse(_))));
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        // Documents can be shared between threads
+        assert_send_sync::<Document>();
+
+        let document = Document::from_bytes(page_tree()).unwrap();
+        let counts: Vec<usize> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| document.pages().count()))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(counts, vec![3; 4]);
+    }
+
+    #[test]
+    fn test_missing_catalog() {
+        let data = build(&["<< /Type /Catalog >>"], "<< /Size 2 >>");
+        match Document::from_bytes(data) {
+            Err(PdfError::MissingCatalog) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        match Document::from_bytes(b"Not a PDF file".to_vec()) {
+            Err(PdfError::Parse(_)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Everything that can go wrong while opening a document.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum PdfError {
+    // Reading the file failed
+    Io(io::Error),
+    // The file structure or one of the objects is malformed
+    Parse(String),
+    // 7.7.2 - The trailer has no /Root, or it doesn't lead to a dictionary
+    MissingCatalog,
+    // 7.5.6 - Asked for a revision past the last one in the file
+    NoSuchRevision(usize),
+}
+
+impl fmt::Display for PdfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PdfError::Io(e) => write!(f, "{}", e),
+            PdfError::Parse(message) => write!(f, "{}", message),
+            PdfError::MissingCatalog =>
+                write!(f, "The document catalog is missing."),
+            PdfError::NoSuchRevision(index) =>
+                write!(f, "The document has no revision {}.", index),
+        }
+    }
+}
+
+impl error::Error for PdfError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PdfError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PdfError {
+    fn from(error: io::Error) -> PdfError {
+        PdfError::Io(error)
+    }
+}
+
+impl From<String> for PdfError {
+    fn from(message: String) -> PdfError {
+        PdfError::Parse(message)
+    }
+}
@@ -0,0 +1,33 @@
+#[macro_use]
+mod parser;
+mod document;
+mod error;
+mod resolver;
+mod types;
+mod font;
+
+extern crate deflate;
+
+pub use crate::document::{
+    Document,
+    Page,
+};
+
+pub use crate::error::PdfError;
+
+pub use crate::parser::{
+    Filter,
+    Key,
+    Operator,
+    Pdf,
+    PdfDictionary,
+    PdfObject,
+    Revision,
+    Stream,
+    Version,
+};
+
+pub use crate::resolver::{
+    resolve_pdf,
+    Catalog,
+};
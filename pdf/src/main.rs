@@ -1,27 +1,78 @@
-#![feature(try_trait)]
+extern crate oxi_pdf;
 
-#[macro_use]
-mod parser;
-mod resolver;
-mod types;
-mod font;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-extern crate deflate;
-extern crate uuid;
+use oxi_pdf::{
+    resolve_pdf,
+    Document,
+    Filter,
+    PdfError,
+    PdfObject,
+};
 
-use std::fs::File;
-use std::io::Read;
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, images) = match &args[..] {
+        [path] => (path, None),
+        [path, images] => (path, Some(Path::new(images))),
+        _ => {
+            eprintln!("Usage: oxi-pdf FILE [IMAGE_DIR]");
+            process::exit(2);
+        },
+    };
 
-use crate::parser::parse_pdf;
-use crate::resolver::resolve_pdf;
+    if let Err(e) = run(path, images) {
+        eprintln!("oxi-pdf: {}: {}", path, e);
+        process::exit(1);
+    }
+}
 
-fn main() -> std::io::Result<()> {
-    let mut file = File::open("pdf.pdf")?;
-    let mut contents = vec![];
-    file.read_to_end(&mut contents)?;
+// Images are only written out when a directory is given for them
+fn run(path: &str, images: Option<&Path>) -> Result<(), PdfError> {
+    let document = Document::open(path)?;
+    println!("{:?}, {} pages, {} revisions", document.version(),
+             document.page_count(), document.revisions().len());
 
-    let pdf = parse_pdf(&contents[..]).unwrap();
-    resolve_pdf(&pdf).unwrap();
+    for (index, page) in document.pages().enumerate() {
+        println!("Page {}: media box {:?}, {} operations", index + 1,
+                 page.media_box(), page.operations()?.len());
+    }
 
+    if let Some(images) = images {
+        println!("{} images saved", save_images(&document, images)?);
+    }
+    println!("{:?}", resolve_pdf(&document)?);
     Ok(())
 }
+
+// The library keeps DCT images as they are, they go to `directory` named
+// after their object
+fn save_images(document: &Document, directory: &Path)
+        -> Result<usize, PdfError> {
+    let keys: HashSet<_> = document.revisions().iter()
+        .flat_map(|revision| revision.objects())
+        .collect();
+
+    let mut saved = 0;
+    for key in keys {
+        let stream = match document.object(&key) {
+            PdfObject::Stream(stream) => stream,
+            _ => continue,
+        };
+        if stream.filters().first() != Some(&Filter::DCTDecode) {
+            continue;
+        }
+
+        fs::create_dir_all(directory)?;
+        fs::write(directory.join(format!("{}-{}.jpg", key.object(),
+                                         key.generation())),
+                  &stream.data)?;
+        saved += 1;
+    }
+
+    Ok(saved)
+}
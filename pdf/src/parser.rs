@@ -1,25 +1,21 @@
 use std::{
     cmp,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     str,
     str::FromStr,
-    io::{
-        Cursor,
-        Write,
-    },
-    fs::File,
-    fs,
-    path::Path,
     mem,
-    convert::From,
-    ops::Try,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    thread::ThreadId,
 };
 
-use uuid::Uuid;
-
 use crate::deflate::{
     BitReader,
-    rfc1950,
+    InflateOptions,
+    LzwOptions,
+    lzw_decode,
+    rfc1950_with_options,
 };
 
 const ASCII_NUL: u8                  = 0x00;
@@ -66,17 +62,34 @@ const ASCII_F_LOWERCASE: u8          = 0x66;
 const ASCII_N_LOWERCASE: u8          = 0x6E;
 const ASCII_TILDE: u8                = 0x7E;
 
+// 7.3.8.2 - The stream dictionary entries needed to read and decode the
+// data. Other entries are left as references, they can point to more
+// streams (/SMask, /Metadata, /Extends...) that would all be loaded along
+// with this one.
+const RESOLVED_STREAM_KEYS: [&str; 3] = ["Length", "Filter", "DecodeParms"];
+
 fn resolve_dictionary<F>(dictionary: PdfDictionary, resolve: &mut F)
         -> PdfDictionary where F: FnMut(&Key) -> PdfObject {
     let mut result = HashMap::new();
 
     for (key, object) in dictionary.data {
-        match object {
-            PdfObject::Reference(r) => {
-                result.insert(key, resolve(&r).clone());
-            },
-            x => { result.insert(key, x); },
+        if !RESOLVED_STREAM_KEYS.contains(&key.as_str()) {
+            result.insert(key, object);
+            continue;
         }
+
+        // With several filters, any of the entries can be a reference
+        let object = match object {
+            PdfObject::Reference(r) => resolve(&r),
+            PdfObject::Array(array) => PdfObject::Array(array.into_iter()
+                .map(|object| match object {
+                    PdfObject::Reference(r) => resolve(&r),
+                    object => object,
+                })
+                .collect()),
+            object => object,
+        };
+        result.insert(key, object);
     }
 
     PdfDictionary::new(result)
@@ -195,10 +208,7 @@ struct Found<'a, T> {
     remaining: &'a [u8],
 }
 
-impl <'a, T> Try for Res<'a, T> {
-    type Ok = T;
-    type Error = String;
-
+impl <'a, T> Res<'a, T> {
     fn into_result(self) -> Result<T, String> {
         match self {
             Res::Found(x) => Ok(x.data),
@@ -209,22 +219,6 @@ impl <'a, T> Try for Res<'a, T> {
         }
     }
 
-    fn from_error(_: String) -> Self {
-        Res::Error
-    }
-
-    fn from_ok(v: T) -> Self {
-        Res::found(v, &[])
-    }
-}
-
-impl <'a, T> From<String> for Res<'a, T> {
-    fn from(_: String) -> Self {
-        Res::Error
-    }
-}
-
-impl <'a, T> Res<'a, T> {
     fn found(data: T, remaining: &[u8]) -> Res<T> {
         Res::Found(Found {
             data: data,
@@ -326,7 +320,7 @@ fn string_comment(mut data: &[u8]) -> Res<'_, String> {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-enum Version {
+pub enum Version {
     V1,
     V1_1,
     V1_2,
@@ -729,12 +723,20 @@ pub struct Key {
 }
 
 impl Key {
-    fn new(object: u64, generation: u64) -> Key {
+    pub fn new(object: u64, generation: u64) -> Key {
         Key {
             object,
             generation,
         }
     }
+
+    pub fn object(&self) -> u64 {
+        self.object
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -752,6 +754,16 @@ impl Definition {
     }
 }
 
+// Streams come from untrusted files, a few bytes of deflate data can
+// expand to gigabytes. Single color images legitimately get close to the
+// best ratio deflate can do, so only the decoded size is limited.
+const MAX_DECODED_STREAM_SIZE: u64 = 256 * 1024 * 1024;
+
+// Loading a stream can load the objects its /Length and /DecodeParms refer
+// to, which can be streams themselves. Chains longer than this are only
+// found in files made to overflow the stack.
+const MAX_NESTED_LOADS: usize = 32;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Stream {
     pub data: Vec<u8>,
@@ -769,49 +781,120 @@ impl Stream {
         }
     }
 
-    fn apply_flate_decode(&mut self) -> Result<(), String> {
-        let mut data = vec![];
-        mem::swap(&mut self.data, &mut data);
-
-        let mut decoded;
+    fn flate_decode(&self) -> Result<Vec<u8>, String> {
+        let mut decoded = vec![];
         {
-            let mut reader = BitReader::new(Box::new(Cursor::new(
-                data)));
-            decoded = Cursor::new(vec![]);
-            rfc1950(&mut reader, &mut decoded)
+            let mut reader = BitReader::from_slice(&self.data);
+            // Producers often leave padding after the deflate data, or get
+            // /Length wrong so that the stream includes part of the EOL
+            // before `endstream`. Truncated streams are common in broken
+            // files too, whatever could be decoded is better than nothing.
+            let options = InflateOptions {
+                max_output: Some(MAX_DECODED_STREAM_SIZE),
+                ignore_trailing_data: true,
+                recover: true,
+                ..InflateOptions::default()
+            };
+            rfc1950_with_options(&mut reader, &mut decoded, &options)
                 .map_err(|e| e.to_string())?;
         }
 
-        self.data = decoded.into_inner();
-        Ok(())
+        Ok(decoded)
+    }
+
+    // 7.4.4
+    fn lzw_decode(&self, parameters: Option<&PdfDictionary>)
+            -> Result<Vec<u8>, String> {
+        let early_change = parameters
+            .and_then(|p| p.integer("EarlyChange"))
+            .unwrap_or(1);
+
+        let options = LzwOptions {
+            early_change: early_change != 0,
+            max_output: Some(MAX_DECODED_STREAM_SIZE),
+            ..LzwOptions::default()
+        };
+
+        let mut decoded = vec![];
+        lzw_decode(&self.data, &mut decoded, &options)
+            .map_err(|e| e.to_string())?;
+
+        Ok(decoded)
     }
 
-    // For DCT decode (basically a Jpeg) we save it to a file and store
-    // the file name in the data
-    fn apply_dct_decode(&mut self) -> Result<(), String> {
-        if !Path::new("images").exists() {
-            fs::create_dir("images").map_err(|e| e.to_string())?;
+    // 7.4.4.4 - Flate and LZW data can have gone through a predictor before
+    // it was compressed, almost always the case for cross-reference streams
+    fn predict(data: Vec<u8>, parameters: Option<&PdfDictionary>)
+            -> Result<Vec<u8>, String> {
+        let parameters = match parameters {
+            Some(parameters) => parameters,
+            None => return Ok(data),
+        };
+
+        let predictor = parameters.integer("Predictor").unwrap_or(1);
+        if predictor == 1 {
+            return Ok(data);
         }
-        let filename = "images/".to_string() +
-                &Uuid::new_v4().to_simple().to_string() + ".jpg";
-        let mut file = File::create(&filename).unwrap();
-        file.write_all(&self.data[..]).unwrap();
 
-        self.data = filename.into_bytes();
-        Ok(())
+        let colors = parameters.unsigned("Colors").unwrap_or(1) as usize;
+        let bits = parameters.unsigned("BitsPerComponent").unwrap_or(8) as usize;
+        let columns = parameters.unsigned("Columns").unwrap_or(1) as usize;
+
+        // Bytes in a row, not counting the PNG filter type
+        let row = colors.checked_mul(bits)
+            .and_then(|pixel| pixel.checked_mul(columns))
+            .map(|bits| bits.div_ceil(8))
+            .filter(|&row| row > 0 && row as u64 <= MAX_DECODED_STREAM_SIZE);
+        let row = match row {
+            Some(row) if [1, 2, 4, 8, 16].contains(&bits) => row,
+            _ => return Err("Invalid predictor parameters.".to_string()),
+        };
+
+        match predictor {
+            2 => Ok(tiff_predictor(&data, row, colors * columns, colors, bits)),
+            10..=15 => png_predictor(&data, row, (colors * bits).div_ceil(8)),
+            _ => Err(format!("Unknown predictor {}.", predictor)),
+        }
+    }
+
+    pub fn dictionary(&self) -> &PdfDictionary {
+        &self.metadata.dictionary
     }
 
+    // The filters that haven't been applied to the data yet
+    pub fn filters(&self) -> &[Filter] {
+        &self.metadata.filters
+    }
+
+    // A filter that fails, or that isn't implemented, stops the decoding:
+    // the data is left as it was before it and the filters from that one on
+    // stay in `filters()`.
     pub fn apply_filters(&mut self) -> Result<(), String> {
         let mut filters = vec![];
         mem::swap(&mut self.metadata.filters, &mut filters);
 
-        for filter in filters {
-            match filter {
-                Filter::FlateDecode => self.apply_flate_decode()?,
-                Filter::DCTDecode => self.apply_dct_decode()?,
-                _ => {
-                    panic!(format!("Unimplemented filter {:?}.", filter));
-                }
+        for (i, filter) in filters.iter().enumerate() {
+            let parameters = self.metadata.decode_parameters(i).cloned();
+            let decoded = match filter {
+                Filter::FlateDecode => self.flate_decode()
+                    .and_then(|data| Stream::predict(data, parameters.as_ref())),
+                Filter::LZWDecode => self.lzw_decode(parameters.as_ref())
+                    .and_then(|data| Stream::predict(data, parameters.as_ref())),
+                // DCT decode (basically a Jpeg) is left to whoever uses the
+                // image, the data stays as it is from here on
+                Filter::DCTDecode => {
+                    self.metadata.filters = filters[i..].to_vec();
+                    break;
+                },
+                _ => Err(format!("Unimplemented filter {:?}.", filter)),
+            };
+
+            match decoded {
+                Ok(data) => self.data = data,
+                Err(e) => {
+                    self.metadata.filters = filters[i..].to_vec();
+                    return Err(e);
+                },
             }
         }
 
@@ -819,6 +902,96 @@ impl Stream {
     }
 }
 
+// 7.4.4.4 - Every row starts with its PNG filter type, the predictor number
+// doesn't matter. `row` is the size of a row without the filter type and
+// `pixel` the size of a pixel rounded up to a byte.
+fn png_predictor(data: &[u8], row: usize, pixel: usize)
+        -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut previous = vec![0; row];
+
+    // The last row may be cut short
+    for encoded in data.chunks(row + 1) {
+        let filter = encoded[0];
+        let encoded = &encoded[1..];
+        let mut current = vec![0; encoded.len()];
+
+        for i in 0..encoded.len() {
+            let left = if i >= pixel { current[i - pixel] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= pixel { previous[i - pixel] } else { 0 };
+
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(format!("Unknown PNG filter {}.", filter)),
+            };
+            current[i] = encoded[i].wrapping_add(prediction);
+        }
+
+        previous[..current.len()].copy_from_slice(&current);
+        result.extend(current);
+    }
+
+    Ok(result)
+}
+
+// The neighbour closest to left + up - up left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance = |value: u8| (estimate - value as i16).abs();
+
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+// 7.4.4.4 - TIFF predictor 2: each component is stored as the difference
+// with the same component of the pixel to its left. A row has `components`
+// of `bits` bits, big endian for 16 bits and packed from the high bit for
+// fewer than 8.
+fn tiff_predictor(data: &[u8], row: usize, components: usize, colors: usize,
+                  bits: usize) -> Vec<u8> {
+    let mut result = data.to_vec();
+
+    for line in result.chunks_mut(row) {
+        match bits {
+            8 => for i in colors..line.len() {
+                line[i] = line[i].wrapping_add(line[i - colors]);
+            },
+            16 => for i in (colors..line.len() / 2).map(|i| i * 2) {
+                let left = u16::from_be_bytes([line[i - colors * 2],
+                                               line[i - colors * 2 + 1]]);
+                let value = u16::from_be_bytes([line[i], line[i + 1]])
+                    .wrapping_add(left);
+                line[i..i + 2].copy_from_slice(&value.to_be_bytes());
+            },
+            _ => {
+                let mask = (1u8 << bits) - 1;
+                let shift = |i: usize| 8 - bits - i * bits % 8;
+                let components = components.min(line.len() * 8 / bits);
+
+                for i in colors..components {
+                    let left = line[(i - colors) * bits / 8] >> shift(i - colors);
+                    let value = (line[i * bits / 8] >> shift(i))
+                        .wrapping_add(left) & mask;
+                    let byte = &mut line[i * bits / 8];
+                    *byte = *byte & !(mask << shift(i)) | value << shift(i);
+                }
+            },
+        }
+    }
+
+    result
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Filter {
     ASCIIHexDecode,
@@ -921,6 +1094,24 @@ impl StreamMetadata {
 
         Some(StreamMetadata { length, filters, dictionary })
     }
+
+    // 7.4.1 - DecodeParms is a dictionary when there is only one filter,
+    // otherwise an array with an entry for each filter.
+    fn decode_parameters(&self, index: usize) -> Option<&PdfDictionary> {
+        let parameters = self.dictionary.get("DecodeParms")
+            .or_else(|| self.dictionary.get("DP"))?;
+
+        let parameters = match parameters {
+            PdfObject::Array(array) => array.get(index)?,
+            _ if index == 0 => parameters,
+            _ => return None,
+        };
+
+        match parameters {
+            PdfObject::Dictionary(dictionary) => Some(dictionary),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -1203,20 +1394,21 @@ where F: FnMut(&Key) -> PdfObject {
 // 7.3.8.1
 fn stream<'a, F>(mut data: &'a [u8], resolve: &mut F) -> Res<'a, Stream>
 where F: FnMut(&Key) -> PdfObject {
-    let dict = resolve_dictionary(block!(data, dictionary), resolve);
+    let dict = block!(data, dictionary);
+    data = consume_whitespace(data);
+
+    exact!(data, "stream");
+    block!(data, eol);
 
+    // Only now that we know this is a stream, resolving references in any
+    // dictionary would load everything it points to.
     let metadata;
-    if let Some(d) = StreamMetadata::from(dict) {
+    if let Some(d) = StreamMetadata::from(resolve_dictionary(dict, resolve)) {
         metadata = d;
     } else {
         return Res::NotFound;
     }
 
-    data = consume_whitespace(data);
-
-    exact!(data, "stream");
-    block!(data, eol);
-
     if data.len() < metadata.length {
         return Res::Error;
     }
@@ -1466,20 +1658,31 @@ fn xref_entry(mut data: &[u8]) -> Res<'_, XrefEntry> {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct Xref {
+    // For compressed objects, the object number of the object stream
     offset: usize,
     type_: XrefType,
     key: Key,
+    // Index of a compressed object in its object stream
+    index: usize,
 }
 
 impl Xref {
     fn from(entry: XrefEntry, object_number: u64) -> Xref {
+        // 7.5.8.3 - The last field of compressed entries is the index, their
+        // generation number is always 0
+        let (generation, index) = match entry.type_ {
+            XrefType::Compressed => (0, entry.generation_number as usize),
+            _ => (entry.generation_number, 0),
+        };
+
         Xref {
             offset: entry.offset,
             type_: entry.type_,
             key: Key {
                 object: object_number,
-                generation: entry.generation_number,
+                generation,
             },
+            index,
         }
     }
 }
@@ -1509,7 +1712,13 @@ fn xref_table(mut data: &[u8]) -> Res<'_, HashMap<u64, Xref>> {
 
 // 7.5.8.2
 fn xref_binary_entry<'a>(mut data: &'a [u8], w: &[usize]) -> Res<'a, XrefEntry> {
-    let type_ = block!(data, binary_integer, w[0]);
+    // Fields with a width of 0 have their default value, which is 1 for the
+    // type and 0 for the others
+    let type_ = if w[0] == 0 {
+        1
+    } else {
+        block!(data, binary_integer, w[0])
+    };
     let offset = block!(data, binary_integer, w[1]) as usize;
     let generation_number = block!(data, binary_integer, w[2]);
 
@@ -1524,23 +1733,49 @@ fn xref_binary_entry<'a>(mut data: &'a [u8], w: &[usize]) -> Res<'a, XrefEntry>
     Res::found(XrefEntry { offset, generation_number, type_: xref_type}, data)
 }
 
-// 7.5.8.2
-fn xref_binary_table<'a>(mut data: &'a [u8], w: &[usize]) -> Res<'a, HashMap<u64, Xref>> {
+// 7.5.8.2 - `index` has the first object number and the number of entries
+// of each subsection, the entries are one after the other. Broken streams
+// often end before all the entries, the ones that are there are kept.
+fn xref_binary_table<'a>(mut data: &'a [u8], w: &[usize], index: &[(u64, u64)])
+        -> Res<'a, HashMap<u64, Xref>> {
     let mut xref_table = HashMap::new();
-    let mut object_number = 0;
 
-    while data.len() > 0 {
-        let xref_entry = block!(data, xref_binary_entry, w);
-        let xref = Xref::from(xref_entry, object_number);
-        xref_table.insert(xref.key.object, xref);
-        object_number += 1;
+    for &(first, count) in index {
+        for object_number in first..first.saturating_add(count) {
+            let xref_entry = match xref_binary_entry(data, w) {
+                Res::Found(r) => {
+                    data = r.remaining;
+                    r.data
+                },
+                _ => return Res::found(xref_table, data),
+            };
+            let xref = Xref::from(xref_entry, object_number);
+            xref_table.insert(xref.key.object, xref);
+        }
     }
 
     Res::found(xref_table, data)
 }
 
-// 7.5.8.1
-fn xref_stream(mut data: &[u8]) -> Res<'_, HashMap<u64, Xref>> {
+// 7.5.8.2 - The subsections in /Index, or a single one for all the objects
+// up to /Size
+fn xref_stream_index(dictionary: &PdfDictionary) -> Option<Vec<(u64, u64)>> {
+    let values: Vec<i64> = match dictionary.integer_array("Index") {
+        Some(values) => values.collect(),
+        None => return Some(vec![(0, dictionary.unsigned("Size")?)]),
+    };
+
+    if !values.len().is_multiple_of(2) || values.iter().any(|&value| value < 0) {
+        return None;
+    }
+
+    Some(values.chunks(2)
+        .map(|pair| (pair[0] as u64, pair[1] as u64))
+        .collect())
+}
+
+// 7.5.8.1 - The stream dictionary doubles as the trailer
+fn xref_stream(mut data: &[u8]) -> Res<'_, (HashMap<u64, Xref>, PdfDictionary)> {
     let definition = block!(data, stream_definition, &mut |r| PdfObject::Reference(*r));
 
     match definition.object {
@@ -1549,11 +1784,26 @@ fn xref_stream(mut data: &[u8]) -> Res<'_, HashMap<u64, Xref>> {
                 .map(|it| it.map(|x| x as usize).collect())
                 .unwrap_or_else(|| vec![]);
 
-            stream.apply_filters()?;
+            // Three fields of up to 8 bytes, entries can't be empty
+            if w.len() != 3 || w.iter().any(|&width| width > 8)
+                    || w.iter().sum::<usize>() == 0 {
+                return Res::Error;
+            }
+
+            let index = match xref_stream_index(&stream.metadata.dictionary) {
+                Some(index) => index,
+                None => return Res::Error,
+            };
+
+            if stream.apply_filters().is_err() {
+                return Res::Error;
+            }
 
-            let mut _stream_data = &stream.data[..];
-            let xref = block!(_stream_data, xref_binary_table, w.as_slice());
-            Res::found(xref, data)
+            match xref_binary_table(&stream.data, &w, &index) {
+                Res::Found(xref) =>
+                    Res::found((xref.data, stream.metadata.dictionary), data),
+                _ => Res::Error,
+            }
         },
         _ => { Res::Error },
     }
@@ -1577,27 +1827,339 @@ fn trailer(mut data: &[u8]) -> Res<'_, PdfDictionary> {
     Res::found(result, data)
 }
 
+// 7.5.4, 7.5.5 and 7.5.8 - A cross-reference section and its trailer
+fn xref_section(mut data: &[u8]) -> Res<'_, (HashMap<u64, Xref>, PdfDictionary)> {
+    if let Res::Found(r) = xref_table(data) {
+        data = consume_whitespace(r.remaining);
+        let trailer_dictionary = block!(data, trailer);
+        return Res::found((r.data, trailer_dictionary), data);
+    }
+
+    xref_stream(data)
+}
+
+// 7.5.6 - One cross-reference section of the file. The original file is a
+// revision, and so is every incremental update appended to it.
 #[derive(Debug)]
+pub struct Revision {
+    offset: usize,
+    trailer: PdfDictionary,
+    xref: HashMap<u64, Xref>,
+}
+
+impl Revision {
+    // Where the section starts in the file
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn trailer(&self) -> &PdfDictionary {
+        &self.trailer
+    }
+
+    // The objects added or changed in this revision
+    pub fn objects(&self) -> Vec<Key> {
+        self.keys(|type_| type_ != XrefType::Free)
+    }
+
+    // The objects deleted in this revision, with the generation number the
+    // object will have if it's used again
+    pub fn freed(&self) -> Vec<Key> {
+        self.keys(|type_| type_ == XrefType::Free)
+    }
+
+    fn keys<F: Fn(XrefType) -> bool>(&self, filter: F) -> Vec<Key> {
+        let mut keys: Vec<Key> = self.xref.values()
+            // Object 0 is always the head of the list of free objects
+            .filter(|xref| xref.key.object != 0 && filter(xref.type_))
+            .map(|xref| xref.key)
+            .collect();
+        keys.sort_by_key(|key| (key.object, key.generation));
+        keys
+    }
+}
+
+// 7.5.5 - The trailer entries that an update takes from the trailers before
+// it when it doesn't have them
+const DOCUMENT_TRAILER_KEYS: [&str; 5] = ["Size", "Root", "Encrypt", "Info", "ID"];
+
+// An object listed in the cross-reference table, it's only parsed the
+// first time it's resolved.
+#[derive(Debug)]
+struct Entry {
+    xref: Xref,
+    object: OnceLock<PdfObject>,
+    // 7.5.7 - The object numbers and offsets, when this is an object stream
+    header: OnceLock<Vec<(u64, usize)>>,
+}
+
 pub struct Pdf {
+    data: Arc<Vec<u8>>,
     version: Version,
-    objects: HashMap<u64, PdfObject>,
+    // Oldest first, shared with the other revisions of the same file
+    revisions: Arc<Vec<Revision>>,
+    // Index in `revisions` of the one the objects are as of
+    revision: usize,
+    trailer: PdfDictionary,
+    entries: HashMap<Key, Entry>,
+    // Objects being parsed right now by each thread, see `resolve`
+    loading: Mutex<HashSet<(ThreadId, Key)>>,
+}
+
+impl fmt::Debug for Pdf {
+    // Without the whole file in it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pdf")
+            .field("version", &self.version)
+            .field("revision", &self.revision)
+            .field("trailer", &self.trailer)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
 }
 
 impl Pdf {
+    // 7.5.6 - The objects as of `revision`: later sections override the
+    // entries of earlier ones, and objects freed along the way are gone.
+    fn new(data: Arc<Vec<u8>>, version: Version, revisions: Arc<Vec<Revision>>,
+           revision: usize) -> Pdf {
+        let sections = &revisions[..=revision];
+        let mut xref = HashMap::new();
+        for section in sections {
+            xref.extend(section.xref.iter().map(|(&object, &x)| (object, x)));
+        }
+
+        // Updates are meant to repeat the entries of the trailer that are
+        // about the whole document, not all do. The others only describe
+        // their own section.
+        let mut trailer_data = revisions[revision].trailer.data.clone();
+        for key in DOCUMENT_TRAILER_KEYS.iter() {
+            if trailer_data.contains_key(*key) {
+                continue;
+            }
+
+            let value = sections.iter().rev()
+                .find_map(|section| section.trailer.get(key));
+            if let Some(value) = value {
+                trailer_data.insert(key.to_string(), value.clone());
+            }
+        }
+
+        let entries = xref.into_values()
+            .filter(|xref| xref.type_ != XrefType::Free)
+            .map(|xref| (xref.key, Entry {
+                xref,
+                object: OnceLock::new(),
+                header: OnceLock::new(),
+            }))
+            .collect();
+
+        Pdf {
+            data,
+            version,
+            revisions,
+            revision,
+            trailer: PdfDictionary::new(trailer_data),
+            entries,
+            loading: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn trailer(&self) -> &PdfDictionary {
+        &self.trailer
+    }
+
+    // All the revisions in the file, oldest first
+    pub fn revisions(&self) -> &[Revision] {
+        &self.revisions
+    }
+
+    // Index of the revision the objects are as of, the last one unless
+    // this comes from `at_revision`
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    // The file as it was when revision `index` was written, 0 being the
+    // original file. Objects are loaded again, nothing is shared with self
+    // but the data.
+    pub fn at_revision(&self, index: usize) -> Option<Pdf> {
+        if index >= self.revisions.len() {
+            return None;
+        }
+
+        Some(Pdf::new(self.data.clone(), self.version.clone(),
+                      self.revisions.clone(), index))
+    }
+
+    // 7.3.10 - Objects that are not in the file, or that can't be parsed,
+    // are null. So are references to an earlier generation of an object.
     pub fn resolve(&self, key: &Key) -> &PdfObject {
-        self.objects.get(&key.object).unwrap_or(&PdfObject::Null)
+        let entry = match self.entries.get(key) {
+            Some(entry) => entry,
+            None => return &PdfObject::Null,
+        };
+
+        if let Some(object) = entry.object.get() {
+            return object;
+        }
+
+        // A stream with a /Length that refers back to the stream itself
+        // would never finish loading. Other threads may be loading the same
+        // object, whichever finishes first is kept.
+        let loading = (thread::current().id(), *key);
+        {
+            let mut objects = self.loading.lock().unwrap();
+            let nested = objects.iter()
+                .filter(|(thread, _)| *thread == loading.0)
+                .count();
+            if nested >= MAX_NESTED_LOADS || !objects.insert(loading) {
+                return &PdfObject::Null;
+            }
+        }
+        let object = self.load(&entry.xref);
+        self.loading.lock().unwrap().remove(&loading);
+
+        entry.object.get_or_init(|| object)
     }
 
-    pub fn objects(&self) -> &HashMap<u64, PdfObject> {
-        &self.objects
+    fn load(&self, xref: &Xref) -> PdfObject {
+        match xref.type_ {
+            XrefType::InUse => self.load_uncompressed(xref),
+            XrefType::Compressed => self.load_compressed(xref),
+            XrefType::Free => PdfObject::Null,
+        }
+    }
+
+    // 7.5.7 - The object stream is loaded like any other stream, its header
+    // is read the first time one of its objects is needed.
+    fn load_compressed(&self, xref: &Xref) -> PdfObject {
+        let mut stream_key = Key::new(xref.offset as u64, 0);
+        let mut visited = HashSet::new();
+
+        while visited.insert(stream_key) {
+            let stream = match self.resolve(&stream_key) {
+                PdfObject::Stream(stream)
+                    if stream.dictionary().identifier("Type") == Some("ObjStm")
+                        && stream.filters().is_empty() => stream,
+                _ => break,
+            };
+
+            let header = match self.object_stream_header(&stream_key, stream) {
+                Some(header) => header,
+                None => break,
+            };
+            if let Some(result) = compressed_object(stream, header,
+                                                    xref.key.object, xref.index) {
+                return result;
+            }
+
+            // Not where the table says, it may be in the stream this one
+            // extends
+            stream_key = match stream.dictionary().get("Extends") {
+                Some(PdfObject::Reference(key)) => *key,
+                _ => break,
+            };
+        }
+
+        PdfObject::Null
+    }
+
+    // A header that can't be read is kept empty, so that it's only tried once
+    fn object_stream_header(&self, key: &Key, stream: &Stream)
+            -> Option<&[(u64, usize)]> {
+        let entry = self.entries.get(key)?;
+        let header = entry.header.get_or_init(|| {
+            let n = stream.dictionary().unsigned("N");
+            let first = stream.dictionary().unsigned("First");
+            match (n, first) {
+                (Some(n), Some(first)) => stream.data.get(..first as usize)
+                    .and_then(|data| object_stream_header(data, n as usize)
+                        .into_result().ok())
+                    .unwrap_or_default(),
+                _ => vec![],
+            }
+        });
+
+        Some(header)
+    }
+
+    fn load_uncompressed(&self, xref: &Xref) -> PdfObject {
+        if xref.offset >= self.data.len() {
+            return PdfObject::Null;
+        }
+
+        let data = &self.data[xref.offset..];
+        let mut result = match stream_definition(data, &mut |k| self.resolve(k).clone()) {
+            Res::Found(r) => r.data,
+            _ => match definition(data) {
+                Res::Found(r) => r.data,
+                _ => return PdfObject::Null,
+            },
+        };
+
+        // The offset is wrong, the table doesn't match the file
+        if result.key != xref.key {
+            return PdfObject::Null;
+        }
+
+        // A stream that can't be decoded is still returned, with the
+        // filters that are left in `filters()`
+        if let PdfObject::Stream(ref mut stream) = result.object {
+            let _ = stream.apply_filters();
+        }
+
+        result.object
     }
 }
 
-// 7.5
-#[allow(unused_assignments)]
-fn pdf(mut data: &[u8]) -> Res<'_, Pdf> {
-    let original_data = data;
+// 7.5.7 - The pairs of object number and offset at the start of an object
+// stream
+fn object_stream_header(mut data: &[u8], n: usize) -> Res<'_, Vec<(u64, usize)>> {
+    let mut header = vec![];
+    for _ in 0..n {
+        data = consume_whitespace(data);
+        let object_number = block!(data, nonnegative_integer);
+        data = consume_whitespace(data);
+        let offset = block!(data, nonnegative_integer) as usize;
+        header.push((object_number, offset));
+    }
+
+    Res::found(header, data)
+}
+
+// 7.5.7 - Object `object_number` in an object stream, `index` is where it
+// should be in the header. The objects are bare, without `obj` and `endobj`.
+fn compressed_object(stream: &Stream, header: &[(u64, usize)],
+                     object_number: u64, index: usize) -> Option<PdfObject> {
+    let first = stream.dictionary().unsigned("First")? as usize;
+    let offset = match header.get(index) {
+        Some(&(number, offset)) if number == object_number => offset,
+        _ => header.iter().find(|&&(number, _)| number == object_number)?.1,
+    };
+
+    let data = stream.data.get(first.checked_add(offset)?..)?;
+    match object(consume_whitespace(data)) {
+        Res::Found(r) => Some(r.data),
+        _ => None,
+    }
+}
+
+// What is read when the file is opened
+struct Structure {
+    version: Version,
+    revisions: Vec<Revision>,
+}
 
+// 7.5 - Only the header, the cross-reference sections and the trailers are
+// read here. The objects are found through the sections when they are
+// needed, so whatever is between them doesn't matter.
+#[allow(unused_assignments)]
+fn pdf(data: &[u8]) -> Res<'_, Structure> {
     if data.len() < 1 {
         return Res::NotFound;
     }
@@ -1613,6 +2175,8 @@ fn pdf(mut data: &[u8]) -> Res<'_, Pdf> {
 
         if let Res::Found(xref) = startxref(&data[end..]) {
             break xref;
+        } else if end == 0 {
+            return Res::NotFound;
         } else {
             end -= 1;
             continue;
@@ -1632,96 +2196,69 @@ fn pdf(mut data: &[u8]) -> Res<'_, Pdf> {
 
     let startxref_index = startxref_obj.data as usize;
 
-    if data.len() < startxref_index {
+    if data.len() <= startxref_index {
         // startxref is not a valid index
         return Res::Error;
     }
 
-    let mut xref_data = &data[startxref_index..];
-
-    let has_binary_xref;
-    let xref;
-    // The xref table can either be explicit on in a stream object
-    if let Res::Found(r) = xref_table(xref_data) {
-        xref = r.data;
-        xref_data = r.remaining;
-        block!(xref_data, trailer);
-        xref_data = consume_whitespace(xref_data);
-
-        // We should be back at startxref now
-        block!(xref_data, startxref);
-        has_binary_xref = false;
-    } else {
-        xref = block!(xref_data, xref_stream);
-        has_binary_xref = true;
-    }
-
-    let version = block!(data, version);
-    data = consume_whitespace(data);
-
-    let mut objects = HashMap::new();
+    // 7.5.6 - Each update points to the section before it with /Prev. The
+    // last section has to be there, the file can still be read without the
+    // older ones.
+    let mut revisions = vec![];
+    let mut visited = HashSet::new();
+    let mut next = Some(startxref_index);
+    while let Some(offset) = next {
+        if offset >= data.len() || !visited.insert(offset) {
+            break;
+        }
 
-    loop {
-        let mut result;
-        if let Res::Found(r) = stream_definition(data, &mut |k| {
-            resolve(k, &xref, &mut objects, original_data).clone()
-        }) {
-            data = r.remaining;
-            result = r.data;
+        let mut xref_data = &data[offset..];
+        let (mut xref, trailer_dictionary) = if revisions.is_empty() {
+            block!(xref_data, xref_section)
+        } else {
+            match xref_section(xref_data) {
+                Res::Found(r) => r.data,
+                _ => break,
+            }
+        };
 
-            if let PdfObject::Stream(ref mut s) = result.object {
-                s.apply_filters()?;
+        // 7.5.8.4 - Hybrid files also have a stream with the objects that
+        // only newer readers know how to find, usually compressed ones. The
+        // table has the last word, except for objects it says are free.
+        let hidden = trailer_dictionary.unsigned("XRefStm")
+            .and_then(|stream_offset| data.get(stream_offset as usize..))
+            .map(xref_stream);
+        if let Some(Res::Found(r)) = hidden {
+            for (object_number, stream_xref) in r.data.0 {
+                let table_xref = xref.entry(object_number).or_insert(stream_xref);
+                if table_xref.type_ == XrefType::Free {
+                    *table_xref = stream_xref;
+                }
             }
-        } else {
-            result = repeat!(data, definition);
         }
 
-        objects.insert(result.key.object, result.object);
-        data = consume_whitespace(data);
+        next = trailer_dictionary.unsigned("Prev").map(|prev| prev as usize);
+        revisions.push(Revision { offset, trailer: trailer_dictionary, xref });
     }
+    revisions.reverse();
 
-    // After all the definitions we should be back at the xref table or at startxref
-    if has_binary_xref {
-        exact!(data, "startxref");
-    } else {
-        exact!(data, "xref");
-    }
+    let mut header = data;
+    let version = block!(header, version);
 
-    Res::found(Pdf {
-        version,
-        objects,
-    }, &[])
+    Res::found(Structure { version, revisions }, &[])
 }
 
-fn resolve<'a>(key: &Key, xref: &HashMap<u64, Xref>,
-           objects: &'a mut HashMap<u64, PdfObject>,
-           data: &[u8]) -> &'a PdfObject {
-    if objects.contains_key(&key.object) {
-        return objects.get(&key.object).unwrap();
-    }
-
-    let offset = xref[&key.object].offset;
-    let resolved_data = &data[offset..];
+pub fn parse_pdf(data: Vec<u8>) -> Result<Pdf, String> {
+    let structure = pdf(&data).into_result()?;
+    let last = structure.revisions.len() - 1;
 
-    match definition(resolved_data) {
-        Res::Found(x) => {
-            if x.data.key != *key {
-                panic!("Expected {:?} but found {:?}", key, x.data.key);
-            }
-            objects.insert(x.data.key.object, x.data.object);
-            objects.get(&key.object).unwrap()
-        }
-        Res::NotFound | Res::Error => &PdfObject::Null,
-    }
-}
-
-pub fn parse_pdf(data: &[u8]) -> Result<Pdf, String> {
-    pdf(data).into_result()
+    Ok(Pdf::new(Arc::new(data), structure.version,
+                Arc::new(structure.revisions), last))
 }
 
 pub fn parse_page(data: &[u8]) -> Result<Vec<(Vec<PdfObject>, Operator)>, String> {
-    println!("=== PAGE");
     contents(data).into_result()
+        .map_err(|_| "Content stream with unparseable data.".to_string())
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -1979,9 +2516,11 @@ fn inline_image(mut data: &[u8]) -> Res<'_, Stream> {
 
     exact!(data, "EI");
 
-    let metadata = StreamMetadata::from_inline_image(
-        PdfDictionary::new(dict),
-        image_data.len()).ok_or(String::new())?;
+    let metadata = match StreamMetadata::from_inline_image(
+            PdfDictionary::new(dict), image_data.len()) {
+        Some(metadata) => metadata,
+        None => return Res::Error,
+    };
 
     Res::found(Stream::new(image_data, metadata, true), data)
 }
@@ -2013,10 +2552,9 @@ fn contents(mut data: &[u8])
         }
     }
 
-    if data.len() > 0 {
-        // TODO: fail gracefully
-        eprintln!("len = {}", data.len());
-        panic!();
+    // What is left is neither an operand nor an operator
+    if !data.is_empty() {
+        return Res::Error;
     }
 
     Res::found(result, data)
@@ -2026,6 +2564,10 @@ fn contents(mut data: &[u8])
 mod test {
     use super::*;
 
+    use crate::deflate::{CompressionLevel, lzw_encode, rfc1950_compress};
+    use crate::document::Document;
+    use crate::error::PdfError;
+
     macro_rules! test {
         ($name: ident, $subject: ident) => {
             fn $name(data: &str, expected: &str, remaining: &str) {
@@ -2102,6 +2644,7 @@ mod test {
                     object: object_number,
                     generation: generation_number,
                 },
+                index: 0,
             }
         }
     }
@@ -2450,6 +2993,38 @@ special characters (*!&}^% and so on).)", "Strings may contain balanced parenthe
                 .iter().cloned().collect());
     }
 
+    fn filtered_stream(data: &[u8]) -> Vec<u8> {
+        let mut result = stream(data, &mut |_| PdfObject::Null).unwrap();
+        result.data.apply_filters().unwrap();
+        result.data.data
+    }
+
+    #[test]
+    fn test_lzw_stream() {
+        // 7.4.4.2 - Example of LZW encoding
+        let mut data = b"<< /Length 9 /Filter /LZWDecode >>\nstream\n".to_vec();
+        data.extend(&[0x80, 0x0B, 0x60, 0x50, 0x22, 0x0C, 0x0C, 0x85, 0x01]);
+        data.extend(b"\nendstream");
+        assert_eq!(filtered_stream(&data), b"-----A---B");
+
+        // Long enough for the codes to get to 10 bits, which is where
+        // EarlyChange makes a difference.
+        let expected: Vec<u8> = (0..2000u32).map(|i| (i * i % 251) as u8)
+            .collect();
+        let mut encoded = vec![];
+        lzw_encode(&expected, &mut encoded, &LzwOptions {
+            early_change: false,
+            ..LzwOptions::default()
+        }).unwrap();
+
+        let mut data = format!("<< /Length {} /Filter [/LZWDecode] \
+            /DecodeParms [<< /EarlyChange 0 >>] >>\nstream\n",
+            encoded.len()).into_bytes();
+        data.extend(&encoded);
+        data.extend(b"\nendstream");
+        assert_eq!(filtered_stream(&data), expected);
+    }
+
     test!(version_test, version, Version);
 
     #[test]
@@ -2574,4 +3149,733 @@ special characters (*!&}^% and so on).)", "Strings may contain balanced parenthe
         assert_eq!(binary_integer(&[0, 0xFF], 2).unwrap().data, 0xFF);
         assert_eq!(binary_integer(&[0xFF, 0x00], 2).unwrap().data, 0xFF00);
     }
+
+    // Writes files the way producers do, keeping track of the offsets for
+    // the cross-reference table.
+    struct Builder {
+        data: Vec<u8>,
+        // Object number, generation, offset and type of the objects written
+        // since the last table. Compressed objects have their index and the
+        // object stream instead.
+        entries: Vec<(u64, u64, usize, XrefType)>,
+        // Write cross-reference streams with Flate and the PNG Up predictor
+        // like most writers do
+        predictor: bool,
+    }
+
+    impl Builder {
+        fn new() -> Builder {
+            Builder {
+                data: b"%PDF-1.7\n".to_vec(),
+                entries: vec![],
+                predictor: false,
+            }
+        }
+
+        fn raw(&mut self, data: &[u8]) {
+            self.data.extend_from_slice(data);
+        }
+
+        fn entry(&mut self, object: u64, generation: u64, offset: usize) {
+            self.entries.push((object, generation, offset, XrefType::InUse));
+        }
+
+        fn free(&mut self, object: u64, next_generation: u64) {
+            self.entries.push((object, next_generation, 0, XrefType::Free));
+        }
+
+        fn compressed(&mut self, object: u64, stream: u64, index: u64) {
+            self.entries.push((object, index, stream as usize,
+                               XrefType::Compressed));
+        }
+
+        // An object stream with `objects` in it, in that order
+        fn object_stream(&mut self, object: u64, dictionary: &str,
+                         objects: &[(u64, &str)]) {
+            let mut header = vec![];
+            let mut body = String::new();
+            for (index, &(number, value)) in objects.iter().enumerate() {
+                header.push(format!("{} {}", number, body.len()));
+                body += value;
+                body += "\n";
+                self.compressed(number, object, index as u64);
+            }
+
+            let header = header.join(" ") + "\n";
+            self.object(object, 0, &format!(
+                "<< /Type /ObjStm /N {} /First {} /Length {} {} >>\n\
+                 stream\n{}{}\nendstream",
+                objects.len(), header.len(), header.len() + body.len(),
+                dictionary, header, body));
+        }
+
+        fn object(&mut self, object: u64, generation: u64, body: &str) -> usize {
+            let offset = self.data.len();
+            self.entry(object, generation, offset);
+            self.raw(format!("{} {} obj\n{}\nendobj\n", object, generation,
+                             body).as_bytes());
+            offset
+        }
+
+        // Sorts the entries and splits them in runs of consecutive object
+        // numbers, as ranges of `entries`
+        fn subsections(&mut self) -> Vec<(usize, usize)> {
+            self.entries.sort_by_key(|entry| entry.0);
+
+            let mut subsections = vec![];
+            let mut start = 0;
+            while start < self.entries.len() {
+                let mut end = start + 1;
+                while end < self.entries.len()
+                        && self.entries[end].0 == self.entries[end - 1].0 + 1 {
+                    end += 1;
+                }
+
+                subsections.push((start, end));
+                start = end;
+            }
+
+            subsections
+        }
+
+        // Writes a table for the entries since the last one, followed by the
+        // trailer. Returns the offset of the table.
+        fn xref_table(&mut self, trailer: &str) -> usize {
+            let offset = self.data.len();
+
+            let mut table = "xref\n".to_string();
+            for (start, end) in self.subsections() {
+                table += &format!("{} {}\n", self.entries[start].0, end - start);
+                for &(_, generation, offset, type_) in &self.entries[start..end] {
+                    let type_ = match type_ {
+                        XrefType::InUse => 'n',
+                        XrefType::Free => 'f',
+                        XrefType::Compressed =>
+                            panic!("Compressed objects need a stream"),
+                    };
+                    table += &format!("{:010} {:05} {}\r\n", offset, generation,
+                                      type_);
+                }
+            }
+
+            self.raw(format!("{}trailer\n<< {} >>\nstartxref\n{}\n%%EOF\n",
+                             table, trailer, offset).as_bytes());
+            self.entries.clear();
+            offset
+        }
+
+        // The same with a cross-reference stream, which doubles as the
+        // trailer. It has an entry for itself.
+        fn xref_stream(&mut self, object: u64, trailer: &str) -> usize {
+            let offset = self.xref_stream_object(object, trailer);
+            self.raw(format!("startxref\n{}\n%%EOF\n", offset).as_bytes());
+            offset
+        }
+
+        // Only the stream, for hybrid files
+        fn xref_stream_object(&mut self, object: u64, trailer: &str) -> usize {
+            let offset = self.data.len();
+            self.entry(object, 0, offset);
+
+            let mut index = vec![];
+            let mut table = vec![];
+            for (start, end) in self.subsections() {
+                index.push(format!("{} {}", self.entries[start].0, end - start));
+                for &entry in &self.entries[start..end] {
+                    let (type_, second, third) = match entry {
+                        (_, generation, offset, XrefType::InUse) =>
+                            (1, offset, generation),
+                        (_, index, stream, XrefType::Compressed) =>
+                            (2, stream, index),
+                        (_, generation, next, XrefType::Free) =>
+                            (0, next, generation),
+                    };
+
+                    table.push(type_);
+                    table.extend_from_slice(&(second as u32).to_be_bytes());
+                    table.extend_from_slice(&(third as u16).to_be_bytes());
+                }
+            }
+
+            let mut filter = String::new();
+            if self.predictor {
+                let mut predicted = vec![];
+                let mut previous = [0; 7];
+                for row in table.chunks(7) {
+                    predicted.push(2);
+                    for (value, above) in row.iter().zip(&mut previous) {
+                        predicted.push(value.wrapping_sub(*above));
+                        *above = *value;
+                    }
+                }
+
+                table.clear();
+                rfc1950_compress(&predicted, &mut table,
+                                 CompressionLevel::Default).unwrap();
+                filter = "/Filter /FlateDecode \
+                          /DecodeParms << /Predictor 12 /Columns 7 >>"
+                    .to_string();
+            }
+
+            let size = self.entries.last().map_or(0, |entry| entry.0 + 1);
+            self.raw(format!("{} 0 obj\n<< /Type /XRef /W [1 4 2] /Size {} \
+                              /Index [{}] {} /Length {} {} >>\nstream\n",
+                             object, size, index.join(" "), filter,
+                             table.len(), trailer).as_bytes());
+            self.raw(&table);
+            self.raw(b"\nendstream\nendobj\n");
+            self.entries.clear();
+            offset
+        }
+    }
+
+    fn loaded(pdf: &Pdf) -> usize {
+        pdf.entries.values().filter(|entry| entry.object.get().is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_lazy_loading() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.raw(b"Not an object ]>> endobj\n");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.object(3, 0, "<< /Broken [ (unbalanced >>");
+        file.xref_table("/Size 4 /Root 1 0 R");
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.version(), &Version::V1_7);
+        assert_eq!(loaded(&pdf), 0);
+
+        let catalog = pdf.resolve(&Key::new(1, 0));
+        assert_eq!(catalog.as_dictionary(&pdf).unwrap().identifier("Type"),
+                   Some("Catalog"));
+        assert_eq!(loaded(&pdf), 1);
+
+        // Parsed only once
+        assert!(std::ptr::eq(catalog, pdf.resolve(&Key::new(1, 0))));
+        assert_eq!(loaded(&pdf), 1);
+
+        // Neither the junk before it nor the broken object after it matter
+        assert!(pdf.resolve(&Key::new(2, 0)).as_dictionary(&pdf).is_some());
+        assert_eq!(pdf.resolve(&Key::new(3, 0)), &PdfObject::Null);
+        assert_eq!(pdf.resolve(&Key::new(4, 0)), &PdfObject::Null);
+    }
+
+    #[test]
+    fn test_stream_length() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Length 2 0 R >>\nstream\nabc\nendstream");
+        file.object(2, 0, "3");
+        file.object(3, 0, "<< /Length 3 0 R >>\nstream\nabc\nendstream");
+        file.xref_table("/Size 4");
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.resolve(&Key::new(1, 0)).as_stream().unwrap().data,
+                   b"abc");
+        // Its own length can't be known
+        assert_eq!(pdf.resolve(&Key::new(3, 0)), &PdfObject::Null);
+    }
+
+    #[test]
+    fn test_stream_references() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Length 2 0 R /SMask 3 0 R >>\n\
+                           stream\nabc\nendstream");
+        file.object(2, 0, "3");
+        file.object(3, 0, "<< /Length 2 0 R >>\nstream\ndef\nendstream");
+        file.xref_table("/Size 4");
+
+        // Only the length is loaded with the stream
+        let pdf = parse_pdf(file.data).unwrap();
+        let stream = pdf.resolve(&Key::new(1, 0)).as_stream().unwrap();
+        assert_eq!(stream.dictionary().integer("Length"), Some(3));
+        assert_eq!(stream.dictionary().get("SMask"),
+                   Some(&PdfObject::Reference(Key::new(3, 0))));
+        assert_eq!(loaded(&pdf), 2);
+    }
+
+    #[test]
+    fn test_stream_length_chain() {
+        // Each /Length is the next stream, loading the first one would
+        // load them all, one inside the other
+        let mut file = Builder::new();
+        for i in 1..10000 {
+            file.object(i, 0, &format!("<< /Length {} 0 R >>\n\
+                                        stream\nabc\nendstream", i + 1));
+        }
+        file.object(10000, 0, "3");
+        file.xref_table("/Size 10001");
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.resolve(&Key::new(1, 0)), &PdfObject::Null);
+        assert_eq!(pdf.resolve(&Key::new(9999, 0)).as_stream().unwrap().data,
+                   b"abc");
+    }
+
+    #[test]
+    fn test_wrong_offset() {
+        let mut file = Builder::new();
+        let offset = file.object(1, 0, "(one)");
+        file.entry(2, 0, offset);
+        file.xref_table("/Size 3");
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.resolve(&Key::new(1, 0)), &PdfObject::string("one"));
+        assert_eq!(pdf.resolve(&Key::new(2, 0)), &PdfObject::Null);
+    }
+
+    #[test]
+    fn test_first_page() {
+        const PAGES: u64 = 2000;
+
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        let kids: Vec<String> = (0..PAGES)
+            .map(|i| format!("{} 0 R", i + 3))
+            .collect();
+        file.object(2, 0, &format!("<< /Type /Pages /Kids [{}] /Count {} >>",
+                                   kids.join(" "), PAGES));
+        for i in 0..PAGES {
+            file.object(i + 3, 0, "<< /Type /Page /Parent 2 0 R \
+                                   /MediaBox [0 0 612 792] >>");
+        }
+        file.xref_table(&format!("/Size {} /Root 1 0 R", PAGES + 3));
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.page_count(), PAGES as usize);
+        assert!(document.page(0).unwrap().media_box().is_some());
+
+        // The catalog, the root of the page tree and the page
+        assert_eq!(loaded(document.pdf()), 3);
+
+        // One walk over the tree for all of them
+        assert_eq!(document.pages().count(), PAGES as usize);
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.object(3, 0, "(original)");
+        file.object(4, 0, "(deleted)");
+        let first = file.xref_table("/Size 5 /Root 1 0 R");
+
+        file.object(3, 0, "(changed)");
+        file.free(4, 1);
+        file.object(5, 0, "(added)");
+        let second = file.xref_table(
+            &format!("/Size 6 /Root 1 0 R /Prev {}", first));
+
+        // Without the /Root of the earlier trailers
+        file.free(5, 1);
+        file.object(6, 0, "(new)");
+        let third = file.xref_table(&format!("/Size 7 /Prev {}", second));
+
+        file.object(5, 1, "(reused)");
+        file.xref_table(&format!("/Size 7 /Root 1 0 R /Prev {}", third));
+
+        let document = Document::from_bytes(file.data).unwrap();
+        let pdf = document.pdf();
+        let offsets: Vec<usize> = pdf.revisions().iter()
+            .map(Revision::offset)
+            .collect();
+        assert_eq!(offsets[..3], [first, second, third]);
+        assert_eq!(pdf.revision(), 3);
+        assert_eq!(pdf.revisions()[1].objects(),
+                   vec![Key::new(3, 0), Key::new(5, 0)]);
+        assert_eq!(pdf.revisions()[1].freed(), vec![Key::new(4, 1)]);
+        assert_eq!(pdf.revisions()[2].trailer().get("Root"), None);
+
+        let text = |pdf: &Pdf, object, generation| {
+            pdf.resolve(&Key::new(object, generation)).clone()
+        };
+
+        assert_eq!(text(pdf, 3, 0), PdfObject::string("changed"));
+        assert_eq!(text(pdf, 4, 0), PdfObject::Null);
+        assert_eq!(text(pdf, 4, 1), PdfObject::Null);
+        assert_eq!(text(pdf, 5, 0), PdfObject::Null);
+        assert_eq!(text(pdf, 5, 1), PdfObject::string("reused"));
+        assert_eq!(text(pdf, 6, 0), PdfObject::string("new"));
+        assert_eq!(pdf.trailer().integer("Size"), Some(7));
+
+        // The trailers are merged
+        let freed = document.at_revision(2).unwrap();
+        assert_eq!(freed.pdf().revision(), 2);
+        assert_eq!(freed.catalog().identifier("Type"), Some("Catalog"));
+        assert_eq!(text(freed.pdf(), 5, 0), PdfObject::Null);
+        assert_eq!(text(freed.pdf(), 5, 1), PdfObject::Null);
+        assert_eq!(text(freed.pdf(), 6, 0), PdfObject::string("new"));
+
+        let updated = document.at_revision(1).unwrap();
+        assert_eq!(text(updated.pdf(), 3, 0), PdfObject::string("changed"));
+        assert_eq!(text(updated.pdf(), 5, 0), PdfObject::string("added"));
+        assert_eq!(text(updated.pdf(), 6, 0), PdfObject::Null);
+
+        let original = document.at_revision(0).unwrap();
+        assert_eq!(original.trailer().integer("Size"), Some(5));
+        assert_eq!(text(original.pdf(), 3, 0), PdfObject::string("original"));
+        assert_eq!(text(original.pdf(), 4, 0), PdfObject::string("deleted"));
+        assert_eq!(text(original.pdf(), 5, 0), PdfObject::Null);
+
+        match document.at_revision(4) {
+            Err(PdfError::NoSuchRevision(4)) => {},
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_prev() {
+        // Pointing back at itself
+        let mut file = Builder::new();
+        file.object(1, 0, "(one)");
+        let offset = file.data.len();
+        file.xref_table(&format!("/Size 2 /Prev {}", offset));
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.revisions().len(), 1);
+        assert_eq!(pdf.resolve(&Key::new(1, 0)), &PdfObject::string("one"));
+
+        // Pointing at something that's not a section, or out of the file
+        for prev in &[3, 1_000_000] {
+            let mut file = Builder::new();
+            file.object(1, 0, "(one)");
+            file.xref_table(&format!("/Size 2 /Prev {}", prev));
+
+            let pdf = parse_pdf(file.data).unwrap();
+            assert_eq!(pdf.revisions().len(), 1);
+            assert_eq!(pdf.resolve(&Key::new(1, 0)), &PdfObject::string("one"));
+        }
+    }
+
+    #[test]
+    fn test_object_stream() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object_stream(10, "", &[
+            (2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>"),
+            (3, "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] >>"),
+        ]);
+        file.object_stream(11, "/Extends 10 0 R", &[
+            (4, "(four)"),
+            (5, "[4 0 R 42]"),
+        ]);
+        file.object_stream(12, "/Extends 12 0 R", &[(6, "6")]);
+        file.xref_stream(13, "/Root 1 0 R");
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.revisions()[0].objects().len(), 10);
+        assert_eq!(document.page_count(), 1);
+        assert_eq!(document.page(0).unwrap().media_box(),
+                   Some([0.0, 0.0, 612.0, 792.0]));
+
+        let pdf = document.pdf();
+        assert_eq!(pdf.resolve(&Key::new(4, 0)), &PdfObject::string("four"));
+        assert_eq!(pdf.resolve(&Key::new(5, 0)), &PdfObject::Array(vec![
+            PdfObject::Reference(Key::new(4, 0)), PdfObject::Integer(42)]));
+        assert_eq!(pdf.resolve(&Key::new(6, 0)), &PdfObject::Integer(6));
+        // Compressed objects are always generation 0
+        assert_eq!(pdf.resolve(&Key::new(4, 1)), &PdfObject::Null);
+
+        // The header is read once, the stream that's extended isn't copied
+        let stream = Key::new(11, 0);
+        assert_eq!(pdf.entries[&stream].header.get().map(Vec::len), Some(2));
+        assert_eq!(pdf.resolve(&stream).as_stream().unwrap().dictionary()
+                   .get("Extends"), Some(&PdfObject::Reference(Key::new(10, 0))));
+    }
+
+    #[test]
+    fn test_wrong_object_stream() {
+        let mut file = Builder::new();
+        file.object(1, 0, "(one)");
+        file.object_stream(10, "", &[(2, "(two)"), (3, "(three)")]);
+        file.object_stream(11, "/Extends 10 0 R", &[(4, "(four)")]);
+        file.object_stream(12, "/Extends 12 0 R", &[]);
+        file.entries.retain(|entry| entry.2 != 10 && entry.0 != 4);
+
+        // At another index, in the stream that's extended, in a stream that
+        // extends itself, in something that's not an object stream and in a
+        // stream it's not in
+        file.compressed(2, 10, 1);
+        file.compressed(3, 11, 0);
+        file.compressed(4, 12, 0);
+        file.compressed(5, 1, 0);
+        file.compressed(6, 11, 0);
+        file.xref_stream(13, "");
+
+        let pdf = parse_pdf(file.data).unwrap();
+        assert_eq!(pdf.resolve(&Key::new(2, 0)), &PdfObject::string("two"));
+        assert_eq!(pdf.resolve(&Key::new(3, 0)), &PdfObject::string("three"));
+        assert_eq!(pdf.resolve(&Key::new(4, 0)), &PdfObject::Null);
+        assert_eq!(pdf.resolve(&Key::new(5, 0)), &PdfObject::Null);
+        assert_eq!(pdf.resolve(&Key::new(6, 0)), &PdfObject::Null);
+    }
+
+    #[test]
+    fn test_xref_binary_table() {
+        let data = [1, 0, 10, 0, 2, 0, 5, 1, 0, 0, 0, 1];
+        let table = xref_binary_table(&data, &[1, 2, 1], &[(3, 1), (7, 2)])
+            .unwrap().data;
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[&3], Xref::new(10, 3, 0, XrefType::InUse));
+        assert_eq!(table[&7].type_, XrefType::Compressed);
+        assert_eq!((table[&7].offset, table[&7].index), (5, 1));
+        assert_eq!(table[&7].key, Key::new(7, 0));
+        assert_eq!(table[&8], Xref::new(0, 8, 1, XrefType::Free));
+
+        // Without a type field everything is in use, without a generation
+        // it's 0
+        let table = xref_binary_table(&[0, 10, 0, 20], &[0, 2, 0], &[(1, 2)])
+            .unwrap().data;
+        assert_eq!(table[&1], Xref::new(10, 1, 0, XrefType::InUse));
+        assert_eq!(table[&2], Xref::new(20, 2, 0, XrefType::InUse));
+
+        // Not enough data for the subsections
+        let table = xref_binary_table(&data[..10], &[1, 2, 1], &[(0, 4)])
+            .unwrap().data;
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[&0], Xref::new(10, 0, 0, XrefType::InUse));
+    }
+
+    #[test]
+    fn test_xref_stream_index() {
+        let index = |data: &str| {
+            xref_stream_index(&dictionary(data.as_bytes()).unwrap().data)
+        };
+
+        assert_eq!(index("<< /Size 5 >>"), Some(vec![(0, 5)]));
+        assert_eq!(index("<< /Size 50 /Index [3 2 40 10] >>"),
+                   Some(vec![(3, 2), (40, 10)]));
+        assert_eq!(index("<< /Index [3] >>"), None);
+        assert_eq!(index("<< /Index [3 -2] >>"), None);
+        assert_eq!(index("<< /Type /XRef >>"), None);
+    }
+
+    #[test]
+    fn test_xref_stream_update() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.object(3, 0, "(original)");
+        let first = file.xref_stream(4, "/Root 1 0 R");
+
+        file.object(3, 0, "(changed)");
+        file.free(4, 1);
+        file.object_stream(10, "", &[(5, "(added)")]);
+        file.xref_stream(11, &format!("/Root 1 0 R /Prev {}", first));
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.revisions().len(), 2);
+        assert_eq!(document.revisions()[1].objects(),
+                   vec![Key::new(3, 0), Key::new(5, 0), Key::new(10, 0),
+                        Key::new(11, 0)]);
+        assert_eq!(document.trailer().integer("Size"), Some(12));
+        assert_eq!(document.catalog().identifier("Type"), Some("Catalog"));
+
+        let pdf = document.pdf();
+        assert_eq!(pdf.resolve(&Key::new(3, 0)), &PdfObject::string("changed"));
+        assert_eq!(pdf.resolve(&Key::new(5, 0)), &PdfObject::string("added"));
+        assert_eq!(pdf.resolve(&Key::new(4, 0)), &PdfObject::Null);
+
+        let original = document.at_revision(0).unwrap();
+        assert_eq!(original.object(&Key::new(3, 0)),
+                   &PdfObject::string("original"));
+        assert!(original.object(&Key::new(4, 0)).as_stream().is_some());
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        file.object_stream(10, "", &[
+            (3, "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] >>"),
+            (4, "(four)"),
+        ]);
+
+        // The table has the uncompressed objects, and 4 as free for the
+        // readers that don't know about object streams
+        let table: Vec<_> = file.entries.iter().cloned()
+            .filter(|entry| entry.3 != XrefType::Compressed)
+            .collect();
+        file.entries.retain(|entry| entry.3 == XrefType::Compressed);
+        // The table wins over the stream for objects in use
+        file.compressed(2, 10, 0);
+        let stream = file.xref_stream_object(11, "");
+
+        file.entries = table;
+        file.free(4, 0);
+        file.xref_table(&format!("/Size 12 /Root 1 0 R /XRefStm {}", stream));
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.revisions().len(), 1);
+        assert_eq!(document.page_count(), 1);
+        assert_eq!(document.page(0).unwrap().media_box(),
+                   Some([0.0, 0.0, 612.0, 792.0]));
+        assert_eq!(document.object(&Key::new(4, 0)),
+                   &PdfObject::string("four"));
+
+        // A broken stream is ignored like older readers do
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.xref_table("/Size 3 /Root 1 0 R /XRefStm 5");
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.page_count(), 0);
+    }
+
+    #[test]
+    fn test_unimplemented_filter() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Filter /ASCIIHexDecode /Length 7 >>\n\
+                           stream\n616263>\nendstream");
+        file.xref_table("/Size 2");
+
+        // The stream is kept as it is in the file
+        let pdf = parse_pdf(file.data).unwrap();
+        let stream = pdf.resolve(&Key::new(1, 0)).as_stream().unwrap();
+        assert_eq!(stream.data, b"616263>");
+        assert_eq!(stream.filters(), [Filter::ASCIIHexDecode]);
+    }
+
+    #[test]
+    fn test_update_trailer() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.object(3, 0, "<< /Producer (test) >>");
+        let first = file.xref_stream(4, "/Root 1 0 R /Info 3 0 R");
+
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 /Rotate 90 >>");
+        file.xref_table(&format!("/Size 5 /Prev {}", first));
+
+        let document = Document::from_bytes(file.data).unwrap();
+        let trailer = document.trailer();
+        assert_eq!(trailer.integer("Size"), Some(5));
+        assert_eq!(trailer.unsigned("Prev"), Some(first as u64));
+        assert_eq!(trailer.get("Root"),
+                   Some(&PdfObject::Reference(Key::new(1, 0))));
+        assert_eq!(trailer.get("Info"),
+                   Some(&PdfObject::Reference(Key::new(3, 0))));
+
+        // Only about the cross-reference stream
+        for key in &["Type", "W", "Index", "Length"] {
+            assert_eq!(trailer.get(key), None);
+        }
+        assert_eq!(document.at_revision(0).unwrap().trailer().identifier("Type"),
+                   Some("XRef"));
+    }
+
+    #[test]
+    fn test_truncated_xref_stream() {
+        let mut file = Builder::new();
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        // Promises more entries than there are
+        file.xref_stream(3, "/Size 10 /Index [1 9] /Root 1 0 R");
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.revisions()[0].objects(),
+                   vec![Key::new(1, 0), Key::new(2, 0), Key::new(3, 0)]);
+        assert_eq!(document.page_count(), 0);
+    }
+
+    #[test]
+    fn test_predicted_xref_stream() {
+        let mut file = Builder::new();
+        file.predictor = true;
+        file.object(1, 0, "<< /Type /Catalog /Pages 2 0 R >>");
+        file.object(2, 0, "<< /Type /Pages /Kids [] /Count 0 >>");
+        file.free(3, 1);
+        file.xref_stream(4, "/Root 1 0 R");
+
+        let document = Document::from_bytes(file.data).unwrap();
+        assert_eq!(document.revisions()[0].objects(),
+                   vec![Key::new(1, 0), Key::new(2, 0), Key::new(4, 0)]);
+        assert_eq!(document.revisions()[0].freed(), vec![Key::new(3, 1)]);
+        assert_eq!(document.page_count(), 0);
+    }
+
+    #[test]
+    fn test_png_predictor() {
+        let data = [1, 1, 1, 1,
+                    2, 1, 1, 1,
+                    3, 0, 0, 0,
+                    4, 5, 0, 0,
+                    0, 9];
+        assert_eq!(png_predictor(&data, 3, 1).unwrap(),
+                   vec![1, 2, 3, 2, 3, 4, 1, 2, 3, 6, 6, 6, 9]);
+
+        assert!(png_predictor(&[7, 1, 2, 3], 3, 1).is_err());
+    }
+
+    #[test]
+    fn test_tiff_predictor() {
+        assert_eq!(tiff_predictor(&[1, 2, 1, 1, 1, 1], 6, 6, 2, 8),
+                   vec![1, 2, 2, 3, 3, 4]);
+        assert_eq!(tiff_predictor(&[0x00, 0xFF, 0x00, 0x02], 4, 2, 1, 16),
+                   vec![0x00, 0xFF, 0x01, 0x01]);
+        assert_eq!(tiff_predictor(&[0x80, 0x40], 1, 8, 1, 1),
+                   vec![0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn test_flate_predictor() {
+        let mut compressed = vec![];
+        rfc1950_compress(&[2, 1, 2, 2, 1, 1], &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let mut data = format!("<< /Length {} /Filter /FlateDecode \
+                                /DecodeParms << /Predictor 2 /Colors 2 \
+                                /Columns 3 >> >>\nstream\n",
+                               compressed.len()).into_bytes();
+        data.extend(compressed);
+        data.extend(b"\nendstream");
+
+        assert_eq!(filtered_stream(&data), [2, 1, 4, 3, 5, 4]);
+    }
+
+    #[test]
+    fn test_parse_page() {
+        assert_eq!(parse_page(b"0 0 m 1 1 l S\n").unwrap().len(), 3);
+        assert_eq!(parse_page(b"").unwrap(), vec![]);
+        assert!(parse_page(b"0 0 m S ) )").is_err());
+    }
+
+    #[test]
+    fn test_dct_stream() {
+        let mut compressed = vec![];
+        rfc1950_compress(b"\xFF\xD8\xFF\xD9", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let mut data = format!("<< /Length {} \
+                                /Filter [/FlateDecode /DCTDecode] >>\n\
+                                stream\n", compressed.len()).into_bytes();
+        data.extend(compressed);
+        data.extend(b"\nendstream");
+
+        // Only the Flate layer comes off, the Jpeg is for the caller
+        let mut result = stream(&data, &mut |_| PdfObject::Null).unwrap();
+        result.data.apply_filters().unwrap();
+        assert_eq!(result.data.data, b"\xFF\xD8\xFF\xD9");
+        assert_eq!(result.data.filters(), [Filter::DCTDecode]);
+    }
+
+    #[test]
+    fn test_failed_filter() {
+        let mut compressed = vec![];
+        rfc1950_compress(b"abc", &mut compressed,
+                         CompressionLevel::Default).unwrap();
+        let mut data = format!("<< /Length {} /Filter /FlateDecode \
+                                /DecodeParms << /Predictor 7 >> >>\n\
+                                stream\n", compressed.len()).into_bytes();
+        data.extend(&compressed);
+        data.extend(b"\nendstream");
+
+        // The predictor fails, so the Flate layer stays on too
+        let mut result = stream(&data, &mut |_| PdfObject::Null).unwrap();
+        assert!(result.data.apply_filters().is_err());
+        assert_eq!(result.data.data, compressed);
+        assert_eq!(result.data.filters(), [Filter::FlateDecode]);
+    }
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use crate::document::Document;
 use crate::parser;
 use crate::parser::{
     PdfObject,
@@ -16,23 +17,10 @@ use crate::font::{
     Font,
 };
 
-pub fn resolve_pdf(pdf: &parser::Pdf) -> Result<(), String> {
-    let mut catalog = None;
-    for object in pdf.objects().values() {
-        if let Some(dictionary) = object.as_dictionary(pdf) {
-            if let Some(type_) = dictionary.identifier("Type") {
-                if type_ == "Catalog" {
-                    catalog = Some(Catalog::from(dictionary, pdf)
-                        .ok_or("Missing Catalog.")?);
-                } else if type_ == "Font" {
-                    let _font : Option<Font> = OptionalFrom::from(object, pdf);
-                }
-            }
-        }
-    }
-
-    println!("{:?}", catalog);
-    Ok(())
+// Reads everything reachable from the catalog, page contents included
+pub fn resolve_pdf(document: &Document) -> Result<Catalog, String> {
+    Catalog::from(document.catalog(), document.pdf())
+        .ok_or_else(|| "Invalid Catalog.".to_string())
 }
 
 // Table 28
@@ -47,7 +35,6 @@ impl Catalog {
     // 7.7.2
     fn from(metadata: &PdfDictionary,
             pdf: &parser::Pdf) -> Option<Catalog> {
-        println!("=== Catalog");
         let page_tree = metadata.dictionary("Pages", pdf)
             .and_then(|pt| PageTree::from(pt, pdf))?;
 
@@ -195,6 +182,7 @@ impl GraphicsState {
 struct Resources {
     proc_set: Vec<ProcSet>,
     graphics_state: Option<HashMap<String, GraphicsState>>,
+    fonts: Option<HashMap<String, Font>>,
 }
 
 impl Resources {
@@ -209,6 +197,10 @@ impl Resources {
                         Some((key.to_string(),
                               GraphicsState::from(value.as_dictionary(pdf)?)))
                     ).collect()),
+            fonts: data.dictionary("Font", pdf)
+                    .map(|fonts| fonts.data.iter().filter_map(|(key, value)|
+                        Some((key.to_string(), OptionalFrom::from(value, pdf)?))
+                    ).collect()),
         });
 
         resources
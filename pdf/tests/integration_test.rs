@@ -0,0 +1,36 @@
+extern crate oxi_pdf;
+
+use oxi_pdf::{
+    resolve_pdf,
+    Document,
+    Operator,
+    PdfObject,
+};
+
+#[test]
+fn test_document() {
+    let document = Document::open("tests/hello.pdf").unwrap();
+    assert_eq!(document.page_count(), 1);
+    assert!(document.trailer().get("Root").is_some());
+
+    let page = document.page(0).unwrap();
+    assert_eq!(page.media_box(), Some([0.0, 0.0, 612.0, 792.0]));
+
+    let font = page.resources().unwrap().dictionary("Font", document.pdf())
+        .and_then(|fonts| fonts.dictionary("F1", document.pdf()))
+        .unwrap();
+    assert_eq!(font.identifier("BaseFont"), Some("Helvetica"));
+
+    // The content stream is compressed
+    let operations = page.operations().unwrap();
+    assert_eq!(operations.len(), 5);
+    assert_eq!(operations[3], (vec![PdfObject::String(b"Hello, world!".to_vec())],
+                               Operator::TextString));
+
+    assert!(resolve_pdf(&document).is_ok());
+}
+
+#[test]
+fn test_missing_file() {
+    assert!(Document::open("tests/missing.pdf").is_err());
+}